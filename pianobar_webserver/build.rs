@@ -0,0 +1,112 @@
+//! Generates the file table of the embedded web ui, if the `embedded-webui` feature is enabled.
+
+use std::collections::hash_map::DefaultHasher;
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::hash::Hasher;
+use std::path::{Path, PathBuf};
+
+/// Overrides the directory of the built web ui
+const WEBUI_DIR_VARIABLE: &str = "PIANOBAR_WEBUI_DIR";
+const DEFAULT_WEBUI_DIR: &str = "../pianobar_webui/build";
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            collect_files(&path, files);
+        } else {
+            files.push(path);
+        }
+    }
+}
+
+fn etag(content: &[u8]) -> String {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(content);
+    format!("\"{:016x}\"", hasher.finish())
+}
+
+/// Precompressed variants are the files with an additional `.gz` or `.br` extension
+fn variant(path: &Path, extension: &str) -> String {
+    let mut variant = path.as_os_str().to_owned();
+    variant.push(extension);
+    match Path::new(&variant).is_file() {
+        true => format!("Some(include_bytes!({:?}))", variant),
+        false => "None".to_string(),
+    }
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-env-changed={}", WEBUI_DIR_VARIABLE);
+    if env::var_os("CARGO_FEATURE_EMBEDDED_WEBUI").is_none() {
+        return;
+    }
+
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let webui_dir = manifest_dir
+        .join(env::var(WEBUI_DIR_VARIABLE).unwrap_or_else(|_| DEFAULT_WEBUI_DIR.to_string()));
+    println!("cargo:rerun-if-changed={}", webui_dir.display());
+    let out_file = PathBuf::from(env::var("OUT_DIR").unwrap()).join("embedded_webui.rs");
+
+    // Reported as a regular compile error instead of a build script panic
+    if !webui_dir.join("index.html").is_file() {
+        let message = format!(
+            "The 'embedded-webui' feature needs the built web ui in '{}'. \
+             Build it first with 'npm ci && npm run build' in 'pianobar_webui', \
+             or point {} to the build directory.",
+            webui_dir.display(),
+            WEBUI_DIR_VARIABLE
+        );
+        let code = format!(
+            "compile_error!({:?});\nstatic FILES: &[EmbeddedFile] = &[];\n",
+            message
+        );
+        fs::write(out_file, code).unwrap();
+        return;
+    }
+
+    let mut files = vec![];
+    collect_files(&webui_dir, &mut files);
+
+    let mut entries = files
+        .iter()
+        .filter(|path| {
+            !matches!(
+                path.extension().and_then(|extension| extension.to_str()),
+                Some("gz") | Some("br")
+            )
+        })
+        .map(|path| {
+            let relative_path = path
+                .strip_prefix(&webui_dir)
+                .unwrap()
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            (relative_path, path)
+        })
+        .collect::<Vec<_>>();
+    // The server looks files up with a binary search
+    entries.sort();
+
+    let mut code = String::from("static FILES: &[EmbeddedFile] = &[\n");
+    for (relative_path, path) in entries {
+        writeln!(
+            code,
+            "    EmbeddedFile {{ path: {:?}, content: include_bytes!({:?}), gzip: {}, brotli: {}, etag: {:?} }},",
+            relative_path,
+            path,
+            variant(path, ".gz"),
+            variant(path, ".br"),
+            etag(&fs::read(path).unwrap()),
+        )
+        .unwrap();
+    }
+    code.push_str("];\n");
+
+    fs::write(out_file, code).unwrap();
+}
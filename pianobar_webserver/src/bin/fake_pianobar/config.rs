@@ -0,0 +1,126 @@
+use anyhow::{anyhow, Result};
+use ini::Ini;
+use structopt::StructOpt;
+
+const CONFIG_VARIABLE: &str = "FAKE_PIANOBAR_CONFIG";
+const DEFAULT_CONFIG_PATH: &str = "~/.config/pianobar/config";
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    about = "Simulates pianobar with scripted stations and songs, for tests and demos. \
+             Point pianobar_webserver's --pianobar-path at it."
+)]
+pub struct Options {
+    #[structopt(
+        short,
+        long,
+        help = const_format::formatcp!(
+            "The pianobar config file. Defaults to ${}, then to {}",
+            CONFIG_VARIABLE,
+            DEFAULT_CONFIG_PATH
+        )
+    )]
+    pub config: Option<String>,
+
+    #[structopt(
+        short,
+        long,
+        help = "A JSON file with the stations and their songs. Defaults to the built-in ones."
+    )]
+    pub fixtures: Option<String>,
+
+    #[structopt(
+        short,
+        long,
+        default_value = "1",
+        help = "How many seconds of a song play per second"
+    )]
+    pub speed: f64,
+}
+
+impl Options {
+    pub fn config_path(&self) -> String {
+        let path = self
+            .config
+            .clone()
+            .or_else(|| std::env::var(CONFIG_VARIABLE).ok())
+            .filter(|path| !path.is_empty())
+            .unwrap_or_else(|| DEFAULT_CONFIG_PATH.to_string());
+        shellexpand::tilde(&path).to_string()
+    }
+}
+
+/// The parts of pianobar's config that change what it prints and runs.
+/// Everything that isn't set falls back to pianobar's defaults.
+#[derive(Debug)]
+pub struct PianobarConfig {
+    pub event_command: Option<String>,
+    pub autostart_station: Option<String>,
+    pub format_msg_none: String,
+    pub format_msg_info: String,
+    pub format_msg_nowplaying: String,
+    pub format_msg_time: String,
+    pub format_msg_err: String,
+    pub format_msg_question: String,
+    pub format_msg_list: String,
+    pub format_list_song: String,
+    pub format_nowplaying_song: String,
+    pub format_nowplaying_station: String,
+    /// Banned and tired songs don't play again, so only loved ones get an icon
+    pub love_icon: String,
+}
+
+impl PianobarConfig {
+    pub fn load(path: &str) -> Result<PianobarConfig> {
+        // pianobar doesn't know escapes, formats like `/!\ %s` are taken literally
+        let ini = Ini::load_from_file_noescape(path)
+            .map_err(|err| anyhow!("Unable to load pianobar config '{}': {}", path, err))?;
+        let section = ini.general_section();
+        let get = |key: &str| section.get(key).map(str::to_string);
+        let get_or = |key: &str, default: &str| get(key).unwrap_or_else(|| default.to_string());
+
+        Ok(PianobarConfig {
+            event_command: get("event_command"),
+            autostart_station: get("autostart_station"),
+            format_msg_none: get_or("format_msg_none", "%s"),
+            format_msg_info: get_or("format_msg_info", "(i) %s"),
+            format_msg_nowplaying: get_or("format_msg_nowplaying", "|>  %s"),
+            format_msg_time: get_or("format_msg_time", "#   %s"),
+            format_msg_err: get_or("format_msg_err", "/!\\ %s"),
+            format_msg_question: get_or("format_msg_question", "[?] %s"),
+            format_msg_list: get_or("format_msg_list", "\t%s"),
+            format_list_song: get_or("format_list_song", "%i) %a - %t%r"),
+            format_nowplaying_song: get_or(
+                "format_nowplaying_song",
+                "\"%t\" by \"%a\" on \"%l\"%r%@%s",
+            ),
+            format_nowplaying_station: get_or("format_nowplaying_station", "Station \"%n\" (%i)"),
+            love_icon: get_or("love_icon", " <3"),
+        })
+    }
+}
+
+/// Replaces pianobar's `%<key>` placeholders. `%%` is a literal `%`,
+/// unknown placeholders are kept as they are.
+pub fn substitute(format: &str, values: &[(char, &str)]) -> String {
+    let mut result = String::with_capacity(format.len());
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('%') => result.push('%'),
+            Some(key) => match values.iter().find(|(k, _)| *k == key) {
+                Some((_, value)) => result.push_str(value),
+                None => {
+                    result.push('%');
+                    result.push(key);
+                }
+            },
+            None => result.push('%'),
+        }
+    }
+    result
+}
@@ -0,0 +1,78 @@
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+
+/// Used unless `--fixtures` says otherwise
+const DEFAULT_FIXTURES: &str = include_str!("fixtures.json");
+
+const DEFAULT_EXPLANATION: &str = "We're playing this track because it features \
+     a subtle use of vocal harmony, repetitive melodic phrasing and many other similarities \
+     as identified by the Music Genome Project.";
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Song {
+    pub title: String,
+    pub artist: String,
+    pub album: String,
+    /// In seconds
+    pub duration: u32,
+    #[serde(default)]
+    pub loved: bool,
+    #[serde(default)]
+    pub detail_url: String,
+    #[serde(default)]
+    pub cover_art: String,
+    /// The station the song comes from, for songs of a QuickMix
+    #[serde(default)]
+    pub station: Option<String>,
+    #[serde(default)]
+    pub explanation: Option<String>,
+}
+
+impl Song {
+    pub fn explanation(&self) -> &str {
+        self.explanation.as_deref().unwrap_or(DEFAULT_EXPLANATION)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Station {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub is_quickmix: bool,
+    #[serde(default)]
+    pub in_quickmix: bool,
+    /// Created by someone else
+    #[serde(default)]
+    pub shared: bool,
+    /// Played in order, a few at a time like pianobar's playlists
+    pub songs: Vec<Song>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Fixtures {
+    pub stations: Vec<Station>,
+}
+
+impl Fixtures {
+    pub fn load(path: Option<&str>) -> Result<Fixtures> {
+        let mut fixtures: Fixtures = match path {
+            Some(path) => {
+                let content = std::fs::read_to_string(path)
+                    .map_err(|err| anyhow!("Unable to read fixtures '{}': {}", path, err))?;
+                serde_json::from_str(&content)
+                    .map_err(|err| anyhow!("Invalid fixtures '{}': {}", path, err))?
+            }
+            None => serde_json::from_str(DEFAULT_FIXTURES)?,
+        };
+
+        if let Some(station) = fixtures.stations.iter().find(|s| s.songs.is_empty()) {
+            return Err(anyhow!("Station '{}' has no songs", station.name));
+        }
+        // pianobar sorts the stations by name, with the QuickMix first
+        fixtures
+            .stations
+            .sort_by_key(|station| (!station.is_quickmix, station.name.to_lowercase()));
+        Ok(fixtures)
+    }
+}
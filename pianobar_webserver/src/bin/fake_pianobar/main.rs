@@ -0,0 +1,65 @@
+mod config;
+mod fixtures;
+mod player;
+
+use anyhow::{bail, Result};
+use config::{Options, PianobarConfig};
+use fixtures::Fixtures;
+use player::Player;
+use std::io::Read;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
+use structopt::StructOpt;
+
+/// pianobar prints the song time about once per second
+const TICK: Duration = Duration::from_secs(1);
+
+fn main() {
+    // stdout belongs to the simulated output, env_logger writes to stderr
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+
+    if let Err(err) = main_with_result() {
+        eprintln!("Error: {}", err);
+        std::process::exit(1);
+    }
+}
+
+fn main_with_result() -> Result<()> {
+    let options = Options::from_args();
+    if options.speed <= 0.0 {
+        bail!("The speed has to be positive");
+    }
+    let config = PianobarConfig::load(&options.config_path())?;
+    log::debug!("pianobar config: {:?}", config);
+    let fixtures = Fixtures::load(options.fixtures.as_deref())?;
+
+    // pianobar reads its keys unbuffered, one at a time
+    let (keys_sender, keys) = mpsc::channel();
+    std::thread::spawn(move || {
+        for byte in std::io::stdin().lock().bytes() {
+            if keys_sender.send(byte).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut player = Player::new(config, fixtures, options.speed);
+    player.start();
+
+    let mut next_tick = Instant::now() + TICK;
+    loop {
+        match keys.recv_timeout(next_tick.saturating_duration_since(Instant::now())) {
+            Ok(byte) => {
+                if !player.key(byte? as char) {
+                    return Ok(());
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                player.tick();
+                next_tick += TICK;
+            }
+            // Without input, pianobar can't be controlled anymore
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+    }
+}
@@ -0,0 +1,441 @@
+use crate::config::{substitute, PianobarConfig};
+use crate::fixtures::{Fixtures, Song};
+use std::collections::VecDeque;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::thread::sleep;
+use std::time::Duration;
+
+/// Songs per playlist, like Pandora's
+const PLAYLIST_LENGTH: usize = 4;
+/// Songs kept for the `h` command, like pianobar's default `history`
+const HISTORY_LENGTH: usize = 5;
+/// How long requests to "Pandora" take
+const REQUEST_DURATION: Duration = Duration::from_millis(300);
+
+/// The message types of pianobar, each with its own `format_msg_*`
+#[derive(Clone, Copy, Debug)]
+enum MessageType {
+    None,
+    Info,
+    NowPlaying,
+    Time,
+    Error,
+    Question,
+    List,
+}
+
+/// What a line of input is for
+#[derive(Clone, Copy, Debug)]
+enum Prompt {
+    Station,
+    HistorySong,
+}
+
+struct PlayingSong {
+    song: Song,
+    /// In seconds
+    played: f64,
+}
+
+pub struct Player {
+    config: PianobarConfig,
+    fixtures: Fixtures,
+    /// How many seconds of a song play per tick
+    speed: f64,
+    station: Option<usize>,
+    /// Where the next playlist starts in the station's songs
+    next_song: usize,
+    playlist: VecDeque<Song>,
+    current: Option<PlayingSong>,
+    paused: bool,
+    /// The most recent song first
+    history: VecDeque<Song>,
+    prompt: Option<(Prompt, String)>,
+}
+
+impl Player {
+    pub fn new(config: PianobarConfig, fixtures: Fixtures, speed: f64) -> Player {
+        Player {
+            config,
+            fixtures,
+            speed,
+            station: None,
+            next_song: 0,
+            playlist: VecDeque::new(),
+            current: None,
+            paused: false,
+            history: VecDeque::new(),
+            prompt: None,
+        }
+    }
+
+    fn print(&self, message_type: MessageType, message: &str) {
+        let format = match message_type {
+            MessageType::None => &self.config.format_msg_none,
+            MessageType::Info => &self.config.format_msg_info,
+            MessageType::NowPlaying => &self.config.format_msg_nowplaying,
+            MessageType::Time => &self.config.format_msg_time,
+            MessageType::Error => &self.config.format_msg_err,
+            MessageType::Question => &self.config.format_msg_question,
+            MessageType::List => &self.config.format_msg_list,
+        };
+        let mut stdout = std::io::stdout();
+        // pianobar clears the line first, except for the outcome of a request
+        // that continues the line of its announcement
+        if !matches!(message_type, MessageType::None) {
+            let _ = stdout.write_all(b"\x1b[2K");
+        }
+        let _ = stdout.write_all(substitute(format, &[('s', message)]).as_bytes());
+        let _ = stdout.flush();
+    }
+
+    /// Announces a request, waits for it and reports its success
+    fn request(&self, message: &str) {
+        self.print(MessageType::Info, message);
+        sleep(REQUEST_DURATION);
+        self.print(MessageType::None, "Ok.\n");
+    }
+
+    fn station_name(&self) -> &str {
+        self.station
+            .map(|station| self.fixtures.stations[station].name.as_str())
+            .unwrap_or_default()
+    }
+
+    fn rating_icon(&self, song: &Song) -> String {
+        if song.loved {
+            self.config.love_icon.clone()
+        } else {
+            String::new()
+        }
+    }
+
+    /// Runs the `event_command` like pianobar does, with the details as `key=value` lines
+    fn event(&self, event: &str) {
+        let command = match &self.config.event_command {
+            Some(command) => command,
+            None => return,
+        };
+
+        let mut details = vec![];
+        let (song, played) = match &self.current {
+            Some(current) => (Some(&current.song), current.played as u32),
+            None => (None, 0),
+        };
+        let song_value = |get: fn(&Song) -> String| song.map(get).unwrap_or_default();
+        details.push(("artist", song_value(|song| song.artist.clone())));
+        details.push(("title", song_value(|song| song.title.clone())));
+        details.push(("album", song_value(|song| song.album.clone())));
+        details.push(("coverArt", song_value(|song| song.cover_art.clone())));
+        details.push(("stationName", self.station_name().to_string()));
+        details.push((
+            "songStationName",
+            song_value(|song| song.station.clone().unwrap_or_default()),
+        ));
+        details.push(("pRet", "1".to_string()));
+        details.push(("pRetStr", "Everything is fine :)".to_string()));
+        details.push(("wRet", "1".to_string()));
+        details.push(("wRetStr", "Everything's fine :)".to_string()));
+        details.push(("songDuration", song_value(|song| song.duration.to_string())));
+        details.push(("songPlayed", played.to_string()));
+        details.push(("rating", song_value(|song| (song.loved as u8).to_string())));
+        details.push(("detailUrl", song_value(|song| song.detail_url.clone())));
+        details.push(("stationCount", self.fixtures.stations.len().to_string()));
+        let station_keys = (0..self.fixtures.stations.len())
+            .map(|num| format!("station{}", num))
+            .collect::<Vec<_>>();
+        for (key, station) in station_keys.iter().zip(&self.fixtures.stations) {
+            details.push((key, station.name.clone()));
+        }
+
+        let payload = details
+            .iter()
+            .map(|(key, value)| format!("{}={}\n", key, value))
+            .collect::<String>();
+
+        log::debug!("Event {}", event);
+        let result = Command::new(command)
+            .arg(event)
+            .stdin(Stdio::piped())
+            .spawn()
+            .and_then(|mut child| {
+                if let Some(mut stdin) = child.stdin.take() {
+                    stdin.write_all(payload.as_bytes())?;
+                }
+                child.wait()
+            });
+        if let Err(err) = result {
+            log::warn!("Unable to run event command '{}': {}", command, err);
+        }
+    }
+
+    fn list_stations(&self) {
+        for (num, station) in self.fixtures.stations.iter().enumerate() {
+            self.print(
+                MessageType::List,
+                &format!(
+                    "{:2}) {}{}{} {}\n",
+                    num,
+                    if station.in_quickmix { 'q' } else { ' ' },
+                    if station.is_quickmix { 'Q' } else { ' ' },
+                    if station.shared { 'S' } else { ' ' },
+                    station.name
+                ),
+            );
+        }
+    }
+
+    fn list_songs<'a>(&self, songs: impl Iterator<Item = &'a Song>) {
+        for (num, song) in songs.enumerate() {
+            let line = substitute(
+                &self.config.format_list_song,
+                &[
+                    ('i', &format!("{:2}", num)),
+                    ('a', &song.artist),
+                    ('t', &song.title),
+                    ('r', &self.rating_icon(song)),
+                ],
+            );
+            self.print(MessageType::List, &format!("{}\n", line));
+        }
+    }
+
+    fn ask(&mut self, prompt: Prompt, question: &str) {
+        self.print(MessageType::Question, question);
+        self.prompt = Some((prompt, String::new()));
+    }
+
+    /// Logs in and loads the stations, then starts the autostart station or asks for one
+    pub fn start(&mut self) {
+        self.print(
+            MessageType::None,
+            "Welcome to pianobar (fake)! Press ? for a list of commands.\n",
+        );
+        self.request("Login... ");
+        self.event("userlogin");
+        self.request("Get stations... ");
+        self.event("usergetstations");
+
+        let autostart = self.config.autostart_station.as_ref().and_then(|id| {
+            self.fixtures
+                .stations
+                .iter()
+                .position(|station| &station.id == id)
+        });
+        match autostart {
+            Some(station) => self.change_station(station),
+            None => {
+                self.list_stations();
+                self.ask(Prompt::Station, "Select station: ");
+            }
+        }
+    }
+
+    fn change_station(&mut self, station: usize) {
+        self.finish_song();
+        self.station = Some(station);
+        self.next_song = 0;
+        self.playlist.clear();
+        self.next_song_or_playlist();
+    }
+
+    fn fetch_playlist(&mut self) {
+        let station = match self.station {
+            Some(station) => &self.fixtures.stations[station],
+            None => return,
+        };
+        let songs = &station.songs;
+        let playlist = (0..PLAYLIST_LENGTH)
+            .map(|num| songs[(self.next_song + num) % songs.len()].clone())
+            .collect();
+        self.next_song = (self.next_song + PLAYLIST_LENGTH) % songs.len();
+
+        self.request("Receiving new playlist... ");
+        self.playlist = playlist;
+        self.event("stationfetchplaylist");
+    }
+
+    fn next_song_or_playlist(&mut self) {
+        if self.playlist.is_empty() {
+            self.fetch_playlist();
+        }
+        let song = match self.playlist.pop_front() {
+            Some(song) => song,
+            None => return,
+        };
+
+        let station = &self.fixtures.stations[self.station.unwrap_or_default()];
+        self.print(
+            MessageType::NowPlaying,
+            &format!(
+                "{}\n",
+                substitute(
+                    &self.config.format_nowplaying_station,
+                    &[('n', &station.name), ('i', &station.id)],
+                )
+            ),
+        );
+        let song_station = song.station.clone().unwrap_or_default();
+        let at = if song_station.is_empty() { "" } else { " @ " };
+        self.print(
+            MessageType::NowPlaying,
+            &format!(
+                "{}\n",
+                substitute(
+                    &self.config.format_nowplaying_song,
+                    &[
+                        ('t', &song.title),
+                        ('a', &song.artist),
+                        ('l', &song.album),
+                        ('r', &self.rating_icon(&song)),
+                        ('@', at),
+                        ('s', &song_station),
+                        ('u', &song.detail_url),
+                    ],
+                )
+            ),
+        );
+
+        self.current = Some(PlayingSong { song, played: 0.0 });
+        self.event("songstart");
+    }
+
+    fn finish_song(&mut self) {
+        if self.current.is_none() {
+            return;
+        }
+        self.event("songfinish");
+        if let Some(current) = self.current.take() {
+            self.history.push_front(current.song);
+            self.history.truncate(HISTORY_LENGTH);
+        }
+    }
+
+    fn skip(&mut self) {
+        self.finish_song();
+        self.next_song_or_playlist();
+    }
+
+    /// Advances the song and prints its time, once per second
+    pub fn tick(&mut self) {
+        let speed = self.speed;
+        let (played, duration) = match &mut self.current {
+            Some(current) => {
+                if !self.paused {
+                    current.played += speed;
+                }
+                (current.played as u32, current.song.duration)
+            }
+            None => return,
+        };
+
+        if played >= duration {
+            self.skip();
+            return;
+        }
+        let left = duration - played;
+        self.print(
+            MessageType::Time,
+            &format!(
+                "-{:02}:{:02}/{:02}:{:02}\r",
+                left / 60,
+                left % 60,
+                duration / 60,
+                duration % 60
+            ),
+        );
+    }
+
+    /// Handles a key press, or a line of input if a question is pending.
+    /// Returns false if pianobar should quit.
+    pub fn key(&mut self, key: char) -> bool {
+        if let Some((prompt, mut line)) = self.prompt.take() {
+            match key {
+                '\r' | '\n' => self.answer(prompt, line.trim()),
+                '\x08' | '\x7f' => {
+                    line.pop();
+                    self.prompt = Some((prompt, line));
+                }
+                _ => {
+                    line.push(key);
+                    self.prompt = Some((prompt, line));
+                }
+            }
+            return true;
+        }
+
+        match key {
+            'q' => return false,
+            'p' => self.paused = !self.paused,
+            'P' => self.paused = false,
+            'S' => self.paused = true,
+            'n' => self.skip(),
+            's' => {
+                self.list_stations();
+                self.ask(Prompt::Station, "Select station: ");
+            }
+            'h' if self.history.is_empty() => self.print(MessageType::Info, "No history yet.\n"),
+            'h' => {
+                self.list_songs(self.history.iter());
+                self.ask(Prompt::HistorySong, "Select song: ");
+            }
+            'u' if self.playlist.is_empty() => {
+                self.print(MessageType::Info, "No songs in queue.\n")
+            }
+            'u' => self.list_songs(self.playlist.iter()),
+            '+' | '-' | 't' | 'e' if self.current.is_none() => {
+                self.print(MessageType::Error, "No song playing.\n")
+            }
+            '+' => {
+                self.request("Loving song... ");
+                if let Some(current) = &mut self.current {
+                    current.song.loved = true;
+                }
+                self.event("songlove");
+            }
+            '-' => {
+                self.request("Banning song... ");
+                self.event("songban");
+                self.skip();
+            }
+            't' => {
+                self.request("Putting song on shelf... ");
+                self.event("songshelf");
+                self.skip();
+            }
+            'e' => {
+                self.request("Receiving explanation... ");
+                if let Some(current) = &self.current {
+                    let explanation = format!("{}\n", current.song.explanation());
+                    self.print(MessageType::Info, &explanation);
+                }
+            }
+            // Line breaks cancel questions, they do nothing on their own
+            '\r' | '\n' => {}
+            key => log::debug!("Ignoring key {:?}", key),
+        }
+        true
+    }
+
+    fn answer(&mut self, prompt: Prompt, answer: &str) {
+        // An empty answer cancels the question
+        if answer.is_empty() {
+            return;
+        }
+        match prompt {
+            Prompt::Station => match answer.parse::<usize>() {
+                Ok(station) if station < self.fixtures.stations.len() => {
+                    self.change_station(station)
+                }
+                _ => self.print(MessageType::Error, "Invalid station.\n"),
+            },
+            Prompt::HistorySong => {
+                log::debug!(
+                    "Actions on history songs aren't simulated, ignoring {}",
+                    answer
+                )
+            }
+        }
+    }
+}
@@ -0,0 +1,129 @@
+use anyhow::{anyhow, Result};
+use ini::Ini;
+use pianobar_webserver::client::ClientSettings;
+use pianobar_webserver::default_config;
+use std::path::Path;
+use structopt::StructOpt;
+
+const URL_VARIABLE: &str = "PIANOBAR_URL";
+const TOKEN_VARIABLE: &str = "PIANOBAR_TOKEN";
+const DEFAULT_CONFIG_PATH: &str = "~/.config/pianobar_ctl/config";
+const DEFAULT_FORMAT: &str = "{artist} - {title}";
+
+#[derive(StructOpt, Debug)]
+#[structopt(about = "Controls a running pianobar_webserver through its websocket")]
+pub struct Options {
+    #[structopt(
+        short,
+        long,
+        help = "The websocket URL of the server, like 'ws://localhost:3030/ws'. \
+                Defaults to $PIANOBAR_URL, then to the config file."
+    )]
+    pub url: Option<String>,
+
+    #[structopt(
+        short,
+        long,
+        help = "The API token. Defaults to $PIANOBAR_TOKEN, then to the config file."
+    )]
+    pub token: Option<String>,
+
+    #[structopt(
+        short,
+        long,
+        help = const_format::formatcp!(
+            "The config file with 'url' and 'token' entries [default: {}]",
+            DEFAULT_CONFIG_PATH
+        )
+    )]
+    pub config: Option<String>,
+
+    #[structopt(subcommand)]
+    pub command: Command,
+}
+
+#[derive(StructOpt, Debug)]
+pub enum Command {
+    /// Skips the current song
+    Skip,
+    /// Bans the current song
+    Ban,
+    /// Pauses playback
+    Pause,
+    /// Resumes playback
+    Resume,
+    /// Pauses or resumes playback
+    TogglePause,
+    /// Changes the station, by its id or (part of) its name
+    Station { name: String },
+    /// Prints the current song
+    NowPlaying {
+        #[structopt(
+            short,
+            long,
+            default_value = DEFAULT_FORMAT,
+            help = "The output format. '{<key>}' gets replaced by the value of <key> in the \
+                    player state, like 'artist', 'title', 'album', 'stationName' or 'rating'."
+        )]
+        format: String,
+    },
+    /// Prints the recently played songs
+    History {
+        #[structopt(long, help = "Prints the songs as JSON")]
+        json: bool,
+    },
+    /// Prints the songs that play next
+    Upcoming {
+        #[structopt(long, help = "Prints the songs as JSON")]
+        json: bool,
+    },
+    /// Prints the current song every time it changes, until interrupted
+    Watch {
+        #[structopt(short, long, default_value = DEFAULT_FORMAT, help = "The output format, see 'now-playing'")]
+        format: String,
+
+        #[structopt(long, help = "Prints the raw notifications as JSON lines instead")]
+        json: bool,
+    },
+}
+
+impl Options {
+    /// Where and how to connect to the server. Command line arguments take
+    /// precedence over environment variables, which take precedence over the config file.
+    pub fn client_settings(&self) -> Result<ClientSettings> {
+        let file = load_config_file(self.config.as_deref())?;
+        let from_file = |key: &str| {
+            file.as_ref()
+                .and_then(|ini| ini.general_section().get(key).map(str::to_string))
+        };
+        let from_env = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+
+        let url = self
+            .url
+            .clone()
+            .or_else(|| from_env(URL_VARIABLE))
+            .or_else(|| from_file("url"))
+            .unwrap_or_else(|| format!("ws://localhost:{}/ws", default_config::WEBSERVER_PORT));
+        let token = self
+            .token
+            .clone()
+            .or_else(|| from_env(TOKEN_VARIABLE))
+            .or_else(|| from_file("token"));
+
+        let mut settings = ClientSettings::new(&url);
+        settings.token = token;
+        Ok(settings)
+    }
+}
+
+/// A missing config file is only an error if it was given explicitly
+fn load_config_file(path: Option<&str>) -> Result<Option<Ini>> {
+    let explicit = path.is_some();
+    let path = shellexpand::tilde(path.unwrap_or(DEFAULT_CONFIG_PATH)).to_string();
+    if !explicit && !Path::new(&path).exists() {
+        return Ok(None);
+    }
+    let ini = Ini::load_from_file(&path)
+        .map_err(|err| anyhow!("Unable to load config '{}': {}", path, err))?;
+    Ok(Some(ini))
+}
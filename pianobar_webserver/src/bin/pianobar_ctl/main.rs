@@ -0,0 +1,241 @@
+mod config;
+
+use anyhow::{bail, Result};
+use config::{Command, Options};
+use futures::StreamExt;
+use pianobar_webserver::client::{ClientSettings, Notification, PianobarClient};
+use pianobar_webserver::protocol::{
+    PendingVote, StateParams, NOTIFICATION_ACTIVITY, NOTIFICATION_HISTORY_UPDATE,
+    NOTIFICATION_PLAYER_ERROR, NOTIFICATION_PLAYER_STATE, NOTIFICATION_UI_EVENT,
+    NOTIFICATION_VOTE_STATE,
+};
+use pianobar_webserver::ui_state::PianobarUiState;
+use serde_json as json;
+use structopt::StructOpt;
+
+#[tokio::main]
+async fn main() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+
+    if let Err(err) = main_with_result().await {
+        eprintln!("Error: {}", err);
+        std::process::exit(1);
+    }
+}
+
+async fn main_with_result() -> Result<()> {
+    let options = Options::from_args();
+    let settings = options.client_settings()?;
+
+    if let Command::Watch { format, json } = &options.command {
+        return watch(settings, format, *json).await;
+    }
+
+    let client = PianobarClient::connect(settings).await?;
+    let result = match &options.command {
+        Command::Skip => client.skip().await.map(print_vote),
+        Command::Ban => client.ban().await.map(print_vote),
+        Command::Pause => client.pause().await,
+        Command::Resume => client.resume().await,
+        Command::TogglePause => client.toggle_pause().await,
+        Command::Station { name } => change_station(&client, name).await,
+        Command::NowPlaying { format } => {
+            println!("{}", now_playing_line(format, &client.ui_state()));
+            Ok(())
+        }
+        Command::History { json } => history(&client, *json).await,
+        Command::Upcoming { json } => upcoming(&client, *json).await,
+        Command::Watch { .. } => unreachable!(),
+    };
+    client.close().await;
+    result
+}
+
+/// Actions that are subject to voting return the pending vote, if any
+fn print_vote(vote: Option<PendingVote>) {
+    if let Some(vote) = vote {
+        println!(
+            "Vote pending: {} of {} votes",
+            vote.votes, vote.required_votes
+        );
+    }
+}
+
+/// Finds a station by its id, its exact name or a unique part of its name, ignoring case
+fn find_station(stations: &[&str], name: &str) -> Result<usize> {
+    if let Ok(id) = name.parse::<usize>() {
+        if id < stations.len() {
+            return Ok(id);
+        }
+    }
+    let lowercase_name = name.to_lowercase();
+    if let Some(id) = stations
+        .iter()
+        .position(|station| station.to_lowercase() == lowercase_name)
+    {
+        return Ok(id);
+    }
+    let matches = stations
+        .iter()
+        .enumerate()
+        .filter(|(_, station)| station.to_lowercase().contains(&lowercase_name))
+        .collect::<Vec<_>>();
+    match matches.as_slice() {
+        [(id, _)] => Ok(*id),
+        [] => bail!("No station matches '{}'", name),
+        _ => bail!(
+            "'{}' matches several stations: {}",
+            name,
+            matches
+                .iter()
+                .map(|(_, station)| **station)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
+async fn change_station(client: &PianobarClient, name: &str) -> Result<()> {
+    let stations = client
+        .ui_state()
+        .get("stations")
+        .and_then(json::Value::as_array)
+        .map(|stations| {
+            stations
+                .iter()
+                .filter_map(json::Value::as_str)
+                .map(str::to_string)
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    if stations.is_empty() {
+        bail!("The server doesn't know any stations yet");
+    }
+
+    let station_id = find_station(
+        &stations.iter().map(String::as_str).collect::<Vec<_>>(),
+        name,
+    )?;
+    print_vote(client.change_station(station_id).await?);
+    Ok(())
+}
+
+/// Replaces every `{<key>}` with the value of `<key>` in the state, or nothing if it's missing
+fn format_state(format: &str, state: &PianobarUiState) -> String {
+    let mut result = String::new();
+    let mut rest = format;
+    while let Some(start) = rest.find('{') {
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => break,
+        };
+        result.push_str(&rest[..start]);
+        match state.get(&rest[start + 1..end]) {
+            Some(json::Value::String(value)) => result.push_str(value),
+            Some(json::Value::Null) | None => (),
+            Some(value) => result.push_str(&value.to_string()),
+        }
+        rest = &rest[end + 1..];
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Nothing gets printed for the state before the first song
+fn now_playing_line(format: &str, state: &PianobarUiState) -> String {
+    if state.contains_key("title") {
+        format_state(format, state)
+    } else {
+        String::new()
+    }
+}
+
+async fn history(client: &PianobarClient, as_json: bool) -> Result<()> {
+    let songs = client.history().await?;
+    if as_json {
+        println!("{}", json::to_string_pretty(&songs)?);
+    } else {
+        for song in songs {
+            println!("{} - {}", song.artist, song.title);
+        }
+    }
+    Ok(())
+}
+
+async fn upcoming(client: &PianobarClient, as_json: bool) -> Result<()> {
+    let songs = client.upcoming().await?;
+    if as_json {
+        println!("{}", json::to_string_pretty(&songs)?);
+    } else {
+        for song in songs {
+            if song.album.is_empty() {
+                println!("{} - {}", song.artist, song.title);
+            } else {
+                println!("{} - {} ({})", song.artist, song.title, song.album);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The notification as sent by the server, `None` for connection changes
+fn notification_json(notification: &Notification) -> Result<Option<json::Value>> {
+    let (method, params) = match notification {
+        Notification::Connected(_) | Notification::Disconnected(_) => return Ok(None),
+        Notification::UiEvent(params) => (NOTIFICATION_UI_EVENT, json::to_value(params)?),
+        Notification::PlayerState(state) => (
+            NOTIFICATION_PLAYER_STATE,
+            json::to_value(StateParams { state })?,
+        ),
+        Notification::PlayerError(params) => (NOTIFICATION_PLAYER_ERROR, json::to_value(params)?),
+        Notification::HistoryUpdate(params) => {
+            (NOTIFICATION_HISTORY_UPDATE, json::to_value(params)?)
+        }
+        Notification::VoteState(state) => (
+            NOTIFICATION_VOTE_STATE,
+            json::to_value(StateParams { state })?,
+        ),
+        Notification::Activity(entry) => (NOTIFICATION_ACTIVITY, json::to_value(entry)?),
+        Notification::Unknown { method, params } => (method.as_str(), params.clone()),
+    };
+    Ok(Some(json::json!({ "method": method, "params": params })))
+}
+
+/// Prints until interrupted. The client reconnects by itself, so status bars survive
+/// server restarts.
+async fn watch(settings: ClientSettings, format: &str, as_json: bool) -> Result<()> {
+    let client = loop {
+        match PianobarClient::connect(settings.clone()).await {
+            Ok(client) => break client,
+            Err(err) => {
+                log::warn!("{}, retrying in {:?} ...", err, settings.reconnect_delay);
+                tokio::time::sleep(settings.reconnect_delay).await;
+            }
+        }
+    };
+
+    let mut notifications = Box::pin(client.notifications());
+    let mut last_line = now_playing_line(format, &client.ui_state());
+    if !as_json {
+        println!("{}", last_line);
+    }
+    while let Some(notification) = notifications.next().await {
+        if as_json {
+            if let Some(line) = notification_json(&notification)? {
+                println!("{}", line);
+            }
+            continue;
+        }
+        let line = match &notification {
+            Notification::UiEvent(params) => now_playing_line(format, &params.state),
+            // Don't show a stale song while disconnected
+            Notification::Disconnected(_) => String::new(),
+            _ => continue,
+        };
+        if line != last_line {
+            println!("{}", line);
+            last_line = line;
+        }
+    }
+    Ok(())
+}
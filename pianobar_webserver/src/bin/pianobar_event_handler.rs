@@ -11,7 +11,7 @@ use pianobar_webserver::ui_state::{PianobarUiEvent, PianobarUiState};
 fn main() -> Result<()> {
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("debug")).init();
 
-    let command_vec = std::env::args().into_iter().skip(1).collect::<Vec<_>>();
+    let command_vec = std::env::args().skip(1).collect::<Vec<_>>();
 
     let command = match command_vec.as_slice() {
         [s] => s.clone(),
@@ -51,7 +51,7 @@ fn main() -> Result<()> {
     let mut tcp_stream =
         TcpStream::connect((Ipv4Addr::new(127, 0, 0, 1), default_config::EVENT_PORT))?;
 
-    tcp_stream.write(&json_message)?;
+    tcp_stream.write_all(&json_message)?;
 
     Ok(())
 }
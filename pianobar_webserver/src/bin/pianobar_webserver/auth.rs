@@ -0,0 +1,478 @@
+use anyhow::{anyhow, bail, Result};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use hmac::{Hmac, Mac, NewMac};
+use ini::Ini;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use subtle::ConstantTimeEq;
+use warp::http::{Response, StatusCode};
+use warp::{Filter, Rejection, Reply};
+
+const SESSION_COOKIE: &str = "pianobar_session";
+const DEFAULT_SESSION_LIFETIME: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+const LOGIN_PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><meta name="viewport" content="width=device-width, initial-scale=1"><title>Pianobar - Login</title></head>
+<body>
+<form method="post" action="login">
+<p>{message}</p>
+<p><input name="username" placeholder="Username" autofocus></p>
+<p><input name="password" type="password" placeholder="Password"></p>
+<p><button type="submit">Login</button></p>
+</form>
+</body>
+</html>
+"#;
+
+/// What a client is allowed to do. Every role includes the permissions of the previous ones.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Can watch the player state
+    Viewer,
+    /// Can control playback: play, pause, skip, rate
+    Listener,
+    /// Can manage stations and the configuration
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Listener => "listener",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "viewer" => Ok(Role::Viewer),
+            "listener" => Ok(Role::Listener),
+            "admin" => Ok(Role::Admin),
+            _ => bail!("Unknown role: '{}'", s),
+        }
+    }
+}
+
+/// Who a client is, as far as authentication is concerned
+#[derive(Clone, Debug, Serialize)]
+pub struct Identity {
+    /// The logged in user or the name of the API token.
+    /// `None` for anonymous clients.
+    pub user: Option<String>,
+    pub role: Role,
+}
+
+struct User {
+    /// An argon2 hash in the PHC string format, which includes the salt
+    password_hash: String,
+    role: Role,
+}
+
+struct ApiToken {
+    name: String,
+    token_sha256: String,
+    role: Role,
+}
+
+/// The content of the authentication config file:
+///
+/// ```ini
+/// [auth]
+/// session_secret = <random string>  ; optional, sessions don't survive restarts without it
+/// session_lifetime = 604800         ; optional, in seconds
+/// anonymous_role = viewer           ; optional, login is required without it
+///
+/// [user.alice]
+/// password_hash = <output of `pianobar_webserver --hash-password`>
+/// role = admin
+///
+/// [token.statusbar]
+/// token = <random string>
+/// role = viewer
+/// ```
+struct AuthConfig {
+    session_secret: Vec<u8>,
+    session_lifetime: Duration,
+    anonymous_role: Option<Role>,
+    users: HashMap<String, User>,
+    tokens: Vec<ApiToken>,
+    /// Checked for unknown users, so the time a login takes doesn't tell which users exist
+    dummy_password_hash: String,
+}
+
+fn sha256_hex(data: &str) -> String {
+    hex::encode(Sha256::digest(data.as_bytes()))
+}
+
+/// Hashes a password for the `password_hash` of the auth config, with a random salt
+pub fn hash_password(password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|err| anyhow!("Unable to hash the password: {}", err))?
+        .to_string())
+}
+
+fn unix_time_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+impl AuthConfig {
+    fn load(path: &str) -> Result<AuthConfig> {
+        let path = shellexpand::tilde(path).to_string();
+        let ini = Ini::load_from_file(&path)
+            .map_err(|err| anyhow!("Unable to load auth config '{}': {}", path, err))?;
+
+        let mut config = AuthConfig {
+            session_secret: vec![],
+            session_lifetime: DEFAULT_SESSION_LIFETIME,
+            anonymous_role: None,
+            users: HashMap::new(),
+            tokens: vec![],
+            dummy_password_hash: hash_password("")?,
+        };
+
+        for (section, properties) in ini.iter() {
+            let get = |key: &str| {
+                properties
+                    .get(key)
+                    .ok_or_else(|| anyhow!("[{}]: '{}' is missing", section.unwrap_or(""), key))
+            };
+
+            match section {
+                Some("auth") => {
+                    if let Some(secret) = properties.get("session_secret") {
+                        config.session_secret = secret.as_bytes().to_vec();
+                    }
+                    if let Some(lifetime) = properties.get("session_lifetime") {
+                        config.session_lifetime = Duration::from_secs(lifetime.parse()?);
+                    }
+                    if let Some(role) = properties.get("anonymous_role") {
+                        config.anonymous_role = Some(role.parse()?);
+                    }
+                }
+                Some(section_name) => {
+                    if let Some(name) = section_name.strip_prefix("user.") {
+                        if properties.contains_key("password_sha256") {
+                            bail!(
+                                "[{}]: 'password_sha256' is no longer supported, \
+                                 replace it with a 'password_hash' created with --hash-password",
+                                section_name
+                            );
+                        }
+                        let password_hash = get("password_hash")?;
+                        PasswordHash::new(password_hash).map_err(|err| {
+                            anyhow!("[{}]: Invalid 'password_hash': {}", section_name, err)
+                        })?;
+                        config.users.insert(
+                            name.to_string(),
+                            User {
+                                password_hash: password_hash.to_string(),
+                                role: get("role")?.parse()?,
+                            },
+                        );
+                    } else if let Some(name) = section_name.strip_prefix("token.") {
+                        config.tokens.push(ApiToken {
+                            name: name.to_string(),
+                            token_sha256: sha256_hex(get("token")?),
+                            role: get("role")?.parse()?,
+                        });
+                    } else {
+                        log::warn!("Unknown section in auth config: [{}]", section_name);
+                    }
+                }
+                None => (),
+            }
+        }
+
+        if config.session_secret.is_empty() {
+            log::info!("No session_secret configured. Sessions will not survive a restart.");
+            config.session_secret = vec![0; 32];
+            rand::thread_rng().fill_bytes(&mut config.session_secret);
+        }
+
+        Ok(config)
+    }
+
+    fn session_mac(&self, payload: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.session_secret)
+            .expect("HMAC accepts keys of any length");
+        mac.update(payload.as_bytes());
+        mac
+    }
+
+    /// Creates the signed session cookie value `<hex user>.<expiry>.<hex signature>`
+    fn create_session(&self, user: &str) -> String {
+        let expiry = unix_time_now() + self.session_lifetime.as_secs();
+        let payload = format!("{}.{}", hex::encode(user), expiry);
+        let signature = hex::encode(self.session_mac(&payload).finalize().into_bytes());
+        format!("{}.{}", payload, signature)
+    }
+
+    fn verify_session(&self, session: &str) -> Option<Identity> {
+        let (payload, signature) = session.rsplit_once('.')?;
+        self.session_mac(payload)
+            .verify(&hex::decode(signature).ok()?)
+            .ok()?;
+
+        let (user, expiry) = payload.split_once('.')?;
+        if expiry.parse::<u64>().ok()? < unix_time_now() {
+            return None;
+        }
+
+        // Take the role from the current config, the user might have changed since
+        let user = String::from_utf8(hex::decode(user).ok()?).ok()?;
+        let role = self.users.get(&user)?.role;
+        Some(Identity {
+            user: Some(user),
+            role,
+        })
+    }
+
+    fn verify_token(&self, token: &str) -> Option<Identity> {
+        let token_sha256 = sha256_hex(token);
+        self.tokens
+            .iter()
+            .find(|api_token| {
+                // In constant time, so the timing doesn't tell how close a guess was
+                api_token
+                    .token_sha256
+                    .as_bytes()
+                    .ct_eq(token_sha256.as_bytes())
+                    .into()
+            })
+            .map(|api_token| Identity {
+                user: Some(api_token.name.clone()),
+                role: api_token.role,
+            })
+    }
+
+    fn verify_password(&self, user: &str, password: &str) -> bool {
+        let (password_hash, known_user) = match self.users.get(user) {
+            Some(user) => (&user.password_hash, true),
+            None => (&self.dummy_password_hash, false),
+        };
+        // The hash was validated when loading the config
+        let verified = PasswordHash::new(password_hash).is_ok_and(|password_hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &password_hash)
+                .is_ok()
+        });
+        verified && known_user
+    }
+}
+
+#[derive(Debug)]
+struct Unauthorized;
+impl warp::reject::Reject for Unauthorized {}
+
+#[derive(Deserialize)]
+struct LoginForm {
+    username: String,
+    password: String,
+}
+
+/// Checks the credentials of incoming requests.
+///
+/// If no auth config is given, everyone is anonymous with the same role.
+#[derive(Clone)]
+pub struct Authenticator {
+    config: Option<Arc<AuthConfig>>,
+    unauthenticated_role: Role,
+    cookie_attributes: String,
+}
+
+impl Authenticator {
+    /// `unauthenticated_role` is the role of everyone if there's no auth config.
+    /// `cookie_path` restricts the session cookie to the URL prefix the server is served under.
+    /// `tls` marks the session cookie as `Secure`, so browsers never send it unencrypted.
+    pub fn new(
+        auth_config_path: Option<&str>,
+        unauthenticated_role: Role,
+        cookie_path: String,
+        tls: bool,
+    ) -> Result<Authenticator> {
+        Ok(Authenticator {
+            config: match auth_config_path {
+                Some(path) => Some(Arc::new(AuthConfig::load(path)?)),
+                None => None,
+            },
+            unauthenticated_role,
+            cookie_attributes: format!(
+                "Path={}; HttpOnly; SameSite=Strict{}",
+                cookie_path,
+                if tls { "; Secure" } else { "" }
+            ),
+        })
+    }
+
+    /// Identifies a client by its session cookie, its `Authorization: Bearer` header
+    /// or its `token` query parameter, in that order.
+    fn identify(
+        &self,
+        session: Option<String>,
+        authorization: Option<String>,
+        query: HashMap<String, String>,
+    ) -> Option<Identity> {
+        let config = match &self.config {
+            Some(config) => config,
+            None => {
+                return Some(Identity {
+                    user: None,
+                    role: self.unauthenticated_role,
+                })
+            }
+        };
+
+        let bearer_token = authorization
+            .as_deref()
+            .and_then(|authorization| authorization.strip_prefix("Bearer "))
+            .map(str::to_string);
+
+        session
+            .and_then(|session| config.verify_session(&session))
+            .or_else(|| bearer_token.and_then(|token| config.verify_token(&token)))
+            .or_else(|| {
+                query
+                    .get("token")
+                    .and_then(|token| config.verify_token(token))
+            })
+            .or_else(|| {
+                config
+                    .anonymous_role
+                    .map(|role| Identity { user: None, role })
+            })
+    }
+
+    /// Extracts the identity of the client, or rejects the request if it's unknown
+    pub fn with_identity(&self) -> impl Filter<Extract = (Identity,), Error = Rejection> + Clone {
+        let authenticator = self.clone();
+        warp::cookie::optional::<String>(SESSION_COOKIE)
+            .and(warp::header::optional::<String>("authorization"))
+            .and(warp::query::<HashMap<String, String>>())
+            .and_then(move |session, authorization, query| {
+                let identity = authenticator.identify(session, authorization, query);
+                async move { identity.ok_or_else(|| warp::reject::custom(Unauthorized)) }
+            })
+    }
+
+    fn login_page(message: &str, status: StatusCode) -> Response<String> {
+        Response::builder()
+            .status(status)
+            .header("content-type", "text/html; charset=utf-8")
+            .body(LOGIN_PAGE.replace("{message}", message))
+            .unwrap()
+    }
+
+    fn login(&self, form: LoginForm) -> Result<Response<String>, Rejection> {
+        let config = self.config.as_ref().ok_or_else(warp::reject::not_found)?;
+
+        if !config.verify_password(&form.username, &form.password) {
+            log::warn!("Failed login attempt for user '{}'", form.username);
+            return Ok(Self::login_page(
+                "Invalid username or password.",
+                StatusCode::UNAUTHORIZED,
+            ));
+        }
+
+        log::info!("User '{}' logged in", form.username);
+        let cookie = format!(
+            "{}={}; Max-Age={}; {}",
+            SESSION_COOKIE,
+            config.create_session(&form.username),
+            config.session_lifetime.as_secs(),
+            self.cookie_attributes
+        );
+        Ok(Response::builder()
+            .status(StatusCode::SEE_OTHER)
+            .header("location", "./")
+            .header("set-cookie", cookie)
+            .body(String::new())
+            .unwrap())
+    }
+
+    fn logout(&self) -> Result<Response<String>, Rejection> {
+        self.config.as_ref().ok_or_else(warp::reject::not_found)?;
+
+        Ok(Response::builder()
+            .status(StatusCode::SEE_OTHER)
+            .header("location", "login")
+            .header(
+                "set-cookie",
+                format!("{}=; Max-Age=0; {}", SESSION_COOKIE, self.cookie_attributes),
+            )
+            .body(String::new())
+            .unwrap())
+    }
+
+    /// The `login` and `logout` routes. Only exist if authentication is enabled.
+    pub fn create_routes(&self) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+        let enabled = self.config.is_some();
+        let login_page = warp::path("login")
+            .and(warp::path::end())
+            .and(warp::get())
+            .and_then(move || async move {
+                if enabled {
+                    Ok(Self::login_page("", StatusCode::OK))
+                } else {
+                    Err(warp::reject::not_found())
+                }
+            });
+
+        let authenticator = self.clone();
+        let login = warp::path("login")
+            .and(warp::path::end())
+            .and(warp::post())
+            .and(warp::body::content_length_limit(4096))
+            .and(warp::body::form())
+            .and_then(move |form| {
+                let authenticator = authenticator.clone();
+                async move {
+                    // Verifying the password hash takes a while, don't block other connections
+                    tokio::task::spawn_blocking(move || authenticator.login(form))
+                        .await
+                        .map_err(|_| warp::reject::reject())?
+                }
+            });
+
+        let authenticator = self.clone();
+        let logout = warp::path("logout")
+            .and(warp::path::end())
+            .and(warp::post())
+            .and_then(move || {
+                let response = authenticator.logout();
+                async move { response }
+            });
+
+        login_page.or(login).or(logout)
+    }
+}
+
+/// Turns authentication failures into `401 Unauthorized` responses
+pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Rejection> {
+    if err.find::<Unauthorized>().is_some() {
+        Ok(warp::reply::with_status(
+            "Authentication required",
+            StatusCode::UNAUTHORIZED,
+        ))
+    } else {
+        Err(err)
+    }
+}
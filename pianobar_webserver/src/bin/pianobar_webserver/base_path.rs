@@ -0,0 +1,66 @@
+use anyhow::{bail, Result};
+use warp::filters::BoxedFilter;
+use warp::http::Uri;
+use warp::{Filter, Rejection, Reply};
+
+/// The URL prefix all routes live under, for example when served
+/// by a reverse proxy at `https://example.com/music/`.
+#[derive(Clone, Debug)]
+pub struct BasePath {
+    segments: Vec<String>,
+}
+
+impl BasePath {
+    /// Accepts `/`, `music`, `/music`, `/music/` and nested paths like `/apps/music/`
+    pub fn new(base_path: &str) -> Result<BasePath> {
+        let segments = base_path
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+
+        for segment in &segments {
+            if segment == "." || segment == ".." || segment.contains(['?', '#', '%']) {
+                bail!("Invalid base path: '{}'", base_path);
+            }
+        }
+
+        Ok(BasePath { segments })
+    }
+
+    /// The base path with leading and trailing slash, like `/music/`
+    pub fn as_prefix(&self) -> String {
+        if self.segments.is_empty() {
+            "/".to_string()
+        } else {
+            format!("/{}/", self.segments.join("/"))
+        }
+    }
+
+    /// Consumes the base path segments
+    pub fn filter(&self) -> BoxedFilter<()> {
+        self.segments
+            .iter()
+            .fold(warp::any().boxed(), |filter, segment| {
+                filter.and(warp::path(segment.clone())).boxed()
+            })
+    }
+
+    /// Redirects `/music` to `/music/`, so relative links of the web ui resolve correctly
+    pub fn redirect(&self) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+        let prefix = self.as_prefix();
+        warp::path::full().and_then(move |path: warp::path::FullPath| {
+            let is_prefix_without_slash =
+                prefix.len() > 1 && path.as_str() == &prefix[..prefix.len() - 1];
+            let redirect = if is_prefix_without_slash {
+                prefix
+                    .parse::<Uri>()
+                    .map(warp::redirect)
+                    .map_err(|_| warp::reject::not_found())
+            } else {
+                Err(warp::reject::not_found())
+            };
+            async move { redirect }
+        })
+    }
+}
@@ -1,5 +1,9 @@
+use crate::pianobar_controller::CommandTimeout;
+use crate::websocket::ControlMode;
 use const_format::formatcp as const_format;
 use pianobar_webserver::default_config;
+use std::net::IpAddr;
+use std::num::{NonZeroU32, NonZeroUsize};
 use structopt::StructOpt;
 
 #[derive(StructOpt, Debug)]
@@ -10,7 +14,133 @@ pub struct Config {
     #[structopt(short, long, default_value = const_format!("{}", default_config::WEBSERVER_PORT))]
     pub port: u16,
 
-    #[structopt(short, long, help = "The path to the build directory of the web ui")]
+    #[structopt(
+        short,
+        long,
+        number_of_values = 1,
+        help = "The addresses to listen on. Can be used multiple times. \
+                Accepts '<ip>[:<port>]', 'localhost[:<port>]' and 'unix:<path>'. \
+                Listens on all interfaces if not given."
+    )]
+    pub bind: Vec<String>,
+
+    #[structopt(
+        long,
+        requires = "tls-key",
+        help = "Enables TLS. Specifies the path of the PEM encoded certificate chain. Gets reloaded on change."
+    )]
+    pub tls_cert: Option<String>,
+
+    #[structopt(
+        long,
+        requires = "tls-cert",
+        help = "Specifies the path of the PEM encoded private key for TLS. Gets reloaded on change."
+    )]
+    pub tls_key: Option<String>,
+
+    #[structopt(
+        long,
+        default_value = "/",
+        help = "The URL path all routes are served under, like '/music/'. For reverse proxies."
+    )]
+    pub base_path: String,
+
+    #[structopt(
+        long,
+        number_of_values = 1,
+        help = "The address of a reverse proxy whose Forwarded and X-Forwarded-For headers are trusted. \
+                Can be used multiple times. Connections through unix sockets are always trusted."
+    )]
+    pub trusted_proxy: Vec<IpAddr>,
+
+    #[structopt(
+        long,
+        default_value = "direct",
+        help = "How skipping, banning and changing stations is controlled. \
+                'direct': every listener acts instantly. \
+                'vote': a quorum of connected listeners has to agree. Admins always act instantly. \
+                Without --auth-config, everyone is a listener in vote mode."
+    )]
+    pub control_mode: ControlMode,
+
+    #[structopt(
+        long,
+        default_value = "0.5",
+        help = "The fraction of connected listeners that has to vote for an action in vote mode"
+    )]
+    pub vote_quorum: f64,
+
+    #[structopt(
+        long,
+        default_value = "60",
+        help = "The number of seconds a vote stays open in vote mode"
+    )]
+    pub vote_window: u64,
+
+    #[structopt(
+        long,
+        help = "Appends every action of every client to the given file, as JSON lines"
+    )]
+    pub audit_log: Option<String>,
+
+    #[structopt(
+        long,
+        default_value = "30",
+        help = "The number of seconds between websocket pings. 0 disables pings."
+    )]
+    pub ping_interval: u64,
+
+    #[structopt(
+        long,
+        default_value = "2",
+        help = "The number of unanswered pings after which a websocket client is disconnected. \
+                Use --ping-interval 0 to never disconnect clients that don't answer."
+    )]
+    pub max_missed_pongs: NonZeroU32,
+
+    #[structopt(
+        long,
+        help = "Disconnects websocket clients that didn't send a message for the given number of seconds"
+    )]
+    pub idle_timeout: Option<u64>,
+
+    #[structopt(
+        long,
+        default_value = "10",
+        help = "The number of pianobar events a websocket client can fall behind \
+                before it gets resynchronized with a full state snapshot"
+    )]
+    pub event_channel_capacity: NonZeroUsize,
+
+    #[structopt(
+        long,
+        default_value = "100",
+        help = "The number of recent pianobar events kept for reconnecting websocket clients \
+                that resume from a sequence number"
+    )]
+    pub event_replay_capacity: usize,
+
+    #[structopt(
+        long,
+        default_value = "16",
+        help = "The number of activity notifications a websocket client can fall behind before it misses some"
+    )]
+    pub activity_channel_capacity: NonZeroUsize,
+
+    #[structopt(
+        long,
+        default_value = "256",
+        help = "The number of messages that may wait for a slow websocket client. \
+                Clients that fall further behind get disconnected."
+    )]
+    pub send_queue_capacity: NonZeroUsize,
+
+    #[structopt(
+        short,
+        long,
+        help = "The path to the build directory of the web ui. \
+                Overrides the web ui embedded with the 'embedded-webui' feature."
+    )]
     pub webpage_folder: Option<String>,
 
     #[structopt(
@@ -34,4 +164,38 @@ pub struct Config {
         default_value = "~/.config/pianobar/config"
     )]
     pub pianobar_config: String,
+
+    #[structopt(
+        long,
+        number_of_values = 1,
+        help = "How long pianobar gets to answer a command, as '<command>=<milliseconds>'. \
+                Can be used multiple times. Supports 'explain', 'history' and 'upcoming', which default to 1000."
+    )]
+    pub command_timeout: Vec<CommandTimeout>,
+
+    #[structopt(
+        long,
+        conflicts_with = "replay",
+        help = "Records everything written to and read from pianobar, and all of its events, \
+                to the given file. Attach it to bug reports."
+    )]
+    pub record: Option<String>,
+
+    #[structopt(
+        long,
+        help = "Replays a file created with --record instead of starting pianobar"
+    )]
+    pub replay: Option<String>,
+
+    #[structopt(
+        long,
+        help = "Enables authentication. Specifies the path of the file that configures users, API tokens and roles"
+    )]
+    pub auth_config: Option<String>,
+
+    #[structopt(
+        long,
+        help = "Reads a password from stdin, prints its hash for the 'password_hash' of the auth config and exits"
+    )]
+    pub hash_password: bool,
 }
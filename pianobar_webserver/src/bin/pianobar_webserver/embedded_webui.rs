@@ -0,0 +1,134 @@
+//! The web ui, compiled into the binary by `build.rs`.
+
+use warp::filters::BoxedFilter;
+use warp::http::{header, StatusCode};
+use warp::hyper::Body;
+use warp::path::Tail;
+use warp::reply::Response;
+use warp::{Filter, Rejection};
+
+struct EmbeddedFile {
+    /// Relative to the build directory, separated by `/`
+    path: &'static str,
+    content: &'static [u8],
+    gzip: Option<&'static [u8]>,
+    brotli: Option<&'static [u8]>,
+    etag: &'static str,
+}
+
+// Defines `FILES`, sorted by path
+include!(concat!(env!("OUT_DIR"), "/embedded_webui.rs"));
+
+const INDEX_FILE: &str = "index.html";
+
+fn find_file(path: &str) -> Option<&'static EmbeddedFile> {
+    FILES
+        .binary_search_by(|file| file.path.cmp(path))
+        .ok()
+        .map(|index| &FILES[index])
+}
+
+/// Paths that aren't files and don't look like files are routes of the web ui
+fn resolve(path: &str) -> Option<&'static EmbeddedFile> {
+    if path.is_empty() || path.ends_with('/') {
+        return find_file(&format!("{}{}", path, INDEX_FILE)).or_else(|| find_file(INDEX_FILE));
+    }
+    find_file(path).or_else(|| {
+        let file_name = path.rsplit('/').next().unwrap_or_default();
+        if file_name.contains('.') {
+            None
+        } else {
+            find_file(INDEX_FILE)
+        }
+    })
+}
+
+/// Whether `Accept-Encoding` allows the encoding, ignoring preferences other than `q=0`
+fn accepts(accept_encoding: &str, encoding: &str) -> bool {
+    accept_encoding.split(',').any(|entry| {
+        let mut parts = entry.split(';');
+        let name = parts.next().unwrap_or_default().trim();
+        let refused = parts.any(|parameter| {
+            parameter
+                .trim()
+                .strip_prefix("q=")
+                .and_then(|quality| quality.parse::<f32>().ok())
+                == Some(0.0)
+        });
+        (name.eq_ignore_ascii_case(encoding) || name == "*") && !refused
+    })
+}
+
+fn matches_etag(if_none_match: &str, etag: &str) -> bool {
+    if_none_match.split(',').any(|candidate| {
+        let candidate = candidate.trim();
+        candidate == "*" || candidate.trim_start_matches("W/") == etag
+    })
+}
+
+fn respond(
+    file: &'static EmbeddedFile,
+    accept_encoding: Option<String>,
+    if_none_match: Option<String>,
+) -> Response {
+    let mut response = Response::new(Body::empty());
+    let headers = response.headers_mut();
+    headers.insert(header::ETAG, header::HeaderValue::from_static(file.etag));
+    headers.insert(
+        header::VARY,
+        header::HeaderValue::from_static("accept-encoding"),
+    );
+    // The build tool puts content hashes into the names of the static files
+    headers.insert(
+        header::CACHE_CONTROL,
+        header::HeaderValue::from_static(if file.path.starts_with("static/") {
+            "public, max-age=31536000, immutable"
+        } else {
+            "no-cache"
+        }),
+    );
+
+    if let Some(if_none_match) = if_none_match {
+        if matches_etag(&if_none_match, file.etag) {
+            *response.status_mut() = StatusCode::NOT_MODIFIED;
+            return response;
+        }
+    }
+
+    let content_type = mime_guess::from_path(file.path).first_or_octet_stream();
+    if let Ok(content_type) = header::HeaderValue::from_str(content_type.as_ref()) {
+        response
+            .headers_mut()
+            .insert(header::CONTENT_TYPE, content_type);
+    }
+
+    let accept_encoding = accept_encoding.unwrap_or_default();
+    let (encoding, content) = match (file.brotli, file.gzip) {
+        (Some(brotli), _) if accepts(&accept_encoding, "br") => (Some("br"), brotli),
+        (_, Some(gzip)) if accepts(&accept_encoding, "gzip") => (Some("gzip"), gzip),
+        _ => (None, file.content),
+    };
+    if let Some(encoding) = encoding {
+        response.headers_mut().insert(
+            header::CONTENT_ENCODING,
+            header::HeaderValue::from_static(encoding),
+        );
+    }
+    *response.body_mut() = Body::from(content);
+    response
+}
+
+/// Serves the embedded files, and `index.html` for routes of the web ui
+pub fn route() -> BoxedFilter<(Response,)> {
+    warp::get()
+        .and(warp::path::tail())
+        .and(warp::header::optional::<String>("accept-encoding"))
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and_then(
+            |tail: Tail, accept_encoding: Option<String>, if_none_match: Option<String>| async move {
+                let file = resolve(tail.as_str()).ok_or_else(warp::reject::not_found)?;
+                Ok::<_, Rejection>(respond(file, accept_encoding, if_none_match))
+            },
+        )
+        .boxed()
+}
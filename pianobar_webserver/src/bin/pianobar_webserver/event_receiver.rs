@@ -1,61 +1,236 @@
 use crate::config::Config;
+use crate::recording::Recorder;
 use anyhow::Result;
 use serde_json as json;
+use std::collections::VecDeque;
 use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
 use tokio::io::AsyncReadExt;
 use tokio::net::TcpListener;
 use tokio::sync::{broadcast, watch};
 
 pub use pianobar_webserver::ui_state::{PianobarUiEvent, PianobarUiState};
 
+/// Keys the server adds to the ui state of the current song
+const SERVER_SONG_KEYS: &[&str] = &["explanation"];
+/// Keys the server adds to the ui state that outlive the current song
+const SERVER_PLAYLIST_KEYS: &[&str] = &["upcoming"];
+
+/// A ui event together with its position in the event stream.
+///
+/// Sequence numbers start at 1 and increase by one with every event.
+#[derive(Clone, Debug)]
+pub struct SequencedUiEvent {
+    pub sequence: u64,
+    pub event: PianobarUiEvent,
+}
+
+/// The ui state after the event with the given sequence number, 0 before the first event
+#[derive(Clone, Debug, Default)]
+pub struct PianobarUiSnapshot {
+    pub sequence: u64,
+    pub state: PianobarUiState,
+}
+
+/// The most recent events, for clients that reconnect and want to catch up
+#[derive(Clone)]
+pub struct ReplayBuffer {
+    capacity: usize,
+    events: Arc<Mutex<VecDeque<SequencedUiEvent>>>,
+}
+
+impl ReplayBuffer {
+    fn new(capacity: usize) -> ReplayBuffer {
+        ReplayBuffer {
+            capacity,
+            events: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+        }
+    }
+
+    fn push(&self, event: SequencedUiEvent) {
+        let mut events = self.events.lock().unwrap();
+        if events.len() >= self.capacity {
+            events.pop_front();
+        }
+        if self.capacity > 0 {
+            events.push_back(event);
+        }
+    }
+
+    /// All events after `sequence` up to and including `until`.
+    ///
+    /// Returns `None` if some of them aren't buffered anymore,
+    /// or if `sequence` lies in the future.
+    pub fn events_between(&self, sequence: u64, until: u64) -> Option<Vec<SequencedUiEvent>> {
+        if sequence >= until {
+            return if sequence == until {
+                Some(vec![])
+            } else {
+                None
+            };
+        }
+        let events = self.events.lock().unwrap();
+        let oldest = events.front()?.sequence;
+        if oldest > sequence + 1 {
+            return None;
+        }
+        Some(
+            events
+                .iter()
+                .filter(|event| event.sequence > sequence && event.sequence <= until)
+                .cloned()
+                .collect(),
+        )
+    }
+}
+
+/// Numbers ui events and distributes them to the ui state, the replay buffer
+/// and all event sources. Clones share the sequence numbers.
+#[derive(Clone)]
+pub struct PianobarUiEventPublisher {
+    sequence: Arc<Mutex<u64>>,
+    update_ui_state: Arc<watch::Sender<PianobarUiSnapshot>>,
+    ui_events: broadcast::Sender<SequencedUiEvent>,
+    replay_buffer: ReplayBuffer,
+}
+
+impl PianobarUiEventPublisher {
+    /// The ui state after the latest event
+    pub fn ui_state(&self) -> PianobarUiState {
+        self.update_ui_state.borrow().state.clone()
+    }
+
+    pub fn publish(&self, event: PianobarUiEvent) {
+        // Keeps the events in order of their sequence numbers everywhere
+        let mut sequence = self.sequence.lock().unwrap();
+        self.publish_in_sequence(&mut sequence, event);
+    }
+
+    /// Publishes the latest ui state, changed by `change`, for additions of the server
+    /// like the explanation of the current song. Nothing gets published if `change`
+    /// returns false.
+    pub fn publish_change(&self, command: &str, change: impl FnOnce(&mut PianobarUiState) -> bool) {
+        let mut sequence = self.sequence.lock().unwrap();
+        let mut state = self.ui_state();
+        if change(&mut state) {
+            let event = PianobarUiEvent {
+                command: command.to_string(),
+                state,
+            };
+            self.publish_in_sequence(&mut sequence, event);
+        }
+    }
+
+    fn publish_in_sequence(&self, sequence: &mut u64, mut event: PianobarUiEvent) {
+        // pianobar's events don't contain the keys of the server,
+        // the ones of the song belong to it until the next one starts
+        {
+            let previous = &self.update_ui_state.borrow().state;
+            let song_keys = match event.command.as_str() {
+                "songstart" => &[],
+                _ => SERVER_SONG_KEYS,
+            };
+            for key in song_keys.iter().chain(SERVER_PLAYLIST_KEYS) {
+                if event.state.contains_key(*key) {
+                    continue;
+                }
+                if let Some(value) = previous.get(*key) {
+                    event.state.insert(key.to_string(), value.clone());
+                }
+            }
+        }
+
+        *sequence += 1;
+        let event = SequencedUiEvent {
+            sequence: *sequence,
+            event,
+        };
+        self.replay_buffer.push(event.clone());
+
+        if let Err(err) = self.update_ui_state.send(PianobarUiSnapshot {
+            sequence: *sequence,
+            state: event.event.state.clone(),
+        }) {
+            log::error!("Error while updating ui state: {}", err);
+        };
+
+        if let Err(err) = self.ui_events.send(event) {
+            log::warn!("Error while broadcasting ui event: {}", err);
+        };
+    }
+}
+
 #[derive(Clone)]
 pub struct PianobarUiEventSourceCreator {
-    ui_state: watch::Receiver<PianobarUiState>,
-    ui_events: broadcast::Sender<PianobarUiEvent>,
+    ui_state: watch::Receiver<PianobarUiSnapshot>,
+    ui_events: broadcast::Sender<SequencedUiEvent>,
+    replay_buffer: ReplayBuffer,
 }
 
 pub struct PianobarUiEventSource {
-    pub ui_initial_state: PianobarUiState,
-    pub ui_events: broadcast::Receiver<PianobarUiEvent>,
+    /// Events up to its sequence number are already contained in it,
+    /// and have to be skipped when they arrive through `ui_events`
+    pub ui_initial_state: PianobarUiSnapshot,
+    pub ui_events: broadcast::Receiver<SequencedUiEvent>,
+    /// The latest ui state, to resynchronize receivers that lagged behind
+    pub ui_state: watch::Receiver<PianobarUiSnapshot>,
+    pub replay_buffer: ReplayBuffer,
 }
 
 impl PianobarUiEventSourceCreator {
     pub fn create_event_source(&self) -> PianobarUiEventSource {
+        // Subscribe before taking the snapshot, so no event falls in between
+        let ui_events = self.ui_events.subscribe();
         PianobarUiEventSource {
             ui_initial_state: self.ui_state.borrow().clone(),
-            ui_events: self.ui_events.subscribe(),
+            ui_events,
+            ui_state: self.ui_state.clone(),
+            replay_buffer: self.replay_buffer.clone(),
         }
     }
 }
 
 pub struct PianobarEventReceiver {
     port: u16,
-    ui_state: watch::Receiver<PianobarUiState>,
-    update_ui_state: watch::Sender<PianobarUiState>,
-    ui_events: broadcast::Sender<PianobarUiEvent>,
-    _ui_events_dummy_receiver: broadcast::Receiver<PianobarUiEvent>,
+    ui_state: watch::Receiver<PianobarUiSnapshot>,
+    publisher: PianobarUiEventPublisher,
+    _ui_events_dummy_receiver: broadcast::Receiver<SequencedUiEvent>,
+    recorder: Option<Recorder>,
 }
 
 impl PianobarEventReceiver {
-    pub fn new(config: &Config) -> PianobarEventReceiver {
-        let (update_ui_state, ui_state) = watch::channel(PianobarUiState::new());
-        let (ui_events, _ui_events_dummy_receiver) = broadcast::channel(10);
+    /// With a recorder, every received event gets recorded
+    pub fn new(config: &Config, recorder: Option<Recorder>) -> PianobarEventReceiver {
+        let (update_ui_state, ui_state) = watch::channel(PianobarUiSnapshot::default());
+        let (ui_events, _ui_events_dummy_receiver) =
+            broadcast::channel(config.event_channel_capacity.get());
         PianobarEventReceiver {
             port: config.event_port,
-            update_ui_state,
             ui_state,
-            ui_events,
+            publisher: PianobarUiEventPublisher {
+                sequence: Arc::new(Mutex::new(0)),
+                update_ui_state: Arc::new(update_ui_state),
+                ui_events,
+                replay_buffer: ReplayBuffer::new(config.event_replay_capacity),
+            },
             _ui_events_dummy_receiver,
+            recorder,
         }
     }
 
     pub fn get_event_source_creator(&self) -> PianobarUiEventSourceCreator {
         PianobarUiEventSourceCreator {
             ui_state: self.ui_state.clone(),
-            ui_events: self.ui_events.clone(),
+            ui_events: self.publisher.ui_events.clone(),
+            replay_buffer: self.publisher.replay_buffer.clone(),
         }
     }
 
+    /// Publishes events that don't come from pianobar's event command
+    pub fn get_publisher(&self) -> PianobarUiEventPublisher {
+        self.publisher.clone()
+    }
+
     pub async fn run(&self) -> Result<()> {
         log::info!("Start event handler ...");
         let listener = TcpListener::bind((Ipv4Addr::new(127, 0, 0, 1), self.port)).await?;
@@ -71,7 +246,7 @@ impl PianobarEventReceiver {
                 loop {
                     match socket.read_buf(&mut buf).await {
                         // socket closed
-                        Ok(n) if n == 0 => break Ok(buf),
+                        Ok(0) => break Ok(buf),
                         Ok(_) => (),
                         Err(e) => break Err(e),
                     };
@@ -95,13 +270,10 @@ impl PianobarEventReceiver {
                 }
             };
 
-            if let Err(err) = self.update_ui_state.send(event.state.clone()) {
-                log::error!("Error while updating ui state: {}", err);
-            };
-
-            if let Err(err) = self.ui_events.send(event) {
-                log::warn!("Error while broadcasting ui event: {}", err);
-            };
+            if let Some(recorder) = &self.recorder {
+                recorder.record_event(&event);
+            }
+            self.publisher.publish(event);
         }
     }
 }
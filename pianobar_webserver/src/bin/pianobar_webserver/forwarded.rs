@@ -0,0 +1,108 @@
+use crate::http_server::peer_address;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use warp::{Filter, Rejection};
+
+/// A single hop of a forwarding chain
+struct Hop {
+    address: String,
+    ip: Option<IpAddr>,
+}
+
+impl Hop {
+    fn from_peer(peer: SocketAddr) -> Hop {
+        Hop {
+            address: peer.to_string(),
+            ip: Some(peer.ip()),
+        }
+    }
+
+    /// Parses a node of a `Forwarded` or `X-Forwarded-For` header.
+    ///
+    /// Accepts `1.2.3.4`, `1.2.3.4:80`, `[::1]`, `[::1]:80` and `::1`.
+    /// Obfuscated identifiers like `_hidden` or `unknown` are kept, but never trusted.
+    fn from_node(node: &str) -> Hop {
+        let node = node.trim().trim_matches('"');
+        let ip = node
+            .parse::<SocketAddr>()
+            .map(|addr| addr.ip())
+            .or_else(|_| node.trim_start_matches('[').trim_end_matches(']').parse())
+            .ok();
+        Hop {
+            address: node.to_string(),
+            ip,
+        }
+    }
+}
+
+/// Extracts the `for=` nodes of a RFC 7239 `Forwarded` header
+fn parse_forwarded(header: &str) -> Vec<Hop> {
+    header
+        .split(',')
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.split_once('=')?;
+                if key.trim().eq_ignore_ascii_case("for") {
+                    Some(Hop::from_node(value))
+                } else {
+                    None
+                }
+            })
+        })
+        .collect()
+}
+
+fn parse_x_forwarded_for(header: &str) -> Vec<Hop> {
+    header.split(',').map(Hop::from_node).collect()
+}
+
+/// Determines the address of the client, walking the forwarding chain
+/// from the nearest hop backwards for as long as the hops are trusted proxies.
+///
+/// Connections through unix sockets always come from a local reverse proxy,
+/// so they are trusted as well.
+fn resolve_client_address(
+    peer: Option<SocketAddr>,
+    forwarded: Option<String>,
+    x_forwarded_for: Option<String>,
+    trusted_proxies: &[IpAddr],
+) -> Option<String> {
+    let is_trusted = |hop: &Hop| hop.ip.is_some_and(|ip| trusted_proxies.contains(&ip));
+
+    let peer_is_trusted = match peer {
+        Some(peer) => is_trusted(&Hop::from_peer(peer)),
+        None => true,
+    };
+    if !peer_is_trusted {
+        return peer.map(|peer| peer.to_string());
+    }
+
+    // Prefer the standardized header
+    let mut chain = match (forwarded, x_forwarded_for) {
+        (Some(forwarded), _) => parse_forwarded(&forwarded),
+        (None, Some(x_forwarded_for)) => parse_x_forwarded_for(&x_forwarded_for),
+        (None, None) => vec![],
+    };
+
+    while let Some(hop) = chain.pop() {
+        if chain.is_empty() || !is_trusted(&hop) {
+            return Some(hop.address);
+        }
+    }
+
+    peer.map(|peer| peer.to_string())
+}
+
+/// Extracts the address of the client, taking `Forwarded` and `X-Forwarded-For`
+/// headers of trusted reverse proxies into account.
+pub fn client_address(
+    trusted_proxies: Vec<IpAddr>,
+) -> impl Filter<Extract = (Option<String>,), Error = Rejection> + Clone {
+    let trusted_proxies = Arc::new(trusted_proxies);
+    peer_address()
+        .and(warp::header::optional::<String>("forwarded"))
+        .and(warp::header::optional::<String>("x-forwarded-for"))
+        .map(move |peer, forwarded, x_forwarded_for| {
+            resolve_client_address(peer, forwarded, x_forwarded_for, &trusted_proxies)
+        })
+}
@@ -0,0 +1,338 @@
+use anyhow::{anyhow, bail, Result};
+use futures::future::select_all;
+use std::convert::Infallible;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::fs::FileTypeExt;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, UnixListener};
+use tokio_rustls::rustls;
+use tokio_rustls::TlsAcceptor;
+use warp::hyper::server::conn::Http;
+use warp::hyper::service::{service_fn, Service};
+use warp::{Filter, Rejection, Reply};
+
+const TLS_RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// Peers that don't finish the TLS handshake in time get disconnected
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait before accepting again after an error, like running out of file descriptors
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_secs(1);
+
+/// An address the web server listens on
+#[derive(Clone, Debug)]
+pub enum BindAddress {
+    Tcp(SocketAddr),
+    /// `127.0.0.1`, and `::1` if the host supports IPv6
+    Localhost(u16),
+    Unix(PathBuf),
+}
+
+impl std::fmt::Display for BindAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BindAddress::Tcp(addr) => write!(f, "{}", addr),
+            BindAddress::Localhost(port) => write!(f, "localhost:{}", port),
+            BindAddress::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl BindAddress {
+    /// Parses a `--bind` argument. Accepted formats:
+    ///
+    /// * `<ip>:<port>`, `[<ipv6>]:<port>`
+    /// * `<ip>`, using the default port
+    /// * `localhost[:<port>]`, which binds to both `127.0.0.1` and `::1`,
+    ///   or only to `127.0.0.1` on hosts without IPv6
+    /// * `unix:<path>`
+    pub fn parse(value: &str, default_port: u16) -> Result<Vec<BindAddress>> {
+        if let Some(path) = value.strip_prefix("unix:") {
+            if path.is_empty() {
+                bail!("Missing unix socket path: '{}'", value);
+            }
+            return Ok(vec![BindAddress::Unix(PathBuf::from(path))]);
+        }
+
+        if let Some(port) = value.strip_prefix("localhost") {
+            let port = match port.strip_prefix(':') {
+                Some(port) => port.parse()?,
+                None if port.is_empty() => default_port,
+                None => bail!("Invalid bind address: '{}'", value),
+            };
+            return Ok(vec![BindAddress::Localhost(port)]);
+        }
+
+        if let Ok(addr) = value.parse::<SocketAddr>() {
+            return Ok(vec![BindAddress::Tcp(addr)]);
+        }
+
+        let ip = value
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+            .map_err(|_| anyhow!("Invalid bind address: '{}'", value))?;
+        Ok(vec![BindAddress::Tcp((ip, default_port).into())])
+    }
+}
+
+/// The address of the peer of a connection, stored in the request extensions.
+///
+/// `None` for unix socket connections.
+#[derive(Clone, Copy, Debug)]
+struct PeerAddress(Option<SocketAddr>);
+
+/// Extracts the address of the peer that sent the request
+pub fn peer_address() -> impl Filter<Extract = (Option<SocketAddr>,), Error = Infallible> + Clone {
+    warp::ext::optional::<PeerAddress>().map(|addr: Option<PeerAddress>| addr.and_then(|a| a.0))
+}
+
+fn load_tls_config(cert_path: &str, key_path: &str) -> Result<rustls::ServerConfig> {
+    let certs = rustls_pemfile::certs(&mut std::io::BufReader::new(std::fs::File::open(
+        cert_path,
+    )?))?
+    .into_iter()
+    .map(rustls::Certificate)
+    .collect::<Vec<_>>();
+    if certs.is_empty() {
+        bail!("No certificate found in '{}'", cert_path);
+    }
+
+    let mut key_reader = std::io::BufReader::new(std::fs::File::open(key_path)?);
+    let key = loop {
+        match rustls_pemfile::read_one(&mut key_reader)? {
+            Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => break rustls::PrivateKey(key),
+            Some(_) => continue,
+            None => bail!("No private key found in '{}'", key_path),
+        }
+    };
+
+    Ok(rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?)
+}
+
+/// A TLS configuration that gets reloaded when the certificate or key file changes
+#[derive(Clone)]
+pub struct TlsSettings {
+    cert_path: String,
+    key_path: String,
+    config: Arc<RwLock<Arc<rustls::ServerConfig>>>,
+}
+
+impl TlsSettings {
+    pub fn new(cert_path: &str, key_path: &str) -> Result<TlsSettings> {
+        let cert_path = shellexpand::tilde(cert_path).to_string();
+        let key_path = shellexpand::tilde(key_path).to_string();
+        let config = load_tls_config(&cert_path, &key_path)?;
+        Ok(TlsSettings {
+            cert_path,
+            key_path,
+            config: Arc::new(RwLock::new(Arc::new(config))),
+        })
+    }
+
+    fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.config.read().unwrap().clone())
+    }
+
+    fn modification_time(&self) -> Option<(SystemTime, SystemTime)> {
+        let modified = |path: &str| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        Some((modified(&self.cert_path)?, modified(&self.key_path)?))
+    }
+
+    /// Watches the certificate and key file and reloads them on change
+    async fn reload_on_change(&self) -> Result<()> {
+        let mut last_modified = self.modification_time();
+        loop {
+            tokio::time::sleep(TLS_RELOAD_CHECK_INTERVAL).await;
+
+            let modified = self.modification_time();
+            if modified == last_modified {
+                continue;
+            }
+            last_modified = modified;
+
+            match load_tls_config(&self.cert_path, &self.key_path) {
+                Ok(config) => {
+                    log::info!("Reloaded TLS certificate from '{}'", self.cert_path);
+                    *self.config.write().unwrap() = Arc::new(config);
+                }
+                Err(err) => {
+                    log::warn!(
+                        "Unable to reload TLS certificate, keeping the old one: {}",
+                        err
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Serves a single connection with the given warp filter
+async fn serve_connection<F, R, IO>(filter: F, io: IO, peer: Option<SocketAddr>)
+where
+    F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+    R: Reply + 'static,
+    IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = warp::service(filter);
+    let service = service_fn(move |mut request| {
+        request.extensions_mut().insert(PeerAddress(peer));
+        service.clone().call(request)
+    });
+
+    if let Err(err) = Http::new()
+        .serve_connection(io, service)
+        .with_upgrades()
+        .await
+    {
+        log::debug!("connection error: {}", err);
+    }
+}
+
+/// Returns `None` for `optional` addresses the host doesn't have,
+/// like `::1` on hosts without IPv6
+async fn bind_tcp(addr: SocketAddr, optional: bool) -> Result<Option<TcpListener>> {
+    match TcpListener::bind(addr).await {
+        Ok(listener) => Ok(Some(listener)),
+        Err(err)
+            if optional
+                && (err.kind() == ErrorKind::AddrNotAvailable
+                    || err.raw_os_error() == Some(libc::EAFNOSUPPORT)) =>
+        {
+            log::info!("Not listening on {}, it isn't available: {}", addr, err);
+            Ok(None)
+        }
+        Err(err) => bail!("Unable to bind to {}: {}", addr, err),
+    }
+}
+
+async fn serve_tcp<F, R>(filter: F, listener: TcpListener, tls: Option<TlsSettings>) -> Result<()>
+where
+    F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+    R: Reply + 'static,
+{
+    let addr = listener.local_addr()?;
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(err) => {
+                log::warn!("Unable to accept connection on {}: {}", addr, err);
+                tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                continue;
+            }
+        };
+        let filter = filter.clone();
+        match &tls {
+            Some(tls) => {
+                let acceptor = tls.acceptor();
+                tokio::spawn(async move {
+                    match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await
+                    {
+                        Ok(Ok(stream)) => serve_connection(filter, stream, Some(peer)).await,
+                        Ok(Err(err)) => {
+                            log::debug!("TLS handshake with {} failed: {}", peer, err)
+                        }
+                        Err(_) => log::debug!("TLS handshake with {} timed out", peer),
+                    }
+                });
+            }
+            None => {
+                tokio::spawn(serve_connection(filter, stream, Some(peer)));
+            }
+        }
+    }
+}
+
+async fn serve_unix<F, R>(filter: F, path: PathBuf) -> Result<()>
+where
+    F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+    R: Reply + 'static,
+{
+    // Remove the socket of a previous run, but nothing else that might be in the way
+    match std::fs::symlink_metadata(&path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(&path)?,
+        Ok(_) => bail!(
+            "Unable to bind to {}: the file exists and is not a socket",
+            path.display()
+        ),
+        Err(err) if err.kind() == ErrorKind::NotFound => (),
+        Err(err) => bail!("Unable to bind to {}: {}", path.display(), err),
+    }
+    let listener = UnixListener::bind(&path)
+        .map_err(|err| anyhow!("Unable to bind to {}: {}", path.display(), err))?;
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(serve_connection(filter.clone(), stream, None));
+            }
+            Err(err) => {
+                log::warn!("Unable to accept connection on {}: {}", path.display(), err);
+                tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+            }
+        }
+    }
+}
+
+/// Serves the filter on all given addresses.
+///
+/// TCP addresses use TLS if `tls` is given; unix sockets never do.
+pub async fn serve<F, R>(
+    filter: F,
+    addresses: Vec<BindAddress>,
+    tls: Option<TlsSettings>,
+) -> Result<()>
+where
+    F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+    R: Reply + 'static,
+{
+    let mut tasks = vec![];
+    for address in addresses {
+        log::debug!("Listening on {} ...", address);
+        let tcp_addresses: Vec<(SocketAddr, bool)> = match address {
+            BindAddress::Tcp(addr) => vec![(addr, false)],
+            BindAddress::Localhost(port) => vec![
+                ((Ipv4Addr::LOCALHOST, port).into(), false),
+                ((Ipv6Addr::LOCALHOST, port).into(), true),
+            ],
+            BindAddress::Unix(path) => {
+                tasks.push(tokio::spawn(serve_unix(filter.clone(), path)));
+                continue;
+            }
+        };
+        for (addr, optional) in tcp_addresses {
+            if let Some(listener) = bind_tcp(addr, optional).await? {
+                tasks.push(tokio::spawn(serve_tcp(
+                    filter.clone(),
+                    listener,
+                    tls.clone(),
+                )));
+            }
+        }
+    }
+    if tasks.is_empty() {
+        bail!("No address to listen on");
+    }
+
+    let reload_task = async {
+        match &tls {
+            Some(tls) => tls.reload_on_change().await,
+            None => futures::future::pending().await,
+        }
+    };
+
+    // Listeners only end on errors, so stop at the first one that ends
+    tokio::select! {
+        (result, _, _) = select_all(tasks) => result??,
+        result = reload_task => result?,
+    };
+
+    bail!("Web server closed. Should never happen.")
+}
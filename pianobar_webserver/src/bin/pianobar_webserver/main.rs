@@ -1,24 +1,39 @@
+mod auth;
+mod base_path;
 mod config;
+#[cfg(feature = "embedded-webui")]
+mod embedded_webui;
 mod event_receiver;
+mod forwarded;
+mod http_server;
 mod pianobar_controller;
+mod recording;
 mod signal_handler;
 mod websocket;
 
-use std::net::Ipv4Addr;
-
-use anyhow::{anyhow, Result};
+use anyhow::Result;
+use auth::{Authenticator, Role};
+use base_path::BasePath;
 use config::Config;
 use event_receiver::PianobarEventReceiver;
+use http_server::{BindAddress, TlsSettings};
 use log::info;
 use pianobar_controller::plugins::actions::PianobarActions;
 use pianobar_controller::plugins::debug_printer::DebugPrinter;
+use pianobar_controller::plugins::explanations::ExplanationCache;
 use pianobar_controller::plugins::manual_controller::ManualController;
+use pianobar_controller::plugins::now_playing::NowPlayingFallback;
 use pianobar_controller::plugins::player_state::PianobarPlayerStateWatcher;
-use pianobar_controller::{set_pianobar_configs, PianobarController};
+use pianobar_controller::plugins::upcoming::UpcomingSongs;
+use pianobar_controller::{set_pianobar_configs, CommandTimeouts, PianobarController};
+use recording::{Recorder, Replay};
 use signal_handler::handle_interrupt_signals;
+use std::time::Duration;
 use structopt::StructOpt;
-use warp::Filter;
-use websocket::PianobarWebsocket;
+use warp::{Filter, Reply};
+use websocket::{
+    AuditLog, ControlMode, ControlPolicy, ControlSettings, KeepaliveSettings, PianobarWebsocket,
+};
 
 #[tokio::main]
 async fn main() {
@@ -50,52 +65,220 @@ async fn main_with_result() -> Result<()> {
     ))
     .init();
 
+    if config.hash_password {
+        let mut password = String::new();
+        std::io::stdin().read_line(&mut password)?;
+        println!(
+            "{}",
+            auth::hash_password(password.trim_end_matches(&['\r', '\n'][..]))?
+        );
+        return Ok(());
+    }
+
+    let base_path = BasePath::new(&config.base_path)?;
+
+    info!("Load authentication config ...");
+    // Without an auth config everyone is an admin, unless admins would bypass the votes
+    let unauthenticated_role = match config.control_mode {
+        ControlMode::Direct => Role::Admin,
+        ControlMode::Vote => Role::Listener,
+    };
+    let authenticator = Authenticator::new(
+        config.auth_config.as_deref(),
+        unauthenticated_role,
+        base_path.as_prefix(),
+        config.tls_cert.is_some() && config.tls_key.is_some(),
+    )?;
+
+    info!("Load web server config ...");
+    let bind_addresses = if config.bind.is_empty() {
+        vec![BindAddress::Tcp(
+            (std::net::Ipv4Addr::UNSPECIFIED, config.port).into(),
+        )]
+    } else {
+        let mut addresses = vec![];
+        for bind in &config.bind {
+            addresses.extend(BindAddress::parse(bind, config.port)?);
+        }
+        addresses
+    };
+    let tls_settings = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => Some(TlsSettings::new(cert, key)?),
+        _ => None,
+    };
+
+    let recorder = match &config.record {
+        Some(path) => {
+            info!("Record pianobar session to '{}' ...", path);
+            Some(Recorder::new(path)?)
+        }
+        None => None,
+    };
+
+    let command_timeouts = CommandTimeouts::new(&config.command_timeout);
+
     info!("Create event handler ...");
-    let event_receiver = PianobarEventReceiver::new(&config);
+    let event_receiver = PianobarEventReceiver::new(&config, recorder.clone());
 
-    info!("Write pianobar config ...");
-    set_pianobar_configs(&config.pianobar_config)?;
+    let (pianobar_controller, _pianobar_process, mut replay, events_available) =
+        match &config.replay {
+            Some(path) => {
+                info!("Replay pianobar session from '{}' ...", path);
+                let (replay, stdout) = Replay::load(path)?;
+                // The input goes nowhere, the recording already contains pianobar's reactions
+                let pianobar_controller = PianobarController::from_streams(
+                    Box::new(tokio::io::sink()),
+                    Box::new(stdout),
+                    None,
+                    command_timeouts,
+                );
+                let events_available = replay.has_events();
+                (pianobar_controller, None, Some(replay), events_available)
+            }
+            None => {
+                info!("Write pianobar config ...");
+                let events_available = set_pianobar_configs(&config.pianobar_config)?;
 
-    info!("Create pianobar controller ...");
-    // Create pianobar_controller object.
-    // This also yields a pianobar_process object, which has the function of killing the
-    // pianobar process at the end of the current scope.
-    let (pianobar_controller, _pianobar_process) =
-        PianobarController::start_pianobar_process(&config.pianobar_path)?;
+                info!("Create pianobar controller ...");
+                // Create pianobar_controller object.
+                // This also yields a pianobar_process object, which has the function of
+                // killing the pianobar process at the end of the current scope.
+                let (pianobar_controller, pianobar_process) =
+                    PianobarController::start_pianobar_process(
+                        &config.pianobar_path,
+                        recorder,
+                        command_timeouts,
+                    )?;
+                (
+                    pianobar_controller,
+                    Some(pianobar_process),
+                    None,
+                    events_available,
+                )
+            }
+        };
+    // Create explanation cache, to ask pianobar only once per song
+    let explanation_cache = ExplanationCache::new(
+        &pianobar_controller,
+        event_receiver.get_publisher(),
+        event_receiver
+            .get_event_source_creator()
+            .create_event_source()
+            .ui_events,
+    );
+    // Create upcoming songs watcher, to keep the queue in the ui state
+    let upcoming_songs = UpcomingSongs::new(
+        &pianobar_controller,
+        event_receiver.get_publisher(),
+        event_receiver
+            .get_event_source_creator()
+            .create_event_source()
+            .ui_events,
+    );
     // Create actions object, to control the pianobar process
-    let pianobar_actions = PianobarActions::new(&pianobar_controller);
+    let pianobar_actions =
+        PianobarActions::new(&pianobar_controller, &explanation_cache, &upcoming_songs);
     // Create state watcher, to stream pianobar player state to websocket
-    let mut pianobar_state = PianobarPlayerStateWatcher::new(&pianobar_controller);
+    let mut pianobar_state = PianobarPlayerStateWatcher::new(
+        &pianobar_controller,
+        event_receiver
+            .get_event_source_creator()
+            .create_event_source()
+            .ui_events,
+    );
+
+    // Create control policy, to decide which actions need votes
+    let control_policy = ControlPolicy::new(
+        ControlSettings::new(
+            config.control_mode,
+            config.vote_quorum,
+            Duration::from_secs(config.vote_window),
+        )?,
+        &pianobar_actions,
+    );
+
+    // Create audit log, to record who did what
+    let audit_log = AuditLog::new(
+        config.audit_log.as_deref(),
+        config.activity_channel_capacity.get(),
+    )?;
 
     info!("Create websocket ...");
     let websocket = PianobarWebsocket::new(
         event_receiver.get_event_source_creator(),
-        pianobar_state.subscribe(),
+        &pianobar_state,
         pianobar_actions,
+        control_policy.clone(),
+        audit_log,
+        KeepaliveSettings {
+            ping_interval: Some(config.ping_interval)
+                .filter(|seconds| *seconds > 0)
+                .map(Duration::from_secs),
+            max_missed_pongs: config.max_missed_pongs.get(),
+            idle_timeout: config.idle_timeout.map(Duration::from_secs),
+        },
+        config.send_queue_capacity.get(),
     );
 
     // Create Websocket route
-    let websocket_route = websocket.create_route("ws");
+    let websocket_route = websocket.create_route(
+        "ws",
+        forwarded::client_address(config.trusted_proxy.clone()),
+        authenticator.with_identity(),
+    );
 
-    // Create web app route to serve static web app files if nothing else matches
-    let webpage_route = config
-        .webpage_folder
-        .clone()
-        .map(|webpage_folder| warp::get().and(warp::fs::dir(webpage_folder)));
+    // Create login and logout routes
+    let auth_routes = authenticator.create_routes();
+
+    // Create web app route to serve static web app files if nothing else matches.
+    // The webpage folder overrides the embedded web ui.
+    let webpage_route = match config.webpage_folder.clone() {
+        Some(webpage_folder) => Some(
+            warp::get()
+                .and(warp::fs::dir(webpage_folder))
+                .map(Reply::into_response)
+                .boxed(),
+        ),
+        #[cfg(feature = "embedded-webui")]
+        None => Some(embedded_webui::route()),
+        #[cfg(not(feature = "embedded-webui"))]
+        None => None,
+    };
 
     // Create the webserver task
     let webserver_task = async move {
-        let addr = (Ipv4Addr::UNSPECIFIED, config.port);
         if let Some(webpage_route) = webpage_route {
-            log::debug!("Serve websocket and webpage at port {} ...", config.port);
-            warp::serve(websocket_route.or(webpage_route))
-                .run(addr)
-                .await;
+            log::debug!(
+                "Serve websocket and webpage at {} ...",
+                base_path.as_prefix()
+            );
+            http_server::serve(
+                base_path
+                    .redirect()
+                    .or(base_path
+                        .filter()
+                        .and(websocket_route.or(auth_routes).or(webpage_route)))
+                    .recover(auth::handle_rejection),
+                bind_addresses,
+                tls_settings,
+            )
+            .await
         } else {
-            log::debug!("Serve websocket at port {} ...", config.port);
-            warp::serve(websocket_route).run(addr).await;
+            log::warn!(
+                "No web ui to serve, only the websocket. Pass --webpage-folder, \
+                 or build with the 'embedded-webui' feature."
+            );
+            log::debug!("Serve websocket at {} ...", base_path.as_prefix());
+            http_server::serve(
+                base_path
+                    .redirect()
+                    .or(base_path.filter().and(websocket_route.or(auth_routes)))
+                    .recover(auth::handle_rejection),
+                bind_addresses,
+                tls_settings,
+            )
+            .await
         }
-        Result::<()>::Err(anyhow!("Web server closed. Should never happen."))
     };
 
     // Additional plugins
@@ -103,6 +286,28 @@ async fn main_with_result() -> Result<()> {
     let mut debug_printer = DebugPrinter::new(&pianobar_controller);
     // Lets the player get controlled via keyboard
     let mut manual_controller = ManualController::new(&pianobar_controller);
+    // Reads the now playing song from pianobar's output, if pianobar can't report events
+    let mut now_playing_fallback = if events_available {
+        None
+    } else {
+        Some(NowPlayingFallback::new(
+            &pianobar_controller,
+            event_receiver.get_publisher(),
+        ))
+    };
+    let now_playing_fallback_task = async {
+        match &mut now_playing_fallback {
+            Some(now_playing_fallback) => now_playing_fallback.run().await,
+            None => Ok(()),
+        }
+    };
+    // Plays the recorded session in place of pianobar
+    let replay_task = async {
+        match &mut replay {
+            Some(replay) => replay.run(event_receiver.get_publisher()).await,
+            None => Ok(()),
+        }
+    };
 
     info!("Starting tasks ...");
     let result = tokio::try_join!(
@@ -110,9 +315,19 @@ async fn main_with_result() -> Result<()> {
         event_receiver.run(),
         pianobar_controller.run(),
         pianobar_state.run(),
+        explanation_cache.run(),
+        upcoming_songs.run(),
+        control_policy.run(
+            event_receiver
+                .get_event_source_creator()
+                .create_event_source()
+                .ui_events
+        ),
         handle_interrupt_signals(),
         debug_printer.run(),
         manual_controller.run(),
+        now_playing_fallback_task,
+        replay_task,
     );
 
     log::info!("Shut down ...");
@@ -1,35 +1,51 @@
 use super::messages::{parse_pianobar_messages, PianobarMessage};
+use super::scheduler::{
+    CommandResponse, CommandScheduler, CommandTimeouts, PianobarCommand, QueuedCommand,
+};
+use crate::recording::Recorder;
 
 use anyhow::{anyhow, bail, Result};
-use log;
-use std::{process::Stdio, sync::Arc};
+use std::{
+    process::Stdio,
+    sync::atomic::{AtomicU64, Ordering},
+    sync::Arc,
+};
 use tokio::{
-    io::AsyncWriteExt,
-    process::{Child, ChildStdin, ChildStdout, Command},
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    process::{Child, Command},
     sync::broadcast,
+    sync::mpsc,
     sync::Mutex,
 };
 
-/// Provides an interface that can be used by function calls to send
-/// commands to the pianobar process.
-///
-/// Solved as a separate struct instead of an mpsc, because it's
-/// important that only one person can communicate with the process
-/// at any given time. This is ensured by wrapping this struct in a
-/// mutex.
+/// pianobar's stdin, or whatever stands in for it
+pub type PianobarInput = Box<dyn AsyncWrite + Unpin + Send>;
+/// pianobar's stdout, or whatever stands in for it
+pub type PianobarOutput = Box<dyn AsyncRead + Unpin + Send>;
+
+/// Writes to the pianobar process. Owned by the `CommandScheduler`,
+/// which makes sure only one command gets written at any given time.
 pub struct PianobarActor {
-    pianobar_stdin: ChildStdin,
+    pianobar_stdin: PianobarInput,
+    recorder: Option<Recorder>,
 }
 
 impl PianobarActor {
-    pub fn new(pianobar_stdin: ChildStdin) -> PianobarActor {
-        PianobarActor { pianobar_stdin }
+    pub fn new(pianobar_stdin: PianobarInput, recorder: Option<Recorder>) -> PianobarActor {
+        PianobarActor {
+            pianobar_stdin,
+            recorder,
+        }
     }
 
     pub async fn write(&mut self, message: &str) -> Result<()> {
+        if let Some(recorder) = &self.recorder {
+            recorder.record_stdin(message);
+        }
+
         // Get slice to send
         let mut send_buffer = message.as_bytes();
-        while send_buffer.len() > 0 {
+        while !send_buffer.is_empty() {
             let num_sent = self.pianobar_stdin.write(send_buffer).await?;
             if num_sent == 0 {
                 bail!("Unable to write to pianobar process");
@@ -46,13 +62,13 @@ impl PianobarActor {
 }
 
 pub struct PianobarStdoutHandler {
-    pianobar_stdout: ChildStdout,
+    pianobar_stdout: PianobarOutput,
     pianobar_received_messages: broadcast::Sender<PianobarMessage>,
 }
 
 impl PianobarStdoutHandler {
     fn new(
-        pianobar_stdout: ChildStdout,
+        pianobar_stdout: PianobarOutput,
         pianobar_received_messages: broadcast::Sender<PianobarMessage>,
     ) -> Self {
         Self {
@@ -67,16 +83,32 @@ impl PianobarStdoutHandler {
     }
 }
 
+/// Commands that change the playback, reported after they were sent to pianobar
+#[derive(Clone, Copy, Debug)]
+pub enum PlaybackCommand {
+    Pause,
+    Resume,
+    TogglePause,
+}
+
 #[derive(Clone)]
 pub struct PianobarController {
-    // Wrapped in Mutex to prevent multiple people from sending simultaneously.
-    pianobar_actor: Arc<Mutex<PianobarActor>>,
+    commands: mpsc::UnboundedSender<QueuedCommand>,
+    next_command_id: Arc<AtomicU64>,
     pianobar_received_messages: broadcast::Sender<PianobarMessage>,
+    playback_commands: broadcast::Sender<PlaybackCommand>,
     pianobar_stdout_handler: Arc<Mutex<PianobarStdoutHandler>>,
+    command_scheduler: Arc<Mutex<CommandScheduler>>,
 }
 
 impl PianobarController {
-    pub fn start_pianobar_process(pianobar_command: &str) -> Result<(PianobarController, Child)> {
+    /// Starts pianobar. With a recorder, everything that gets written to and read
+    /// from pianobar gets recorded.
+    pub fn start_pianobar_process(
+        pianobar_command: &str,
+        recorder: Option<Recorder>,
+        timeouts: CommandTimeouts,
+    ) -> Result<(PianobarController, Child)> {
         // Start the pianobar process and get the handle to the stdin and stdout streams
         log::info!("Start pianobar process ...");
         let mut pianobar_process = Command::new(pianobar_command)
@@ -92,37 +124,83 @@ impl PianobarController {
             .stdout
             .take()
             .ok_or(anyhow!("Unable to get pianobar stdout."))?;
+        let pianobar_stdout: PianobarOutput = match &recorder {
+            Some(recorder) => Box::new(recorder.record_reads(pianobar_stdout)),
+            None => Box::new(pianobar_stdout),
+        };
 
+        Ok((
+            PianobarController::from_streams(
+                Box::new(pianobar_stdin),
+                pianobar_stdout,
+                recorder,
+                timeouts,
+            ),
+            pianobar_process,
+        ))
+    }
+
+    /// Talks to something that behaves like pianobar, like a replay
+    pub fn from_streams(
+        pianobar_stdin: PianobarInput,
+        pianobar_stdout: PianobarOutput,
+        recorder: Option<Recorder>,
+        timeouts: CommandTimeouts,
+    ) -> PianobarController {
         // Create a broadcast channel for the communication with the stdout task
         let (pianobar_received_messages, _) = broadcast::channel(20);
+        let (playback_commands, _) = broadcast::channel(20);
 
         // Spawn the stdout handler task
         let pianobar_stdout_handler =
             PianobarStdoutHandler::new(pianobar_stdout, pianobar_received_messages.clone());
 
-        // Create the pianobar actor
-        let pianobar_actor = Arc::new(Mutex::new(PianobarActor::new(pianobar_stdin)));
+        // Create the scheduler, the only one writing to pianobar
+        let (commands, commands_receiver) = mpsc::unbounded_channel();
+        let command_scheduler = CommandScheduler::new(
+            PianobarActor::new(pianobar_stdin, recorder),
+            commands_receiver,
+            pianobar_received_messages.subscribe(),
+            playback_commands.clone(),
+            timeouts,
+        );
 
         // Create the controller object
-        Ok((
-            PianobarController {
-                pianobar_actor,
-                pianobar_received_messages,
-                pianobar_stdout_handler: Arc::new(Mutex::new(pianobar_stdout_handler)),
-            },
-            pianobar_process,
-        ))
+        PianobarController {
+            commands,
+            next_command_id: Arc::new(AtomicU64::new(1)),
+            pianobar_received_messages,
+            playback_commands,
+            pianobar_stdout_handler: Arc::new(Mutex::new(pianobar_stdout_handler)),
+            command_scheduler: Arc::new(Mutex::new(command_scheduler)),
+        }
     }
 
-    pub async fn take_actor(&self) -> tokio::sync::MutexGuard<'_, PianobarActor> {
-        self.pianobar_actor.lock().await
+    /// Queues the command and waits until pianobar handled it.
+    /// Dropping the returned future cancels the command.
+    pub async fn execute(&self, command: PianobarCommand) -> Result<CommandResponse> {
+        let id = self.next_command_id.fetch_add(1, Ordering::Relaxed);
+        let (queued, response) = QueuedCommand::new(id, command);
+        self.commands
+            .send(queued)
+            .map_err(|_| anyhow!("Pianobar command queue closed."))?;
+        response
+            .await
+            .map_err(|_| anyhow!("Pianobar command #{} was dropped.", id))?
     }
 
     pub fn subscribe(&self) -> broadcast::Receiver<PianobarMessage> {
         self.pianobar_received_messages.subscribe()
     }
 
+    pub fn subscribe_playback_commands(&self) -> broadcast::Receiver<PlaybackCommand> {
+        self.playback_commands.subscribe()
+    }
+
     pub async fn run(&self) -> Result<()> {
-        self.pianobar_stdout_handler.lock().await.run().await
+        let mut pianobar_stdout_handler = self.pianobar_stdout_handler.lock().await;
+        let mut command_scheduler = self.command_scheduler.lock().await;
+        tokio::try_join!(pianobar_stdout_handler.run(), command_scheduler.run())?;
+        Ok(())
     }
 }
@@ -1,18 +1,42 @@
 use anyhow::{anyhow, bail, Result};
+use bytes::{Buf, BytesMut};
+use futures::StreamExt;
 use regex::Regex;
 use std::cmp::min;
-use tokio::{io::AsyncReadExt, process::ChildStdout, sync::broadcast};
+use tokio::{io::AsyncRead, sync::broadcast};
+use tokio_util::codec::{Decoder, FramedRead};
 
-#[derive(Clone, Debug)]
+/// Everything pianobar prints. Not every field is used by the plugins yet.
+#[allow(dead_code)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum PianobarMessage {
     SongTime {
         current: u32,
         total: u32,
-        paused: bool,
+        /// The same time was repeated, so the song doesn't advance.
+        /// Either it's paused or playback stalled.
+        stalled: bool,
     },
     ListEntrySong {
         artist: String,
         title: String,
+        /// Empty if pianobar doesn't know the `%l` of `format_list_song`
+        album: String,
+        /// Like the `rating` of `NowPlayingSong`
+        rating: u8,
+    },
+    ListEntryStation {
+        id: usize,
+        name: String,
+        /// Part of the QuickMix
+        in_quickmix: bool,
+        is_quickmix: bool,
+        /// Created by someone else
+        shared: bool,
+    },
+    /// Any other list entry, like search results
+    ListEntry {
+        message: String,
     },
     Question {
         message: String,
@@ -20,34 +44,223 @@ pub enum PianobarMessage {
     Info {
         message: String,
     },
+    /// pianobar started an operation that takes a while, like `Login...`
+    Wait {
+        message: String,
+    },
+    /// Unformatted output, like the `Ok.` that ends a wait
+    Plain {
+        message: String,
+    },
+    Error {
+        message: String,
+    },
+    Debug {
+        message: String,
+    },
+    NowPlayingSong {
+        title: String,
+        artist: String,
+        album: String,
+        /// Like the `rating` of ui events: 0 = none, 1 = loved, 2 = banned, 3 = tired
+        rating: u8,
+        /// Only set if it differs from the current station, like in QuickMix
+        station: String,
+        detail_url: String,
+    },
+    NowPlayingStation {
+        name: String,
+        id: String,
+    },
 }
 
-struct PianobarMessageParser {
-    buffer: String,
+/// Every message is framed like `\x1e\x1e[[#TYPE#\x1eARGUMENT\x1e...\x1e\x1e#]]`,
+/// see `set_message_formats`
+const MESSAGE_START: &[u8] = b"\x1e\x1e[[#";
+const MESSAGE_END: &[u8] = b"\x1e\x1e#]]";
+const ARGUMENT_SEPARATOR: u8 = 0x1e;
+
+/// Messages should never be that big. Larger frames are treated as malformed,
+/// which prevents a potential out-of-memory situation if pianobar
+/// decides to send really large messages.
+const MAX_MESSAGE_LENGTH: usize = 1000;
+
+/// Where the decoder is within a terminal escape sequence
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum EscapeState {
+    None,
+    /// After `ESC`
+    Escape,
+    /// Within a control sequence, like `ESC [ 2 K`
+    ControlSequence,
+    /// Within a string sequence, like `ESC ] 0 ; title BEL`
+    String,
+    /// After `ESC` within a string sequence, which usually terminates it
+    StringEscape,
+}
+
+/// Decodes the messages in pianobar's output.
+///
+/// Works on bytes, so characters split across reads don't matter.
+/// Terminal escape sequences and control codes get stripped before framing,
+/// everything outside of message frames is ignored.
+struct PianobarMessageCodec {
+    /// The stripped output that wasn't decoded yet
+    buffer: Vec<u8>,
+    escape_state: EscapeState,
     message_time_regex: Regex,
+    list_entry_station_regex: Regex,
     message_time_previous: (u32, u32),
     message_time_repeat_counter: u32,
 }
 
-fn message_start_differs_from(message: &str, expected: &str) -> bool {
-    let num_chars = min(message.len(), expected.len());
-    message[..num_chars] != expected[..num_chars]
+/// Like the `rating` of ui events: 0 = none, 1 = loved, 2 = banned, 3 = tired.
+/// The icons are set by `set_message_formats`.
+fn parse_rating(icon: &str) -> u8 {
+    match icon {
+        "<3" => 1,
+        "</3" => 2,
+        "zZ" => 3,
+        _ => 0,
+    }
 }
 
-impl PianobarMessageParser {
-    pub fn new() -> PianobarMessageParser {
-        PianobarMessageParser {
-            buffer: String::new(),
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// The length of the longest end of `data` that `pattern` starts with
+fn partial_match_at_end(data: &[u8], pattern: &[u8]) -> usize {
+    (1..min(data.len() + 1, pattern.len()))
+        .rev()
+        .find(|length| data[data.len() - length..] == pattern[..*length])
+        .unwrap_or(0)
+}
+
+impl PianobarMessageCodec {
+    pub fn new() -> PianobarMessageCodec {
+        PianobarMessageCodec {
+            buffer: Vec::new(),
+            escape_state: EscapeState::None,
             message_time_regex: Regex::new(r"^-?(\d+):(\d+)/(\d+):(\d+)$").unwrap(),
+            // pianobar lists stations like ` 3) qQS Station name`
+            list_entry_station_regex: Regex::new(r"^(\d+)\) ([q ])([Q ])([S ]) (.+)$").unwrap(),
             message_time_previous: (0, 0),
             message_time_repeat_counter: 0,
         }
     }
 
+    /// Appends the byte to the buffer, unless it's part of an escape sequence or a control code
+    fn push_stripped(&mut self, byte: u8) {
+        const ESC: u8 = 0x1b;
+        const BEL: u8 = 0x07;
+        // Control codes within escape sequences mean the sequence is broken.
+        // Abort it, so it can't swallow the frame markers.
+        let aborts_sequence = byte < 0x20 && byte != ESC && byte != BEL;
+
+        self.escape_state = match self.escape_state {
+            EscapeState::Escape if !aborts_sequence => {
+                match byte {
+                    b'[' => EscapeState::ControlSequence,
+                    b']' | b'P' | b'X' | b'^' | b'_' => EscapeState::String,
+                    // Intermediate bytes, like the `(` of `ESC ( B`
+                    0x20..=0x2f => EscapeState::Escape,
+                    _ => EscapeState::None,
+                }
+            }
+            EscapeState::ControlSequence if !aborts_sequence => match byte {
+                0x40..=0x7e => EscapeState::None,
+                _ => EscapeState::ControlSequence,
+            },
+            EscapeState::String if !aborts_sequence => match byte {
+                BEL => EscapeState::None,
+                ESC => EscapeState::StringEscape,
+                _ => EscapeState::String,
+            },
+            EscapeState::StringEscape if !aborts_sequence => EscapeState::None,
+            _ => {
+                if byte == ESC {
+                    EscapeState::Escape
+                } else {
+                    if byte == ARGUMENT_SEPARATOR || (byte >= 0x20 && byte != 0x7f) {
+                        self.buffer.push(byte);
+                    }
+                    EscapeState::None
+                }
+            }
+        };
+    }
+
+    /// Removes the next complete frame from the buffer and returns its content.
+    /// Drops everything that can't become part of a frame.
+    fn next_frame(&mut self) -> Option<Vec<u8>> {
+        loop {
+            let start = match find(&self.buffer, MESSAGE_START) {
+                Some(start) => start,
+                None => {
+                    // Keep what could become the start of a frame
+                    let keep = partial_match_at_end(&self.buffer, MESSAGE_START);
+                    self.buffer.drain(..self.buffer.len() - keep);
+                    return None;
+                }
+            };
+            self.buffer.drain(..start);
+
+            let content = &self.buffer[MESSAGE_START.len()..];
+            let end = find(content, MESSAGE_END);
+            // A frame that starts before the current one ended means the current one got cut off
+            let next_start = find(content, MESSAGE_START);
+            match (end, next_start) {
+                (Some(end), next_start) if next_start.is_none_or(|next| end < next) => {
+                    let frame = content[..end].to_vec();
+                    self.buffer
+                        .drain(..MESSAGE_START.len() + end + MESSAGE_END.len());
+                    // Check complete frames as well, so it doesn't matter how the output was read
+                    if frame.len() > MAX_MESSAGE_LENGTH {
+                        log::warn!("Invalid message received: frame too long");
+                        continue;
+                    }
+                    return Some(frame);
+                }
+                (_, Some(next_start)) => {
+                    log::warn!("Invalid message received: incomplete frame");
+                    self.buffer.drain(..MESSAGE_START.len() + next_start);
+                }
+                (_, None)
+                    if self.buffer.len()
+                        > MESSAGE_START.len() + MAX_MESSAGE_LENGTH + MESSAGE_END.len() =>
+                {
+                    log::warn!("Invalid message received: frame too long");
+                    self.buffer.drain(..MESSAGE_START.len());
+                }
+                (_, None) => return None,
+            }
+        }
+    }
+
+    /// Parses the content of a frame, like `TYPE#\x1eARGUMENT`
+    fn process_frame(&mut self, frame: &[u8]) -> Result<PianobarMessage> {
+        // Invalid UTF-8 only affects the characters in question
+        let frame = String::from_utf8_lossy(frame);
+        let mut message_parts = frame
+            .split(ARGUMENT_SEPARATOR as char)
+            .map(|s| s.trim().to_string());
+
+        let message_type = message_parts.next().unwrap_or_default();
+        let message_type = match message_type.strip_suffix('#') {
+            Some(message_type) if !message_type.is_empty() => message_type,
+            _ => bail!("Invalid type string"),
+        };
+
+        self.process_message(message_type, &message_parts.collect::<Vec<_>>())
+    }
+
     fn process_message_time(&mut self, arguments: &[String]) -> Result<PianobarMessage> {
         let parsed_arguments = self
             .message_time_regex
-            .captures(arguments.get(0).ok_or(anyhow!("Not enough arguments"))?)
+            .captures(arguments.first().ok_or(anyhow!("Not enough arguments"))?)
             .ok_or(anyhow!("Argument format does not match."))?;
 
         let time_left = parsed_arguments
@@ -80,7 +293,7 @@ impl PianobarMessageParser {
 
         let time_current = time_total - time_left;
 
-        // Compute 'paused' info
+        // Compute 'stalled' info
         let (prev_current, prev_total) = self.message_time_previous;
         if (prev_current == time_current) && (prev_total == time_total) {
             self.message_time_repeat_counter = min(self.message_time_repeat_counter + 1, 10);
@@ -88,40 +301,94 @@ impl PianobarMessageParser {
             self.message_time_repeat_counter = 0;
         }
         self.message_time_previous = (time_current, time_total);
-        // Only set 'paused' when time was repeated at least twice.
+        // Only set 'stalled' when time was repeated at least twice.
         // Pianobar has a bug where due to time jitter it repeats timestamps
         // once, so don't count that. Otherwise it would sometimes oscillate
-        // between 'play' and 'paused'
-        let paused = self.message_time_repeat_counter >= 2;
+        // between 'play' and 'stalled'
+        let stalled = self.message_time_repeat_counter >= 2;
 
         Ok(PianobarMessage::SongTime {
             current: time_current,
             total: time_total,
-            paused,
+            stalled,
         })
     }
 
+    /// Song entries are marked by `set_message_formats`, everything else is
+    /// pianobar's own formatting
     fn process_message_list_entry(&mut self, arguments: &[String]) -> Result<PianobarMessage> {
-        if let [artist, title] = arguments {
-            Ok(PianobarMessage::ListEntrySong {
-                artist: artist.clone(),
-                title: title.clone(),
-            })
-        } else {
-            Err(anyhow!("Invalid number of arguments!"))
+        match arguments {
+            [marker, artist, title, rating, album] if marker == "SONG" => {
+                Ok(PianobarMessage::ListEntrySong {
+                    artist: artist.clone(),
+                    title: title.clone(),
+                    // pianobar prints placeholders it doesn't know as they are
+                    album: if album == "%l" {
+                        String::new()
+                    } else {
+                        album.clone()
+                    },
+                    rating: parse_rating(rating),
+                })
+            }
+            [message] => Ok(match self.list_entry_station_regex.captures(message) {
+                Some(station) => PianobarMessage::ListEntryStation {
+                    id: station[1].parse()?,
+                    name: station[5].to_string(),
+                    in_quickmix: &station[2] == "q",
+                    is_quickmix: &station[3] == "Q",
+                    shared: &station[4] == "S",
+                },
+                None => PianobarMessage::ListEntry {
+                    message: message.clone(),
+                },
+            }),
+            _ => Err(anyhow!("Invalid number of arguments!")),
+        }
+    }
+
+    fn process_message_now_playing(&mut self, arguments: &[String]) -> Result<PianobarMessage> {
+        match arguments {
+            [marker, title, artist, album, rating, station, detail_url] if marker == "SONG" => {
+                Ok(PianobarMessage::NowPlayingSong {
+                    title: title.clone(),
+                    artist: artist.clone(),
+                    album: album.clone(),
+                    rating: parse_rating(rating),
+                    station: station.clone(),
+                    detail_url: detail_url.clone(),
+                })
+            }
+            [marker, name, id] if marker == "STATION" => Ok(PianobarMessage::NowPlayingStation {
+                name: name.clone(),
+                id: id.clone(),
+            }),
+            _ => Err(anyhow!("Invalid now playing message!")),
         }
     }
 
+    fn first_argument(arguments: &[String]) -> Result<String> {
+        Ok(arguments
+            .first()
+            .ok_or(anyhow!("Missing argument"))?
+            .clone())
+    }
+
     fn process_message_question(&mut self, arguments: &[String]) -> Result<PianobarMessage> {
         Ok(PianobarMessage::Question {
-            message: arguments.get(0).ok_or(anyhow!("Missing argument"))?.clone(),
+            message: Self::first_argument(arguments)?,
         })
     }
 
+    /// pianobar announces operations that take a while like `Login... `,
+    /// and reports their outcome as plain message
     fn process_message_info(&mut self, arguments: &[String]) -> Result<PianobarMessage> {
-        Ok(PianobarMessage::Info {
-            message: arguments.get(0).ok_or(anyhow!("Missing argument"))?.clone(),
-        })
+        let message = Self::first_argument(arguments)?;
+        if message.ends_with("...") {
+            Ok(PianobarMessage::Wait { message })
+        } else {
+            Ok(PianobarMessage::Info { message })
+        }
     }
 
     fn process_message(
@@ -136,98 +403,333 @@ impl PianobarMessageParser {
         );
         match message_type {
             "TIME" => self.process_message_time(message_arguments),
-            "LIST_ENTRY_SONG" => self.process_message_list_entry(message_arguments),
+            "LIST" => self.process_message_list_entry(message_arguments),
+            "NOWPLAYING" => self.process_message_now_playing(message_arguments),
             "QUESTION" => self.process_message_question(message_arguments),
             "INFO" => self.process_message_info(message_arguments),
+            "NONE" => Ok(PianobarMessage::Plain {
+                message: Self::first_argument(message_arguments)?,
+            }),
+            "ERROR" => Ok(PianobarMessage::Error {
+                message: Self::first_argument(message_arguments)?,
+            }),
+            "DEBUG" => Ok(PianobarMessage::Debug {
+                message: Self::first_argument(message_arguments)?,
+            }),
             _ => bail!("Unknown message type received: {}", message_type),
         }
     }
+}
 
-    fn process(&mut self, ch: char) -> Option<PianobarMessage> {
-        self.buffer.push(ch);
+impl Decoder for PianobarMessageCodec {
+    type Item = PianobarMessage;
+    type Error = anyhow::Error;
 
-        // Reset if the buffer grows too large.
-        // Messages should never be that big.
-        // This prevents a potential out-of-memory situation
-        // if pianobar decides to send really large messages.
-        if self.buffer.len() > 1000 {
-            self.buffer = self.buffer[500..].to_string();
-            return None;
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<PianobarMessage>> {
+        if !src.is_empty() {
+            // Print to console, for debugging
+            log::trace!("\n{}", String::from_utf8_lossy(src));
+            for &byte in src.iter() {
+                self.push_stripped(byte);
+            }
+            src.advance(src.len());
         }
 
-        // Reset if the message does not start with "\x1e\x1e[[#"
-        if message_start_differs_from(&self.buffer, "\x1e\x1e[[#") {
-            self.buffer = String::new();
-            return None;
+        while let Some(frame) = self.next_frame() {
+            match self.process_frame(&frame) {
+                Ok(message) => return Ok(Some(message)),
+                Err(err) => log::warn!("Failed to parse message: {}", err),
+            }
         }
+        Ok(None)
+    }
 
-        // Message is not yet over, keep reading
-        if !self.buffer.ends_with("\x1e\x1e#]]") {
-            return None;
-        }
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<PianobarMessage>> {
+        // Incomplete frames at the end are simply lost
+        self.decode(src)
+    }
+}
 
-        // Seems like we have a message.
-        // Parse it and then reset the buffer.
-        let message_parts = self.buffer[2..(self.buffer.len() - 5)]
-            .split("\x1e")
-            .map(|s| s.trim().to_string())
-            .collect::<Vec<_>>();
-        self.buffer = String::new();
-
-        // Get message type
-        let message_type = {
-            let message_type_part = &message_parts[0];
-            if !message_type_part.starts_with("[[#")
-                || !message_type_part.ends_with("#")
-                || message_type_part.len() < 4
-            {
-                log::warn!("Invalid message received: invalid type string");
-                return None;
+pub async fn parse_pianobar_messages(
+    pianobar_stream: &mut (impl AsyncRead + Unpin),
+    pianobar_received_messages: &broadcast::Sender<PianobarMessage>,
+) -> Result<()> {
+    let mut messages = FramedRead::new(pianobar_stream, PianobarMessageCodec::new());
+    while let Some(parsed_message) = messages.next().await {
+        // The message parser found a message, send it to all listeners
+        match pianobar_received_messages.send(parsed_message?) {
+            Ok(_num_receivers) => {
+                //log::debug!("Sent pianobar message to {} listeners.", num_receivers)
+            }
+            Err(broadcast::error::SendError(msg)) => {
+                log::error!("No receiver for message: {:?}", msg);
             }
-            &message_type_part[3..message_type_part.len() - 1]
         };
+    }
+    bail!("pianobar program closed!");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// pianobar's output while logging in, starting a station and playing a song,
+    /// with the formats of `set_message_formats`
+    const TRANSCRIPT: &[u8] = b"\
+        \x1e\x1e[[#NONE#\x1eWelcome to pianobar (2022.04.01)! Press ? for a list of commands.\n\x1e\x1e#]]\
+        \x1b[2K\x1e\x1e[[#INFO#\x1eLogin... \x1e\x1e#]]\
+        \x1e\x1e[[#NONE#\x1eOk.\n\x1e\x1e#]]\
+        \x1b[2K\x1e\x1e[[#INFO#\x1eGet stations... \x1e\x1e#]]\
+        \x1e\x1e[[#NONE#\x1eOk.\n\x1e\x1e#]]\
+        \x1b[2K\x1e\x1e[[#LIST#\x1e 0)  Q  QuickMix\n\x1e\x1e#]]\
+        \x1b[2K\x1e\x1e[[#LIST#\x1e 1) q   Caf\xc3\xa9 Jazz \xe2\x98\x95\n\x1e\x1e#]]\
+        \x1b[2K\x1e\x1e[[#LIST#\x1e 2) q S Sigur R\xc3\xb3s Radio \xf0\x9f\x8e\xb9\n\x1e\x1e#]]\
+        \x1b[2K\x1e\x1e[[#QUESTION#\x1eSelect station: \x1e\x1e#]]\
+        \x1b[2K\x1e\x1e[[#NOWPLAYING#\x1eSTATION\x1eSigur R\xc3\xb3s Radio \xf0\x9f\x8e\xb9\x1e4242\x1e\x1e#]]\n\
+        \x1b[2K\x1e\x1e[[#INFO#\x1eReceiving new playlist... \x1e\x1e#]]\
+        \x1e\x1e[[#NONE#\x1eOk.\n\x1e\x1e#]]\
+        \x1b[2K\x1e\x1e[[#NOWPLAYING#\x1eSONG\x1eHopp\xc3\xadpolla\x1eSigur R\xc3\xb3s\x1eTakk...\
+            \x1e<3\x1e\x1ehttp://www.pandora.com/sigur-ros/takk/hoppipolla\x1e\x1e#]]\n\
+        \x1b[2K\x1e\x1e[[#TIME#\x1e-04:30/04:32\x1e\x1e#]]\r\
+        \x1b[2K\x1e\x1e[[#TIME#\x1e-04:29/04:32\x1e\x1e#]]\r\
+        \x1b[2K\x1e\x1e[[#TIME#\x1e-04:29/04:32\x1e\x1e#]]\r\
+        \x1b[2K\x1e\x1e[[#TIME#\x1e-04:29/04:32\x1e\x1e#]]\r\
+        \x1b[2K\x1e\x1e[[#LIST#\x1eSONG\x1eJ\xc3\xb3nsi\x1eGo Do\x1e\x1e%l\x1e\x1e#]]\n\
+        \x1b[2K\x1e\x1e[[#ERROR#\x1eError: Access denied. Try again later.\n\x1e\x1e#]]";
 
-        // Process message
-        match self.process_message(message_type, &message_parts[1..]) {
-            Ok(message) => Some(message),
-            Err(err) => {
-                log::warn!("Failed to parse message: {}", err);
-                None
+    fn transcript_messages() -> Vec<PianobarMessage> {
+        let plain = |message: &str| PianobarMessage::Plain {
+            message: message.to_string(),
+        };
+        let wait = |message: &str| PianobarMessage::Wait {
+            message: message.to_string(),
+        };
+        let time = |current, stalled| PianobarMessage::SongTime {
+            current,
+            total: 272,
+            stalled,
+        };
+        vec![
+            plain("Welcome to pianobar (2022.04.01)! Press ? for a list of commands."),
+            wait("Login..."),
+            plain("Ok."),
+            wait("Get stations..."),
+            plain("Ok."),
+            PianobarMessage::ListEntryStation {
+                id: 0,
+                name: "QuickMix".to_string(),
+                in_quickmix: false,
+                is_quickmix: true,
+                shared: false,
+            },
+            PianobarMessage::ListEntryStation {
+                id: 1,
+                name: "Café Jazz ☕".to_string(),
+                in_quickmix: true,
+                is_quickmix: false,
+                shared: false,
+            },
+            PianobarMessage::ListEntryStation {
+                id: 2,
+                name: "Sigur Rós Radio 🎹".to_string(),
+                in_quickmix: true,
+                is_quickmix: false,
+                shared: true,
+            },
+            PianobarMessage::Question {
+                message: "Select station:".to_string(),
+            },
+            PianobarMessage::NowPlayingStation {
+                name: "Sigur Rós Radio 🎹".to_string(),
+                id: "4242".to_string(),
+            },
+            wait("Receiving new playlist..."),
+            plain("Ok."),
+            PianobarMessage::NowPlayingSong {
+                title: "Hoppípolla".to_string(),
+                artist: "Sigur Rós".to_string(),
+                album: "Takk...".to_string(),
+                rating: 1,
+                station: String::new(),
+                detail_url: "http://www.pandora.com/sigur-ros/takk/hoppipolla".to_string(),
+            },
+            time(2, false),
+            time(3, false),
+            // Repeated once, which happens due to jitter
+            time(3, false),
+            time(3, true),
+            PianobarMessage::ListEntrySong {
+                artist: "Jónsi".to_string(),
+                title: "Go Do".to_string(),
+                album: String::new(),
+                rating: 0,
+            },
+            PianobarMessage::Error {
+                message: "Error: Access denied. Try again later.".to_string(),
+            },
+        ]
+    }
+
+    fn plain(message: &str) -> Vec<u8> {
+        [MESSAGE_START, b"NONE#\x1e", message.as_bytes(), MESSAGE_END].concat()
+    }
+
+    fn decode_chunks<'a>(chunks: impl IntoIterator<Item = &'a [u8]>) -> Vec<PianobarMessage> {
+        let mut codec = PianobarMessageCodec::new();
+        let mut buffer = BytesMut::new();
+        let mut messages = vec![];
+        for chunk in chunks {
+            buffer.extend_from_slice(chunk);
+            while let Some(message) = codec.decode(&mut buffer).unwrap() {
+                messages.push(message);
             }
         }
+        while let Some(message) = codec.decode_eof(&mut buffer).unwrap() {
+            messages.push(message);
+        }
+        messages
     }
-}
 
-pub async fn parse_pianobar_messages(
-    pianobar_stream: &mut ChildStdout,
-    pianobar_received_messages: &broadcast::Sender<PianobarMessage>,
-) -> Result<()> {
-    let mut message_parser = PianobarMessageParser::new();
-    loop {
-        // Read from pianobar stdout
-        let mut output = [0u8; 128];
-        let num_read = pianobar_stream.read(&mut output).await?;
-        if num_read == 0 {
-            bail!("pianobar program closed!");
+    fn decode(output: &[u8]) -> Vec<PianobarMessage> {
+        decode_chunks([output])
+    }
+
+    #[test]
+    fn decodes_transcript() {
+        assert_eq!(decode(TRANSCRIPT), transcript_messages());
+    }
+
+    #[test]
+    fn decodes_transcript_split_at_every_byte() {
+        // Includes splits within multi-byte characters and frame markers
+        for split in 0..=TRANSCRIPT.len() {
+            let (first, second) = TRANSCRIPT.split_at(split);
+            assert_eq!(
+                decode_chunks([first, second]),
+                transcript_messages(),
+                "split at {}",
+                split
+            );
         }
-        let msg = std::str::from_utf8(&output[..num_read])?;
-
-        // Print to console, for debugging
-        log::debug!("\n{}", msg);
-
-        // Feed all characters into the message parser
-        for ch in msg.chars() {
-            if let Some(parsed_message) = message_parser.process(ch) {
-                // The message parser found a message, send it to all listeners
-                match pianobar_received_messages.send(parsed_message) {
-                    Ok(_num_receivers) => {
-                        //log::debug!("Sent pianobar message to {} listeners.", num_receivers)
-                    }
-                    Err(broadcast::error::SendError(msg)) => {
-                        log::error!("No receiver for message: {:?}", msg);
-                    }
-                };
+        assert_eq!(decode_chunks(TRANSCRIPT.chunks(1)), transcript_messages());
+    }
+
+    #[test]
+    fn strips_escape_sequences_and_control_codes() {
+        let output = b"\x1b]0;pianobar\x07\x1b[2K\x1e\x1e[[#INFO#\x1e\
+            \x1b[1mBold\x1b[0m and \x1b(Bcharset\x07\r\x08\x7f text\x1e\x1e#]]";
+        assert_eq!(
+            decode(output),
+            vec![PianobarMessage::Info {
+                message: "Bold and charset text".to_string()
+            }]
+        );
+    }
+
+    #[test]
+    fn broken_escape_sequences_keep_frames() {
+        let output = [
+            b"\x1b[12".as_ref(),
+            &plain("Ok."),
+            b"\x1b]0;unterminated title",
+            &plain("Still ok."),
+            b"\x1b",
+            &plain("Ok again."),
+        ]
+        .concat();
+        assert_eq!(
+            decode(&output),
+            vec![
+                PianobarMessage::Plain {
+                    message: "Ok.".to_string()
+                },
+                PianobarMessage::Plain {
+                    message: "Still ok.".to_string()
+                },
+                PianobarMessage::Plain {
+                    message: "Ok again.".to_string()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn drops_oversized_frames() {
+        let longest = "x".repeat(MAX_MESSAGE_LENGTH - b"NONE#\x1e".len());
+        let too_long = "x".repeat(MAX_MESSAGE_LENGTH);
+        let output = [plain(&too_long), plain(&longest), plain("Ok.")].concat();
+
+        let expected = vec![
+            PianobarMessage::Plain { message: longest },
+            PianobarMessage::Plain {
+                message: "Ok.".to_string(),
+            },
+        ];
+        assert_eq!(decode(&output), expected);
+        assert_eq!(decode_chunks(output.chunks(7)), expected);
+    }
+
+    #[test]
+    fn recovers_after_truncated_and_garbage_frames() {
+        let output = [
+            // Cut off by the next frame
+            b"\x1e\x1e[[#NOWPLAYING#\x1eSONG\x1eHopp".as_ref(),
+            &plain("Ok."),
+            // Garbage and stray frame markers
+            b"\x00\xff\xfe#]]\x1e\x1e#]][[#\x1e\x1e[",
+            // Frames that can't be parsed
+            b"\x1e\x1e[[#BOGUS#\x1ex\x1e\x1e#]]",
+            b"\x1e\x1e[[#TIME#\x1e12:00/01:00\x1e\x1e#]]",
+            b"\x1e\x1e[[#\x1ex\x1e\x1e#]]",
+            b"\x1e\x1e[[#ERROR#\x1e\x1e\x1e#]]",
+            // Invalid UTF-8 only affects the characters in question
+            b"\x1e\x1e[[#NONE#\x1eCaf\xc3\x1e\x1e#]]",
+            &plain("Still ok."),
+            // Lost at the end of the output
+            b"\x1e\x1e[[#NONE#\x1eTruncated",
+        ]
+        .concat();
+        assert_eq!(
+            decode(&output),
+            vec![
+                PianobarMessage::Plain {
+                    message: "Ok.".to_string()
+                },
+                PianobarMessage::Error {
+                    message: String::new()
+                },
+                PianobarMessage::Plain {
+                    message: "Caf\u{fffd}".to_string()
+                },
+                PianobarMessage::Plain {
+                    message: "Still ok.".to_string()
+                },
+            ]
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn decoding_does_not_depend_on_chunking(
+            prefix in proptest::collection::vec(any::<u8>(), 0..64),
+            chunk_sizes in proptest::collection::vec(1..64usize, 1..32),
+        ) {
+            let too_long = plain(&"x".repeat(MAX_MESSAGE_LENGTH + 1));
+            let output = [&prefix, TRANSCRIPT, &too_long, TRANSCRIPT].concat();
+
+            let mut chunks = vec![];
+            let mut rest = output.as_slice();
+            for size in chunk_sizes.iter().cycle() {
+                if rest.is_empty() {
+                    break;
+                }
+                let (chunk, remaining) = rest.split_at(min(*size, rest.len()));
+                chunks.push(chunk);
+                rest = remaining;
             }
+
+            prop_assert_eq!(decode_chunks(chunks), decode(&output));
         }
     }
 }
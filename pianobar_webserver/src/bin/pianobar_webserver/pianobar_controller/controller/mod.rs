@@ -1,6 +1,9 @@
+#[allow(clippy::module_inception)]
 mod controller;
 mod messages;
+mod scheduler;
 
-pub use controller::PianobarActor;
 pub use controller::PianobarController;
+pub use controller::PlaybackCommand;
 pub use messages::PianobarMessage;
+pub use scheduler::{CommandResponse, CommandTimeout, CommandTimeouts, PianobarCommand};
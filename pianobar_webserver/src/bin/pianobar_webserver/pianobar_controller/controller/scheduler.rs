@@ -0,0 +1,477 @@
+use super::controller::{PianobarActor, PlaybackCommand};
+use super::messages::PianobarMessage;
+
+use anyhow::{anyhow, bail, Result};
+use pianobar_webserver::protocol::{HistoryEntry, UpcomingSong};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::time::{sleep_until, Instant};
+
+/// How long pianobar gets to answer, unless configured otherwise
+const DEFAULT_RESPONSE_TIMEOUT: Duration = Duration::from_millis(1000);
+/// pianobar prints lists at once, a list without an end is complete after this long
+const LIST_QUIET_PERIOD: Duration = Duration::from_millis(100);
+
+/// Something pianobar should do
+#[derive(Clone, Debug)]
+pub enum PianobarCommand {
+    ChangeStation(usize),
+    Pause,
+    Resume,
+    TogglePause,
+    Skip,
+    Ban,
+    Explain,
+    History,
+    Upcoming,
+    /// Keys typed into the server's terminal
+    Keys(String),
+}
+
+/// Commands with a higher priority get written first
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CommandPriority {
+    Query,
+    Control,
+    Transport,
+}
+
+/// What pianobar answered
+#[derive(Debug)]
+pub enum CommandResponse {
+    /// The command doesn't have an answer, it was written
+    Written,
+    Explanation(String),
+    History(Vec<HistoryEntry>),
+    Upcoming(Vec<UpcomingSong>),
+}
+
+/// The commands pianobar answers, which can get their own timeout
+const COMMANDS_WITH_RESPONSE: &[&str] = &["explain", "history", "upcoming"];
+
+fn with_reset(msg: &str) -> String {
+    format!("\r\n\r\n{}", msg)
+}
+
+impl PianobarCommand {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PianobarCommand::ChangeStation(_) => "change_station",
+            PianobarCommand::Pause => "pause",
+            PianobarCommand::Resume => "resume",
+            PianobarCommand::TogglePause => "toggle_pause",
+            PianobarCommand::Skip => "skip",
+            PianobarCommand::Ban => "ban",
+            PianobarCommand::Explain => "explain",
+            PianobarCommand::History => "history",
+            PianobarCommand::Upcoming => "upcoming",
+            PianobarCommand::Keys(_) => "keys",
+        }
+    }
+
+    /// Pausing shouldn't wait for anything, queries can
+    pub fn priority(&self) -> CommandPriority {
+        match self {
+            PianobarCommand::Pause | PianobarCommand::Resume | PianobarCommand::TogglePause => {
+                CommandPriority::Transport
+            }
+            PianobarCommand::ChangeStation(_)
+            | PianobarCommand::Skip
+            | PianobarCommand::Ban
+            | PianobarCommand::Keys(_) => CommandPriority::Control,
+            PianobarCommand::Explain | PianobarCommand::History | PianobarCommand::Upcoming => {
+                CommandPriority::Query
+            }
+        }
+    }
+
+    /// What gets written to pianobar. The line breaks cancel questions pianobar might be asking.
+    fn keys(&self) -> String {
+        match self {
+            PianobarCommand::ChangeStation(station_id) => with_reset(&format!("s{}\n", station_id)),
+            PianobarCommand::Pause => with_reset("S"),
+            PianobarCommand::Resume => with_reset("P"),
+            PianobarCommand::TogglePause => with_reset("p"),
+            PianobarCommand::Skip => with_reset("n"),
+            PianobarCommand::Ban => with_reset("-"),
+            PianobarCommand::Explain => with_reset("e"),
+            PianobarCommand::History => with_reset("h\r\n\r\n"),
+            PianobarCommand::Upcoming => with_reset("u"),
+            PianobarCommand::Keys(keys) => keys.clone(),
+        }
+    }
+
+    fn playback_command(&self) -> Option<PlaybackCommand> {
+        match self {
+            PianobarCommand::Pause => Some(PlaybackCommand::Pause),
+            PianobarCommand::Resume => Some(PlaybackCommand::Resume),
+            PianobarCommand::TogglePause => Some(PlaybackCommand::TogglePause),
+            _ => None,
+        }
+    }
+
+    fn response_collector(&self) -> Option<ResponseCollector> {
+        match self {
+            PianobarCommand::Explain => Some(ResponseCollector::Explanation),
+            PianobarCommand::History => Some(ResponseCollector::History(vec![])),
+            PianobarCommand::Upcoming => Some(ResponseCollector::Upcoming(vec![])),
+            _ => None,
+        }
+    }
+}
+
+/// Picks the answer of a command out of pianobar's output
+enum ResponseCollector {
+    Explanation,
+    History(Vec<HistoryEntry>),
+    /// pianobar doesn't end the list of upcoming songs
+    Upcoming(Vec<UpcomingSong>),
+}
+
+impl ResponseCollector {
+    /// Returns the response once it is complete
+    fn process(&mut self, message: PianobarMessage) -> Option<CommandResponse> {
+        match (self, message) {
+            (ResponseCollector::Explanation, PianobarMessage::Info { message })
+                if message.starts_with("We're playing this track because") =>
+            {
+                Some(CommandResponse::Explanation(message))
+            }
+            (ResponseCollector::History(entries), PianobarMessage::Info { message })
+                if message == "No history yet." =>
+            {
+                Some(CommandResponse::History(std::mem::take(entries)))
+            }
+            (ResponseCollector::History(entries), PianobarMessage::Question { message }) => {
+                log::debug!("History finished with question: {}", message);
+                Some(CommandResponse::History(std::mem::take(entries)))
+            }
+            (
+                ResponseCollector::History(entries),
+                PianobarMessage::ListEntrySong { artist, title, .. },
+            ) => {
+                entries.push(HistoryEntry { artist, title });
+                None
+            }
+            (ResponseCollector::Upcoming(_), PianobarMessage::Info { message })
+                if message == "No songs in queue." =>
+            {
+                Some(CommandResponse::Upcoming(vec![]))
+            }
+            (
+                ResponseCollector::Upcoming(songs),
+                PianobarMessage::ListEntrySong {
+                    artist,
+                    title,
+                    album,
+                    rating,
+                },
+            ) => {
+                songs.push(UpcomingSong {
+                    artist,
+                    title,
+                    album,
+                    rating,
+                });
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// How long to wait for more of an answer that has no end
+    fn quiet_period(&self) -> Option<Duration> {
+        match self {
+            ResponseCollector::Upcoming(songs) if !songs.is_empty() => Some(LIST_QUIET_PERIOD),
+            _ => None,
+        }
+    }
+
+    /// The response once pianobar went quiet, for answers that have no end
+    fn finish(&mut self) -> Option<CommandResponse> {
+        match self {
+            ResponseCollector::Upcoming(songs) if !songs.is_empty() => {
+                Some(CommandResponse::Upcoming(std::mem::take(songs)))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A timeout for the answer of a command, given as `<command>=<milliseconds>`
+#[derive(Clone, Debug)]
+pub struct CommandTimeout {
+    command: &'static str,
+    timeout: Duration,
+}
+
+impl FromStr for CommandTimeout {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let (command, milliseconds) = value.split_once('=').ok_or(anyhow!(
+            "Expected '<command>=<milliseconds>', got '{}'",
+            value
+        ))?;
+        let command = COMMANDS_WITH_RESPONSE
+            .iter()
+            .find(|name| **name == command.trim())
+            .ok_or(anyhow!(
+                "Unknown command '{}', expected one of: {}",
+                command,
+                COMMANDS_WITH_RESPONSE.join(", ")
+            ))?;
+        Ok(CommandTimeout {
+            command,
+            timeout: Duration::from_millis(milliseconds.trim().parse()?),
+        })
+    }
+}
+
+/// How long pianobar gets to answer each command
+#[derive(Clone, Debug, Default)]
+pub struct CommandTimeouts {
+    timeouts: HashMap<&'static str, Duration>,
+}
+
+impl CommandTimeouts {
+    pub fn new(timeouts: &[CommandTimeout]) -> CommandTimeouts {
+        CommandTimeouts {
+            timeouts: timeouts
+                .iter()
+                .map(|timeout| (timeout.command, timeout.timeout))
+                .collect(),
+        }
+    }
+
+    fn get(&self, command: &PianobarCommand) -> Duration {
+        self.timeouts
+            .get(command.name())
+            .copied()
+            .unwrap_or(DEFAULT_RESPONSE_TIMEOUT)
+    }
+}
+
+/// A command waiting to be written, numbered in the order of submission
+pub struct QueuedCommand {
+    id: u64,
+    command: PianobarCommand,
+    responder: oneshot::Sender<Result<CommandResponse>>,
+}
+
+impl QueuedCommand {
+    /// Dropping the receiver cancels the command
+    pub fn new(
+        id: u64,
+        command: PianobarCommand,
+    ) -> (QueuedCommand, oneshot::Receiver<Result<CommandResponse>>) {
+        let (responder, response) = oneshot::channel();
+        (
+            QueuedCommand {
+                id,
+                command,
+                responder,
+            },
+            response,
+        )
+    }
+}
+
+/// The command whose answer pianobar is printing
+struct CommandInFlight {
+    queued: QueuedCommand,
+    collector: ResponseCollector,
+    /// When the command times out
+    timeout_at: Instant,
+    /// When an answer without an end is complete
+    quiet_at: Option<Instant>,
+}
+
+impl CommandInFlight {
+    fn deadline(&self) -> Instant {
+        match self.quiet_at {
+            Some(quiet_at) => quiet_at.min(self.timeout_at),
+            None => self.timeout_at,
+        }
+    }
+}
+
+/// Writes commands to pianobar in order of their priority.
+///
+/// Only one command that pianobar answers is in flight at a time, so every message
+/// pianobar prints in the meantime belongs to it. Commands without an answer don't
+/// have to wait for it; pianobar handles its keys in order anyway.
+pub struct CommandScheduler {
+    actor: PianobarActor,
+    commands: mpsc::UnboundedReceiver<QueuedCommand>,
+    messages: broadcast::Receiver<PianobarMessage>,
+    playback_commands: broadcast::Sender<PlaybackCommand>,
+    timeouts: CommandTimeouts,
+    queue: Vec<QueuedCommand>,
+    in_flight: Option<CommandInFlight>,
+}
+
+impl CommandScheduler {
+    pub fn new(
+        actor: PianobarActor,
+        commands: mpsc::UnboundedReceiver<QueuedCommand>,
+        messages: broadcast::Receiver<PianobarMessage>,
+        playback_commands: broadcast::Sender<PlaybackCommand>,
+        timeouts: CommandTimeouts,
+    ) -> Self {
+        CommandScheduler {
+            actor,
+            commands,
+            messages,
+            playback_commands,
+            timeouts,
+            queue: vec![],
+            in_flight: None,
+        }
+    }
+
+    /// Removes the next command that can be written now, skipping cancelled ones
+    fn next_command(&mut self) -> Option<QueuedCommand> {
+        self.queue.retain(|queued| {
+            let cancelled = queued.responder.is_closed();
+            if cancelled {
+                log::debug!(
+                    "Command #{} ({}) cancelled",
+                    queued.id,
+                    queued.command.name()
+                );
+            }
+            !cancelled
+        });
+
+        let busy = self.in_flight.is_some();
+        let index = self
+            .queue
+            .iter()
+            .enumerate()
+            .filter(|(_, queued)| !busy || queued.command.response_collector().is_none())
+            .max_by_key(|(_, queued)| (queued.command.priority(), std::cmp::Reverse(queued.id)))
+            .map(|(index, _)| index)?;
+        Some(self.queue.remove(index))
+    }
+
+    async fn write(&mut self, queued: QueuedCommand) {
+        let command = &queued.command;
+        log::debug!("Command #{}: {:?}", queued.id, command);
+
+        // Messages from before the command can't belong to it, but they can
+        // belong to a query that is still in flight
+        while let Ok(message) = self.messages.try_recv() {
+            if self.in_flight.is_some() {
+                self.process_message(message);
+            } else {
+                log::trace!("Unanswered message: {:?}", message);
+            }
+        }
+
+        if let Err(err) = self.actor.write(&command.keys()).await {
+            let _ = queued.responder.send(Err(err));
+            return;
+        }
+        if let Some(playback_command) = command.playback_command() {
+            // Fails if nobody listens, which is fine
+            let _ = self.playback_commands.send(playback_command);
+        }
+
+        match command.response_collector() {
+            Some(collector) => {
+                let timeout_at = Instant::now() + self.timeouts.get(command);
+                self.in_flight = Some(CommandInFlight {
+                    queued,
+                    collector,
+                    timeout_at,
+                    quiet_at: None,
+                });
+            }
+            None => {
+                let _ = queued.responder.send(Ok(CommandResponse::Written));
+            }
+        }
+    }
+
+    fn process_message(&mut self, message: PianobarMessage) {
+        let in_flight = match &mut self.in_flight {
+            Some(in_flight) => in_flight,
+            None => return,
+        };
+        log::trace!(
+            "Message for command #{}: {:?}",
+            in_flight.queued.id,
+            message
+        );
+        match in_flight.collector.process(message) {
+            Some(response) => self.answer(response),
+            None => {
+                in_flight.quiet_at = in_flight
+                    .collector
+                    .quiet_period()
+                    .map(|quiet_period| Instant::now() + quiet_period);
+            }
+        }
+    }
+
+    fn answer(&mut self, response: CommandResponse) {
+        if let Some(in_flight) = self.in_flight.take() {
+            let queued = in_flight.queued;
+            log::debug!("Command #{} answered", queued.id);
+            // The receiver is gone if the command was cancelled in the meantime
+            let _ = queued.responder.send(Ok(response));
+        }
+    }
+
+    fn time_out(&mut self) {
+        let finished = self
+            .in_flight
+            .as_mut()
+            .and_then(|in_flight| in_flight.collector.finish());
+        if let Some(response) = finished {
+            self.answer(response);
+        } else if let Some(in_flight) = self.in_flight.take() {
+            let queued = in_flight.queued;
+            log::warn!(
+                "Command #{} ({}) timed out",
+                queued.id,
+                queued.command.name()
+            );
+            let _ = queued.responder.send(Err(anyhow!(
+                "pianobar didn't answer the {} command in time",
+                queued.command.name()
+            )));
+        }
+    }
+
+    pub async fn run(&mut self) -> Result<()> {
+        loop {
+            while let Some(queued) = self.next_command() {
+                self.write(queued).await;
+            }
+
+            let deadline = self.in_flight.as_ref().map(CommandInFlight::deadline);
+            tokio::select! {
+                command = self.commands.recv() => match command {
+                    Some(command) => self.queue.push(command),
+                    None => bail!("Pianobar command queue closed."),
+                },
+                message = self.messages.recv() => match message {
+                    Ok(message) => self.process_message(message),
+                    Err(broadcast::error::RecvError::Lagged(num)) => {
+                        log::warn!("Missed {} messages", num);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => {
+                        bail!("Pianobar internal stdout queue closed.")
+                    }
+                },
+                _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    self.time_out();
+                }
+            }
+        }
+    }
+}
@@ -2,7 +2,8 @@ mod controller;
 mod pianobar_configurator;
 pub mod plugins;
 
-pub use controller::PianobarActor;
 pub use controller::PianobarController;
 pub use controller::PianobarMessage;
+pub use controller::PlaybackCommand;
+pub use controller::{CommandResponse, CommandTimeout, CommandTimeouts, PianobarCommand};
 pub use pianobar_configurator::set_pianobar_configs;
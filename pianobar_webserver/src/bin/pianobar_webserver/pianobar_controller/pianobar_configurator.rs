@@ -1,7 +1,6 @@
 use anyhow::{anyhow, bail, Result};
 use ini::{EscapePolicy, Ini};
 
-use log;
 use std::path::Path;
 
 fn set_event_command(config: &mut Ini) -> Result<()> {
@@ -30,20 +29,36 @@ fn set_event_command(config: &mut Ini) -> Result<()> {
 pub fn set_message_formats(config: &mut Ini) {
     config
         .with_general_section()
+        .set("format_msg_none", "\x1e\x1e[[#NONE#\x1e%s\x1e\x1e#]]")
         .set("format_msg_time", "\x1e\x1e[[#TIME#\x1e%s\x1e\x1e#]]")
-        .set("format_msg_list", "%s")
+        .set("format_msg_err", "\x1e\x1e[[#ERROR#\x1e%s\x1e\x1e#]]")
+        .set("format_msg_debug", "\x1e\x1e[[#DEBUG#\x1e%s\x1e\x1e#]]")
         .set(
             "format_msg_question",
             "\x1e\x1e[[#QUESTION#\x1e%s\x1e\x1e#]]",
         )
         .set("format_msg_info", "\x1e\x1e[[#INFO#\x1e%s\x1e\x1e#]]")
+        // Songs and stations are printed as list messages and now playing messages.
+        // Their formats only add a marker and the fields to those frames.
+        .set("format_msg_list", "\x1e\x1e[[#LIST#\x1e%s\x1e\x1e#]]")
+        .set("format_list_song", "SONG\x1e%a\x1e%t\x1e%r\x1e%l")
         .set(
-            "format_list_song",
-            "\x1e\x1e[[#LIST_ENTRY_SONG#\x1e%a\x1e%t\x1e\x1e#]]",
-        );
+            "format_msg_nowplaying",
+            "\x1e\x1e[[#NOWPLAYING#\x1e%s\x1e\x1e#]]",
+        )
+        .set(
+            "format_nowplaying_song",
+            "SONG\x1e%t\x1e%a\x1e%l\x1e%r\x1e%s\x1e%u",
+        )
+        .set("format_nowplaying_station", "STATION\x1e%n\x1e%i")
+        // The rating of songs is parsed from these
+        .set("love_icon", "<3")
+        .set("ban_icon", "</3")
+        .set("tired_icon", "zZ");
 }
 
-pub fn set_pianobar_configs(config_file: &str) -> Result<()> {
+/// Returns whether pianobar reports its events through the event command
+pub fn set_pianobar_configs(config_file: &str) -> Result<bool> {
     // Compute config path
     let config_path_expanded = shellexpand::tilde(config_file).to_string();
     let config_path = Path::new(&config_path_expanded);
@@ -62,7 +77,11 @@ pub fn set_pianobar_configs(config_file: &str) -> Result<()> {
 
     // Set config options
     set_message_formats(&mut config);
-    if let Err(err) = set_event_command(&mut config) {
+    let event_command_set = set_event_command(&mut config);
+    // The user might have set it manually before
+    let events_available =
+        event_command_set.is_ok() || config.general_section().get("event_command").is_some();
+    if let Err(err) = &event_command_set {
         log::warn!(
             "------------------------------------------------------------------------------"
         );
@@ -71,6 +90,9 @@ pub fn set_pianobar_configs(config_file: &str) -> Result<()> {
         log::warn!(
             "Please set it to the absolute path of the \"pianobar_event_handler\" executable."
         );
+        if !events_available {
+            log::warn!("Until then, only the now playing song is read from pianobar's output.");
+        }
         log::warn!(
             "------------------------------------------------------------------------------"
         );
@@ -95,5 +117,5 @@ pub fn set_pianobar_configs(config_file: &str) -> Result<()> {
     // Write config to file
     config.write_to_file_policy(config_path, EscapePolicy::Nothing)?;
 
-    Ok(())
+    Ok(events_available)
 }
@@ -26,7 +26,10 @@ impl DebugPrinter {
                 }
             };
 
-            log::debug!("Message: {:?}", message);
+            match message {
+                PianobarMessage::Debug { message } => log::debug!("pianobar: {}", message),
+                message => log::debug!("Message: {:?}", message),
+            }
         }
     }
 }
@@ -0,0 +1,152 @@
+use super::{CommandResponse, PianobarCommand, PianobarController};
+use crate::event_receiver::{PianobarUiEventPublisher, PianobarUiState, SequencedUiEvent};
+use anyhow::{bail, Result};
+use pianobar_webserver::protocol::{Explanation, EXPLANATION_COMMAND};
+use serde_json as json;
+use std::sync::Arc;
+use tokio::sync::{broadcast, Mutex};
+
+const EXPLANATION_PREFIX: &str = "We're playing this track because ";
+const TRAITS_PREFIX: &str = "it features ";
+/// Pandora ends the list of traits with a summary like this
+const SUMMARY_PREFIX: &str = "many other ";
+
+/// Splits pianobar's explanation, like "We're playing this track because it features
+/// a leisurely tempo, a folk influence and many other similarities as identified by
+/// the Music Genome Project.", into the traits and the other reasons.
+pub fn parse_explanation(raw: &str) -> Explanation {
+    let sentence = raw.trim().trim_end_matches('.');
+    let because = sentence
+        .strip_prefix(EXPLANATION_PREFIX)
+        .unwrap_or(sentence);
+
+    let (traits, reasons) = match because.strip_prefix(TRAITS_PREFIX) {
+        Some(traits) => {
+            let mut traits = split_list(traits);
+            let reasons = match traits.iter().position(|t| t.starts_with(SUMMARY_PREFIX)) {
+                Some(index) => traits.split_off(index),
+                None => vec![],
+            };
+            (traits, reasons)
+        }
+        None if because.is_empty() => (vec![], vec![]),
+        None => (vec![], vec![because.to_string()]),
+    };
+
+    Explanation {
+        traits,
+        reasons,
+        raw: raw.trim().to_string(),
+    }
+}
+
+/// Splits a list joined like "a, b and c"
+fn split_list(list: &str) -> Vec<String> {
+    let mut items: Vec<&str> = list.split(", ").collect();
+    // Traits can contain "and" themselves, the summary at the end doesn't
+    if let Some((last_but_one, last)) = items.pop().and_then(|last| last.rsplit_once(" and ")) {
+        items.push(last_but_one);
+        items.push(last);
+    }
+    items
+        .into_iter()
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Identifies the song of a ui state. pianobar's events don't contain an id,
+/// but the detail url is unique per song.
+fn song_id(state: &PianobarUiState) -> Option<String> {
+    let get = |key: &str| state.get(key).and_then(json::Value::as_str).unwrap_or("");
+    match (get("detailUrl"), get("title")) {
+        ("", "") => None,
+        ("", title) => Some(format!("{} - {}", get("artist"), title)),
+        (detail_url, _) => Some(detail_url.to_string()),
+    }
+}
+
+struct CachedExplanation {
+    song_id: String,
+    explanation: Explanation,
+}
+
+/// Explains the current song, and remembers the explanation until the next song
+/// starts. Once known, it gets added to the ui state as `explanation`.
+#[derive(Clone)]
+pub struct ExplanationCache {
+    controller: PianobarController,
+    publisher: PianobarUiEventPublisher,
+    cached: Arc<std::sync::Mutex<Option<CachedExplanation>>>,
+    ui_events: Arc<Mutex<broadcast::Receiver<SequencedUiEvent>>>,
+}
+
+impl ExplanationCache {
+    pub fn new(
+        controller: &PianobarController,
+        publisher: PianobarUiEventPublisher,
+        ui_events: broadcast::Receiver<SequencedUiEvent>,
+    ) -> Self {
+        ExplanationCache {
+            controller: controller.clone(),
+            publisher,
+            cached: Arc::new(std::sync::Mutex::new(None)),
+            ui_events: Arc::new(Mutex::new(ui_events)),
+        }
+    }
+
+    pub async fn explain(&self) -> Result<Explanation> {
+        let current_song = song_id(&self.publisher.ui_state());
+        if let Some(cached) = &*self.cached.lock().unwrap() {
+            if Some(&cached.song_id) == current_song.as_ref() {
+                log::debug!("Explanation of '{}' is cached", cached.song_id);
+                return Ok(cached.explanation.clone());
+            }
+        }
+
+        let explanation = match self.controller.execute(PianobarCommand::Explain).await? {
+            CommandResponse::Explanation(raw) => parse_explanation(&raw),
+            response => bail!("Unexpected response to explain: {:?}", response),
+        };
+
+        // The next song might have started in the meantime
+        if let Some(current_song) = current_song {
+            self.publisher.publish_change(EXPLANATION_COMMAND, |state| {
+                if song_id(state).as_ref() != Some(&current_song) {
+                    return false;
+                }
+                state.insert("explanation".to_string(), json::json!(explanation));
+                *self.cached.lock().unwrap() = Some(CachedExplanation {
+                    song_id: current_song,
+                    explanation: explanation.clone(),
+                });
+                true
+            });
+        }
+
+        Ok(explanation)
+    }
+
+    pub async fn run(&self) -> Result<()> {
+        let mut ui_events = self.ui_events.lock().await;
+        loop {
+            let ui_event = match ui_events.recv().await {
+                Ok(ui_event) => ui_event,
+                Err(broadcast::error::RecvError::Lagged(num)) => {
+                    // One of them might have been a songstart
+                    log::warn!("Missed {} ui events", num);
+                    self.cached.lock().unwrap().take();
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => {
+                    bail!("Pianobar ui event queue closed.")
+                }
+            };
+
+            if ui_event.event.command == "songstart" {
+                self.cached.lock().unwrap().take();
+            }
+        }
+    }
+}
@@ -1,7 +1,7 @@
 use anyhow::Result;
 use tokio::io::AsyncReadExt;
 
-use super::super::PianobarController;
+use super::super::{PianobarCommand, PianobarController};
 
 pub struct ManualController {
     controller: PianobarController,
@@ -24,7 +24,9 @@ impl ManualController {
                 return Ok(());
             }
             let message = std::str::from_utf8(&buffer[..num_read])?.to_string();
-            self.controller.take_actor().await.write(&message).await?;
+            self.controller
+                .execute(PianobarCommand::Keys(message))
+                .await?;
         }
     }
 }
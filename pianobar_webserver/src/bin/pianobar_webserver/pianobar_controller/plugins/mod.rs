@@ -1,6 +1,11 @@
 pub mod actions;
 pub mod debug_printer;
+pub mod explanations;
 pub mod manual_controller;
+pub mod now_playing;
 pub mod player_state;
+pub mod upcoming;
 
-use super::{PianobarActor, PianobarController, PianobarMessage};
+use super::{
+    CommandResponse, PianobarCommand, PianobarController, PianobarMessage, PlaybackCommand,
+};
@@ -0,0 +1,92 @@
+use super::{PianobarController, PianobarMessage};
+use crate::event_receiver::{PianobarUiEvent, PianobarUiEventPublisher, PianobarUiState};
+use anyhow::{bail, Result};
+use serde_json as json;
+use tokio::sync::broadcast;
+
+/// Keys of the ui state that survive a song change
+const PERSISTENT_KEYS: &[&str] = &["stations", "stationName"];
+
+/// Derives `songstart` events from pianobar's now playing messages,
+/// for when pianobar can't report its events through the event command
+pub struct NowPlayingFallback {
+    receiver: broadcast::Receiver<PianobarMessage>,
+    publisher: PianobarUiEventPublisher,
+    /// pianobar prints the station before the songs of a new playlist
+    station_name: Option<String>,
+}
+
+impl NowPlayingFallback {
+    pub fn new(controller: &PianobarController, publisher: PianobarUiEventPublisher) -> Self {
+        NowPlayingFallback {
+            receiver: controller.subscribe(),
+            publisher,
+            station_name: None,
+        }
+    }
+
+    fn process_message(&mut self, message: PianobarMessage) {
+        let (title, artist, album, rating, station, detail_url) = match message {
+            PianobarMessage::NowPlayingStation { name, .. } => {
+                self.station_name = Some(name);
+                return;
+            }
+            PianobarMessage::NowPlayingSong {
+                title,
+                artist,
+                album,
+                rating,
+                station,
+                detail_url,
+            } => (title, artist, album, rating, station, detail_url),
+            _ => return,
+        };
+
+        // Start from the previous state, like the event command would, but without
+        // the details of the previous song
+        let mut state = self
+            .publisher
+            .ui_state()
+            .into_iter()
+            .filter(|(key, _)| PERSISTENT_KEYS.contains(&key.as_str()))
+            .collect::<PianobarUiState>();
+        let mut set = |key: &str, value: String| {
+            state.insert(key.to_string(), json::Value::String(value));
+        };
+        if let Some(station_name) = &self.station_name {
+            set("stationName", station_name.clone());
+        }
+        set("title", title);
+        set("artist", artist);
+        set("album", album);
+        set("rating", rating.to_string());
+        set("detailUrl", detail_url);
+        // Only set for QuickMix
+        if !station.is_empty() {
+            set("songStationName", station);
+        }
+
+        log::debug!("Now playing from pianobar output: {:?}", state);
+        self.publisher.publish(PianobarUiEvent {
+            command: "songstart".to_string(),
+            state,
+        });
+    }
+
+    pub async fn run(&mut self) -> Result<()> {
+        loop {
+            let message = match self.receiver.recv().await {
+                Ok(msg) => msg,
+                Err(broadcast::error::RecvError::Lagged(num)) => {
+                    log::warn!("Missed {} messages", num);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => {
+                    bail!("Pianobar internal stdout queue closed.")
+                }
+            };
+
+            self.process_message(message);
+        }
+    }
+}
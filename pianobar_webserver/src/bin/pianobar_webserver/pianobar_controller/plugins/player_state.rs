@@ -1,75 +1,218 @@
-use super::{PianobarController, PianobarMessage};
+use super::{PianobarController, PianobarMessage, PlaybackCommand};
+use crate::event_receiver::SequencedUiEvent;
 use anyhow::{bail, Result};
-use serde::Serialize;
 use tokio::sync::{broadcast, watch};
 
-#[derive(Clone, Debug, Serialize)]
-pub struct PianobarPlayerState {
-    pub song_time_played: u32,
-    pub song_time_total: u32,
-    pub paused: bool,
+use pianobar_webserver::protocol::timestamp_now;
+pub use pianobar_webserver::protocol::{PianobarPlayerState, PlaybackState};
+
+/// The number of errors a receiver can fall behind before it misses some
+const ERROR_CHANNEL_CAPACITY: usize = 16;
+
+/// The number of consecutive advancing timestamps that overrule a pause.
+/// A single one might have been printed before pianobar received the pause command.
+const ADVANCES_OVERRULING_PAUSE: u32 = 2;
+
+/// How many seconds the predicted song position may be off before clients get corrected.
+/// pianobar prints whole seconds, so the prediction is up to a second off anyway.
+const DRIFT_THRESHOLD: f64 = 1.5;
+
+/// Everything that drives the playback state
+#[derive(Debug)]
+enum PlaybackInput {
+    /// Sent by us, so we know about it before pianobar reacts
+    Command(PlaybackCommand),
+    SongStart,
+    SongFinish,
+    /// pianobar waits for something, like the next playlist
+    Wait,
+    /// The song time advanced this many times in a row
+    Advancing(u32),
+    /// The song time doesn't advance anymore
+    Stalled,
+}
+
+/// The playback state machine. The timestamps only serve as cross-check,
+/// for pauses and resumes that didn't go through us, and for stalls.
+fn next_playback(playback: PlaybackState, input: &PlaybackInput) -> PlaybackState {
+    use PlaybackCommand::{Pause, Resume, TogglePause};
+    use PlaybackInput::{Advancing, Command, SongFinish, SongStart, Stalled, Wait};
+    use PlaybackState::{Buffering, Paused, Playing, Stopped};
+
+    match (playback, input) {
+        (_, SongStart) => Buffering,
+        (_, SongFinish) => Stopped,
+        (Stopped, Wait) => Buffering,
+        (Playing | Buffering, Command(Pause | TogglePause)) => Paused,
+        (Paused, Command(Resume | TogglePause)) => Playing,
+        (Stopped | Buffering, Advancing(_)) => Playing,
+        (Paused, Advancing(count)) if *count >= ADVANCES_OVERRULING_PAUSE => Playing,
+        (Playing, Stalled) => Buffering,
+        (playback, _) => playback,
+    }
 }
 
-impl PianobarPlayerState {
-    fn initial_state() -> Self {
-        PianobarPlayerState {
-            song_time_played: 0,
-            song_time_total: 0,
-            paused: true,
+/// Receives the next value, skipping over the ones that got missed
+async fn receive<T: Clone>(receiver: &mut broadcast::Receiver<T>) -> Result<T> {
+    loop {
+        match receiver.recv().await {
+            Ok(value) => return Ok(value),
+            Err(broadcast::error::RecvError::Lagged(num)) => {
+                log::warn!("Missed {} messages", num);
+            }
+            Err(broadcast::error::RecvError::Closed) => {
+                bail!("Pianobar internal queue closed.")
+            }
         }
     }
 }
 
 pub struct PianobarPlayerStateWatcher {
     receiver: broadcast::Receiver<PianobarMessage>,
+    playback_commands: broadcast::Receiver<PlaybackCommand>,
+    ui_events: broadcast::Receiver<SequencedUiEvent>,
     channel_in: watch::Sender<PianobarPlayerState>,
     channel_out: watch::Receiver<PianobarPlayerState>,
+    errors: broadcast::Sender<String>,
+    /// The song time pianobar printed last, as (current, total)
+    last_song_time: (u32, u32),
+    /// How often in a row the song time advanced
+    consecutive_advances: u32,
+    /// Whether the song time changed since the last playback command. Until then,
+    /// a stall is what pianobar counted while it was paused, not a new one.
+    time_changed_since_command: bool,
 }
 
 impl PianobarPlayerStateWatcher {
-    pub fn new(controller: &PianobarController) -> Self {
-        let (channel_in, channel_out) = watch::channel(PianobarPlayerState::initial_state());
+    pub fn new(
+        controller: &PianobarController,
+        ui_events: broadcast::Receiver<SequencedUiEvent>,
+    ) -> Self {
+        let (channel_in, channel_out) = watch::channel(PianobarPlayerState::default());
+        let (errors, _) = broadcast::channel(ERROR_CHANNEL_CAPACITY);
         PianobarPlayerStateWatcher {
             receiver: controller.subscribe(),
+            playback_commands: controller.subscribe_playback_commands(),
+            ui_events,
             channel_in,
             channel_out,
+            errors,
+            last_song_time: (0, 0),
+            consecutive_advances: 0,
+            time_changed_since_command: true,
         }
     }
 
-    async fn process_message(&mut self, message: PianobarMessage) -> Result<()> {
-        let mut state = self.channel_in.borrow().clone();
+    /// Sends the new state, unless clients predict it from the previous one anyway
+    fn update(&self, change: impl FnOnce(&mut PianobarPlayerState)) -> Result<()> {
+        let predicted = self.channel_in.borrow().at(timestamp_now());
+        let mut state = predicted.clone();
+        change(&mut state);
+        state.paused = state.playback != PlaybackState::Playing;
+        state.rate = if state.paused { 0.0 } else { 1.0 };
+        if state.playback != predicted.playback {
+            log::debug!("Playback {:?} -> {:?}", predicted.playback, state.playback);
+        }
+        if state != predicted {
+            log::debug!("Player state: {:?}", state);
+            self.channel_in.send(state)?;
+        }
+        Ok(())
+    }
+
+    fn process_playback_input(&self, input: PlaybackInput) -> Result<()> {
+        self.update(|state| state.playback = next_playback(state.playback, &input))
+    }
+
+    fn process_song_time(&mut self, current: u32, total: u32, stalled: bool) -> Result<()> {
+        let (previous_current, previous_total) = self.last_song_time;
+        self.last_song_time = (current, total);
+        if (current, total) != (previous_current, previous_total) {
+            self.time_changed_since_command = true;
+        }
+        let stalled = stalled && self.time_changed_since_command;
+        let advancing = !stalled && (current > previous_current || total != previous_total);
+        self.consecutive_advances = if advancing {
+            self.consecutive_advances + 1
+        } else {
+            0
+        };
 
+        let input = if stalled {
+            Some(PlaybackInput::Stalled)
+        } else if advancing {
+            Some(PlaybackInput::Advancing(self.consecutive_advances))
+        } else {
+            None
+        };
+        self.update(|state| {
+            let playback = match input {
+                Some(input) => next_playback(state.playback, &input),
+                None => state.playback,
+            };
+            let drift = (state.position - f64::from(current)).abs();
+            if playback != state.playback
+                || total != state.song_time_total
+                || drift > DRIFT_THRESHOLD
+            {
+                state.song_time_played = current;
+                state.song_time_total = total;
+                state.position = f64::from(current);
+            }
+            state.playback = playback;
+        })
+    }
+
+    fn process_message(&mut self, message: PianobarMessage) -> Result<()> {
         match message {
             PianobarMessage::SongTime {
                 current,
                 total,
-                paused,
-            } => {
-                state.song_time_played = current;
-                state.song_time_total = total;
-                state.paused = paused;
+                stalled,
+            } => self.process_song_time(current, total, stalled),
+            PianobarMessage::Wait { message } => self.update(|state| {
+                state.busy = Some(message);
+                state.playback = next_playback(state.playback, &PlaybackInput::Wait);
+            }),
+            PianobarMessage::Error { message } => {
+                log::warn!("pianobar error: {}", message);
+                // Fails if nobody listens, which is fine
+                let _ = self.errors.send(message);
+                self.update(|state| state.busy = None)
             }
-            _ => return Ok(()),
-        };
+            // Waits end with their outcome, or with a question if pianobar needs input
+            PianobarMessage::Plain { .. } | PianobarMessage::Question { .. } => {
+                self.update(|state| state.busy = None)
+            }
+            _ => Ok(()),
+        }
+    }
 
-        self.channel_in.send(state)?;
-        Ok(())
+    fn process_ui_event(&mut self, ui_event: SequencedUiEvent) -> Result<()> {
+        match ui_event.event.command.as_str() {
+            "songstart" => self.update(|state| {
+                state.playback = next_playback(state.playback, &PlaybackInput::SongStart);
+                // The next song time tells the length
+                state.song_time_played = 0;
+                state.position = 0.0;
+            }),
+            "songfinish" => self.process_playback_input(PlaybackInput::SongFinish),
+            _ => Ok(()),
+        }
     }
 
     pub async fn run(&mut self) -> Result<()> {
         loop {
-            let message = match self.receiver.recv().await {
-                Ok(msg) => msg,
-                Err(broadcast::error::RecvError::Lagged(num)) => {
-                    log::warn!("Missed {} messages", num);
-                    continue;
-                }
-                Err(broadcast::error::RecvError::Closed) => {
-                    bail!("Pianobar internal stdout queue closed.")
+            let result = tokio::select! {
+                message = receive(&mut self.receiver) => self.process_message(message?),
+                command = receive(&mut self.playback_commands) => {
+                    self.time_changed_since_command = false;
+                    self.process_playback_input(PlaybackInput::Command(command?))
                 }
+                ui_event = receive(&mut self.ui_events) => self.process_ui_event(ui_event?),
             };
 
-            if let Err(err) = self.process_message(message).await {
+            if let Err(err) = result {
                 log::warn!("Unable to process message: {}", err);
             }
         }
@@ -78,4 +221,9 @@ impl PianobarPlayerStateWatcher {
     pub fn subscribe(&self) -> watch::Receiver<PianobarPlayerState> {
         self.channel_out.clone()
     }
+
+    /// The errors pianobar prints
+    pub fn errors(&self) -> broadcast::Sender<String> {
+        self.errors.clone()
+    }
 }
@@ -0,0 +1,80 @@
+use super::{CommandResponse, PianobarCommand, PianobarController};
+use crate::event_receiver::{PianobarUiEventPublisher, SequencedUiEvent};
+use anyhow::{bail, Result};
+use pianobar_webserver::protocol::{UpcomingSong, UPCOMING_COMMAND};
+use serde_json as json;
+use std::sync::Arc;
+use tokio::sync::{broadcast, Mutex};
+
+/// Events after which pianobar's queue has changed
+const REFRESH_EVENTS: &[&str] = &["songstart", "stationfetchplaylist"];
+
+/// Keeps the songs pianobar plays next in the ui state, as `upcoming`,
+/// so clients see them without asking
+#[derive(Clone)]
+pub struct UpcomingSongs {
+    controller: PianobarController,
+    publisher: PianobarUiEventPublisher,
+    ui_events: Arc<Mutex<broadcast::Receiver<SequencedUiEvent>>>,
+}
+
+impl UpcomingSongs {
+    pub fn new(
+        controller: &PianobarController,
+        publisher: PianobarUiEventPublisher,
+        ui_events: broadcast::Receiver<SequencedUiEvent>,
+    ) -> Self {
+        UpcomingSongs {
+            controller: controller.clone(),
+            publisher,
+            ui_events: Arc::new(Mutex::new(ui_events)),
+        }
+    }
+
+    /// Asks pianobar for the upcoming songs, and publishes them if they changed
+    pub async fn refresh(&self) -> Result<Vec<UpcomingSong>> {
+        let songs = match self.controller.execute(PianobarCommand::Upcoming).await? {
+            CommandResponse::Upcoming(songs) => songs,
+            response => bail!("Unexpected response to upcoming: {:?}", response),
+        };
+
+        let value = json::json!(songs);
+        self.publisher.publish_change(UPCOMING_COMMAND, |state| {
+            if state.get("upcoming") == Some(&value) {
+                return false;
+            }
+            state.insert("upcoming".to_string(), value);
+            true
+        });
+
+        Ok(songs)
+    }
+
+    pub async fn run(&self) -> Result<()> {
+        let mut ui_events = self.ui_events.lock().await;
+        loop {
+            let ui_event = match ui_events.recv().await {
+                Ok(ui_event) => ui_event,
+                Err(broadcast::error::RecvError::Lagged(num)) => {
+                    // One of them might have changed the queue
+                    log::warn!("Missed {} ui events", num);
+                    self.refresh_logged().await;
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => {
+                    bail!("Pianobar ui event queue closed.")
+                }
+            };
+
+            if REFRESH_EVENTS.contains(&ui_event.event.command.as_str()) {
+                self.refresh_logged().await;
+            }
+        }
+    }
+
+    async fn refresh_logged(&self) {
+        if let Err(err) = self.refresh().await {
+            log::warn!("Unable to refresh upcoming songs: {}", err);
+        }
+    }
+}
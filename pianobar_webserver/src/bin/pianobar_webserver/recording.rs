@@ -0,0 +1,193 @@
+use crate::event_receiver::{PianobarUiEvent, PianobarUiEventPublisher};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_json as json;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWriteExt, DuplexStream, ReadBuf};
+use tokio::time::{sleep_until, Instant};
+
+/// Large enough for everything pianobar prints at once
+const REPLAY_PIPE_CAPACITY: usize = 64 * 1024;
+
+/// Something that crossed the boundary to pianobar
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum RecordedData {
+    /// Written to pianobar's stdin
+    Stdin { data: String },
+    /// Read from pianobar's stdout, hex encoded because it doesn't have to be UTF-8
+    Stdout { data: String },
+    /// Received from pianobar's event command
+    Event { event: PianobarUiEvent },
+}
+
+/// One line of a recording
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RecordedEntry {
+    /// Milliseconds since the recording started
+    pub time: u64,
+    #[serde(flatten)]
+    pub data: RecordedData,
+}
+
+/// Writes everything that crosses the boundary to pianobar to a file, as JSON lines,
+/// so a session can be replayed with `Replay`
+#[derive(Clone)]
+pub struct Recorder {
+    file: Arc<Mutex<File>>,
+    start: Instant,
+}
+
+impl Recorder {
+    pub fn new(path: &str) -> Result<Recorder> {
+        let path = shellexpand::tilde(path).to_string();
+        let file = File::create(&path)
+            .map_err(|err| anyhow!("Unable to create recording '{}': {}", path, err))?;
+        Ok(Recorder {
+            file: Arc::new(Mutex::new(file)),
+            start: Instant::now(),
+        })
+    }
+
+    fn record(&self, data: RecordedData) {
+        let entry = RecordedEntry {
+            time: self.start.elapsed().as_millis() as u64,
+            data,
+        };
+        let result = json::to_string(&entry)
+            .map_err(anyhow::Error::from)
+            .and_then(|line| Ok(writeln!(self.file.lock().unwrap(), "{}", line)?));
+        if let Err(err) = result {
+            log::warn!("Unable to write recording: {}", err);
+        }
+    }
+
+    pub fn record_stdin(&self, data: &str) {
+        self.record(RecordedData::Stdin {
+            data: data.to_string(),
+        });
+    }
+
+    pub fn record_stdout(&self, data: &[u8]) {
+        self.record(RecordedData::Stdout {
+            data: hex::encode(data),
+        });
+    }
+
+    pub fn record_event(&self, event: &PianobarUiEvent) {
+        self.record(RecordedData::Event {
+            event: event.clone(),
+        });
+    }
+
+    /// Records everything that gets read from `reader`
+    pub fn record_reads<R>(&self, reader: R) -> RecordingReader<R> {
+        RecordingReader {
+            reader,
+            recorder: self.clone(),
+        }
+    }
+}
+
+pub struct RecordingReader<R> {
+    reader: R,
+    recorder: Recorder,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for RecordingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let filled_before = buf.filled().len();
+        let result = Pin::new(&mut self.reader).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            let read = &buf.filled()[filled_before..];
+            if !read.is_empty() {
+                self.recorder.record_stdout(read);
+            }
+        }
+        result
+    }
+}
+
+/// Plays a recording back in place of pianobar, at its original pace
+pub struct Replay {
+    entries: Vec<RecordedEntry>,
+    stdout: Option<DuplexStream>,
+}
+
+impl Replay {
+    /// Also returns the stream the recorded stdout gets replayed to
+    pub fn load(path: &str) -> Result<(Replay, DuplexStream)> {
+        let path = shellexpand::tilde(path).to_string();
+        let file = File::open(&path)
+            .map_err(|err| anyhow!("Unable to open replay '{}': {}", path, err))?;
+
+        let mut entries = vec![];
+        for (num, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry = json::from_str(&line).map_err(|err| {
+                anyhow!("Invalid entry in line {} of '{}': {}", num + 1, path, err)
+            })?;
+            entries.push(entry);
+        }
+        log::info!("Loaded {} entries from '{}'", entries.len(), path);
+
+        let (stdout, stdout_reader) = tokio::io::duplex(REPLAY_PIPE_CAPACITY);
+        Ok((
+            Replay {
+                entries,
+                stdout: Some(stdout),
+            },
+            stdout_reader,
+        ))
+    }
+
+    /// Whether pianobar reported events in the recorded session
+    pub fn has_events(&self) -> bool {
+        self.entries
+            .iter()
+            .any(|entry| matches!(entry.data, RecordedData::Event { .. }))
+    }
+
+    pub async fn run(&mut self, publisher: PianobarUiEventPublisher) -> Result<()> {
+        let mut stdout = self
+            .stdout
+            .take()
+            .ok_or(anyhow!("The replay already ran."))?;
+        let start = Instant::now();
+
+        for entry in &self.entries {
+            sleep_until(start + Duration::from_millis(entry.time)).await;
+            match &entry.data {
+                RecordedData::Stdin { data } => {
+                    // What we write now goes nowhere, this shows where the recorded user acted
+                    log::info!("Recorded input: {:?}", data);
+                }
+                RecordedData::Stdout { data } => {
+                    stdout.write_all(&hex::decode(data)?).await?;
+                }
+                RecordedData::Event { event } => {
+                    log::debug!("Recorded event: {}", event.command);
+                    publisher.publish(event.clone());
+                }
+            }
+        }
+
+        // Closing stdout would shut the server down, but the final state is worth a look
+        log::info!("Replay finished.");
+        futures::future::pending::<()>().await;
+        Ok(())
+    }
+}
@@ -0,0 +1,227 @@
+use super::client_context::{ClientContext, CLIENT_IDENTITY};
+use super::json_rpc::JsonRpcWebsocket;
+use super::method_registry::{Args, FieldSpec, MethodSpec, ParamSpec, ValueType};
+use crate::auth::Role;
+use anyhow::anyhow;
+use futures::Future;
+use jsonrpc_core::Result;
+use pianobar_webserver::protocol::timestamp_now;
+pub use pianobar_webserver::protocol::AuditEntry;
+use serde_json as json;
+use std::collections::VecDeque;
+use std::fs::OpenOptions;
+use std::sync::{Arc, Mutex};
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{broadcast, mpsc};
+
+/// The number of entries kept in memory for the `audit_log` method
+const MEMORY_CAPACITY: usize = 1000;
+const DEFAULT_LIMIT: usize = 100;
+
+/// An append-only log of all actions clients executed.
+///
+/// Recent entries are kept in memory; if a file is given, all entries
+/// are appended to it as JSON lines.
+#[derive(Clone)]
+pub struct AuditLog {
+    entries: Arc<Mutex<VecDeque<AuditEntry>>>,
+    /// The lines for the file, written by `write_task` so handlers don't block on it
+    file: Option<mpsc::UnboundedSender<String>>,
+    activity: broadcast::Sender<AuditEntry>,
+}
+
+async fn write_task(mut file: File, mut lines: mpsc::UnboundedReceiver<String>) {
+    while let Some(line) = lines.recv().await {
+        // Tokio only finishes a write on flush
+        let result = match file.write_all(line.as_bytes()).await {
+            Ok(()) => file.flush().await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = result {
+            log::warn!("Unable to write audit log: {}", err);
+        }
+    }
+}
+
+impl AuditLog {
+    /// `channel_capacity` is the number of activity notifications
+    /// a client can fall behind before it misses some
+    pub fn new(path: Option<&str>, channel_capacity: usize) -> anyhow::Result<AuditLog> {
+        let file = match path {
+            Some(path) => {
+                let path = shellexpand::tilde(path).to_string();
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)
+                    .map_err(|err| anyhow!("Unable to open audit log '{}': {}", path, err))?;
+                let (lines, receiver) = mpsc::unbounded_channel();
+                tokio::spawn(write_task(File::from_std(file), receiver));
+                Some(lines)
+            }
+            None => None,
+        };
+        let (activity, _) = broadcast::channel(channel_capacity);
+        Ok(AuditLog {
+            entries: Arc::new(Mutex::new(VecDeque::new())),
+            file,
+            activity,
+        })
+    }
+
+    fn record(&self, entry: AuditEntry) {
+        log::info!(
+            "{} ({}) called {} {}",
+            entry.client.name,
+            entry.client.address.as_deref().unwrap_or("unknown address"),
+            entry.method,
+            entry.params
+        );
+
+        if let Some(file) = &self.file {
+            match json::to_string(&entry) {
+                // The writer runs as long as the server does
+                Ok(line) => {
+                    let _ = file.send(line + "\n");
+                }
+                Err(err) => log::warn!("Unable to write audit log: {}", err),
+            }
+        }
+
+        {
+            let mut entries = self.entries.lock().unwrap();
+            if entries.len() >= MEMORY_CAPACITY {
+                entries.pop_front();
+            }
+            entries.push_back(entry.clone());
+        }
+
+        // Fails if nobody is connected, which is fine
+        let _ = self.activity.send(entry);
+    }
+
+    /// The most recent entries, oldest first
+    pub fn recent(&self, limit: usize) -> Vec<AuditEntry> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .iter()
+            .skip(entries.len().saturating_sub(limit))
+            .cloned()
+            .collect()
+    }
+
+    /// Receives every new entry
+    pub fn subscribe(&self) -> broadcast::Receiver<AuditEntry> {
+        self.activity.subscribe()
+    }
+}
+
+/// Client addresses are personal data, so only admins get to see them
+pub fn visible_to(mut entry: AuditEntry, role: Role) -> AuditEntry {
+    if role < Role::Admin {
+        entry.client.address = None;
+    }
+    entry
+}
+
+fn record_call(
+    context: &ClientContext,
+    spec: &MethodSpec,
+    params: json::Value,
+    result: &Result<json::Value>,
+) {
+    let (value, error) = match result {
+        Ok(value) => (value.clone(), None),
+        Err(err) => (json::Value::Null, Some(err.message.clone())),
+    };
+    context.audit_log.record(AuditEntry {
+        timestamp: timestamp_now(),
+        client: context.client_identity(),
+        method: spec.name.to_string(),
+        params,
+        result: value,
+        error,
+    });
+}
+
+/// Registers a method whose calls get recorded in the audit log,
+/// including the ones that were denied or had invalid parameters
+pub fn add_audited_method<F, X>(
+    handler: &mut JsonRpcWebsocket<ClientContext>,
+    spec: &'static MethodSpec,
+    method: F,
+) where
+    F: Fn(Args, ClientContext) -> X + Send + Sync + 'static,
+    X: Future<Output = Result<json::Value>> + Send + 'static,
+{
+    handler.add_method_with_rejection(
+        spec,
+        move |args, context: ClientContext| {
+            let params = args.to_json();
+            let call = method(args, context.clone());
+            async move {
+                let result = call.await;
+                record_call(&context, spec, params, &result);
+                result
+            }
+        },
+        move |params, context, error| record_call(&context, spec, params, &Err(error.clone())),
+    );
+}
+
+const AUDIT_ENTRY: ValueType = ValueType::Object(&[
+    FieldSpec {
+        name: "timestamp",
+        value_type: ValueType::UnsignedInteger,
+    },
+    FieldSpec {
+        name: "client",
+        value_type: CLIENT_IDENTITY,
+    },
+    FieldSpec {
+        name: "method",
+        value_type: ValueType::String,
+    },
+    FieldSpec {
+        name: "params",
+        value_type: ValueType::Object(&[]),
+    },
+    FieldSpec {
+        name: "result",
+        value_type: ValueType::Any,
+    },
+    FieldSpec {
+        name: "error",
+        value_type: ValueType::Nullable(&ValueType::String),
+    },
+]);
+
+const AUDIT_LOG: MethodSpec = MethodSpec {
+    name: "audit_log",
+    description: "Lists the most recent actions of all clients, oldest first. \
+                  Client addresses are only included for admins.",
+    params: &[ParamSpec {
+        name: "limit",
+        description: "The maximum number of entries. Defaults to 100.",
+        value_type: ValueType::UnsignedInteger,
+        required: false,
+    }],
+    result: ValueType::Array(&AUDIT_ENTRY),
+    role: Role::Viewer,
+};
+
+pub fn register(handler: &mut JsonRpcWebsocket<ClientContext>) {
+    handler.add_method(&AUDIT_LOG, audit_log);
+}
+
+async fn audit_log(args: Args, context: ClientContext) -> Result<json::Value> {
+    let limit = args.get_optional("limit")?.unwrap_or(DEFAULT_LIMIT);
+    let entries = context
+        .audit_log
+        .recent(limit)
+        .into_iter()
+        .map(|entry| visible_to(entry, context.identity.role))
+        .collect::<Vec<_>>();
+    Ok(json::json!(entries))
+}
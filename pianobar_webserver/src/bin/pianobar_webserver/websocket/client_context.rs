@@ -0,0 +1,177 @@
+use super::audit_log::AuditLog;
+use super::connection::SharedServices;
+use super::control_policy::ControlClient;
+use super::json_rpc::{CloseRequest, JsonRpcWebsocket};
+use super::method_registry::{Args, Caller, FieldSpec, MethodSpec, ParamSpec, ValueType};
+use super::resume::ResumeRequest;
+use super::stats::LagCounters;
+use super::subscriptions::Subscriptions;
+use crate::auth::{Identity, Role};
+use crate::PianobarActions;
+use jsonrpc_core as jsonrpc;
+pub use pianobar_webserver::protocol::ClientIdentity;
+use pianobar_webserver::protocol::ServerInfo;
+use serde_json as json;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+const MAX_NICKNAME_LENGTH: usize = 32;
+
+pub const CLIENT_IDENTITY: ValueType = ValueType::Object(&[
+    FieldSpec {
+        name: "name",
+        value_type: ValueType::String,
+    },
+    FieldSpec {
+        name: "user",
+        value_type: ValueType::Nullable(&ValueType::String),
+    },
+    FieldSpec {
+        name: "nickname",
+        value_type: ValueType::Nullable(&ValueType::String),
+    },
+    FieldSpec {
+        name: "address",
+        value_type: ValueType::Nullable(&ValueType::String),
+    },
+]);
+
+/// The per-connection state that gets passed to every JSON-RPC method
+#[derive(Clone)]
+pub struct ClientContext {
+    pub actions: PianobarActions,
+    pub identity: Identity,
+    pub control: Arc<ControlClient>,
+    pub audit_log: AuditLog,
+    pub lag_counters: LagCounters,
+    pub server_info: Arc<ServerInfo>,
+    pub close_request: CloseRequest,
+    pub resume_requests: mpsc::UnboundedSender<ResumeRequest>,
+    address: String,
+    nickname: Arc<Mutex<Option<String>>>,
+    subscriptions: Arc<Mutex<Subscriptions>>,
+}
+
+impl jsonrpc::Metadata for ClientContext {}
+
+/// Identifies the person behind a client for votes: the logged in user, or the IP address
+/// of anonymous clients. Never the nickname, clients pick that themselves.
+/// The port differs for every connection.
+fn voter(identity: &Identity, address: &str) -> String {
+    match &identity.user {
+        Some(user) => format!("user:{}", user),
+        None => {
+            let ip = match address.parse::<SocketAddr>() {
+                Ok(address) => address.ip().to_string(),
+                Err(_) => address.to_string(),
+            };
+            format!("anonymous:{}", ip)
+        }
+    }
+}
+
+impl ClientContext {
+    pub fn new(
+        identity: Identity,
+        address: String,
+        services: &SharedServices,
+        server_info: ServerInfo,
+        close_request: CloseRequest,
+        resume_requests: mpsc::UnboundedSender<ResumeRequest>,
+    ) -> Self {
+        ClientContext {
+            actions: services.pianobar_actions.clone(),
+            control: Arc::new(
+                services
+                    .control_policy
+                    .connect(identity.role, voter(&identity, &address)),
+            ),
+            identity,
+            audit_log: services.audit_log.clone(),
+            lag_counters: services.lag_counters.clone(),
+            server_info: Arc::new(server_info),
+            close_request,
+            resume_requests,
+            address,
+            nickname: Arc::new(Mutex::new(None)),
+            subscriptions: Arc::new(Mutex::new(Subscriptions::default())),
+        }
+    }
+
+    pub fn subscriptions(&self) -> Subscriptions {
+        self.subscriptions.lock().unwrap().clone()
+    }
+
+    pub fn update_subscriptions<F, R>(&self, update: F) -> R
+    where
+        F: FnOnce(&mut Subscriptions) -> R,
+    {
+        update(&mut self.subscriptions.lock().unwrap())
+    }
+
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    pub fn client_identity(&self) -> ClientIdentity {
+        let nickname = self.nickname.lock().unwrap().clone();
+        ClientIdentity {
+            name: nickname
+                .clone()
+                .or_else(|| self.identity.user.clone())
+                .unwrap_or_else(|| "anonymous".to_string()),
+            user: self.identity.user.clone(),
+            nickname,
+            address: Some(self.address.clone()),
+        }
+    }
+}
+
+impl Caller for ClientContext {
+    fn role(&self) -> Role {
+        self.identity.role
+    }
+}
+
+const SET_NICKNAME: MethodSpec = MethodSpec {
+    name: "set_nickname",
+    description: "Sets the name other clients see for this client. Returns the resulting identity.",
+    params: &[ParamSpec {
+        name: "nickname",
+        description: "The nickname, or null to remove it",
+        value_type: ValueType::Nullable(&ValueType::String),
+        required: false,
+    }],
+    result: CLIENT_IDENTITY,
+    role: Role::Viewer,
+};
+
+pub fn register(handler: &mut JsonRpcWebsocket<ClientContext>) {
+    handler.add_method(&SET_NICKNAME, set_nickname);
+}
+
+async fn set_nickname(args: Args, context: ClientContext) -> jsonrpc::Result<json::Value> {
+    let nickname = args
+        .get_optional::<String>("nickname")?
+        .map(|nickname| nickname.trim().to_string())
+        .filter(|nickname| !nickname.is_empty());
+
+    if let Some(nickname) = &nickname {
+        if nickname.chars().count() > MAX_NICKNAME_LENGTH || nickname.chars().any(char::is_control)
+        {
+            return Err(jsonrpc::Error::invalid_params(format!(
+                "Invalid nickname: at most {} printable characters are allowed",
+                MAX_NICKNAME_LENGTH
+            )));
+        }
+    }
+
+    log::info!(
+        "{} is now known as {}",
+        context.address,
+        nickname.as_deref().unwrap_or("<no nickname>")
+    );
+    *context.nickname.lock().unwrap() = nickname;
+    Ok(json::json!(context.client_identity()))
+}
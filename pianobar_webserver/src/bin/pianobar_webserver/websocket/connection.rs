@@ -1,36 +1,94 @@
-use crate::event_receiver::{PianobarUiEvent, PianobarUiEventSource, PianobarUiState};
+use crate::auth::Identity;
+use crate::event_receiver::{PianobarUiEventSource, PianobarUiSnapshot, SequencedUiEvent};
 use crate::PianobarActions;
 
-use super::json_rpc::JsonRpcWebsocket;
-use super::pianobar_actions;
+use super::audit_log::{self, AuditEntry, AuditLog};
+use super::client_context::{self, ClientContext};
+use super::control_policy::{ControlPolicy, VoteState};
+use super::json_rpc::{CloseReason, JsonRpcWebsocket, KeepaliveSettings};
+use super::protocol;
+use super::resume::{self, ResumeRequest};
+use super::stats::{self, LagCounters};
+use super::subscriptions::{self, Throttler};
 use super::PianobarPlayerState;
-use anyhow::{self, Result};
+use super::{pianobar_actions, subscriptions::ui_event_topic};
+use anyhow::{self, bail, Result};
+use futures::FutureExt;
 use jsonrpc_core as jsonrpc;
+use pianobar_webserver::protocol::{
+    timestamp_now, HistoryEntry, HistoryUpdateParams, PlayerErrorParams, ResumeOutcome, ServerInfo,
+    StateParams, UiEventParams, NOTIFICATION_ACTIVITY, NOTIFICATION_HISTORY_UPDATE,
+    NOTIFICATION_PLAYER_ERROR, NOTIFICATION_PLAYER_STATE, NOTIFICATION_UI_EVENT,
+    NOTIFICATION_VOTE_STATE, OUTPUT_ERROR_COMMAND, TOPIC_ACTIVITY, TOPIC_ERRORS, TOPIC_HISTORY,
+    TOPIC_PLAYER_STATE, TOPIC_VOTES, WELCOME_COMMAND,
+};
+use serde::Serialize;
+use serde_json as json;
 use std::borrow::Borrow;
-use std::net::SocketAddr;
-use tokio::sync::{broadcast, watch};
+use std::convert::Infallible;
+use std::time::Instant;
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::time::{sleep, sleep_until};
 use warp::ws::WebSocket;
 
-// use futures::FutureExt;
+/// pianobar's return code for successful operations, both for `pRet` and `wRet`
+const PIANOBAR_RET_OK: &str = "1";
 
-impl jsonrpc::Metadata for PianobarActions {}
+struct Notification {
+    topic: String,
+    method: &'static str,
+    params: json::Map<String, json::Value>,
+}
+
+/// Serializes the parameters of a notification, which always are an object
+fn to_params_map(params: &impl Serialize) -> Result<json::Map<String, json::Value>> {
+    match json::to_value(params)? {
+        json::Value::Object(params) => Ok(params),
+        _ => bail!("notification parameters are not an object"),
+    }
+}
+
+fn to_params(params: &impl Serialize) -> Result<jsonrpc::Params> {
+    Ok(jsonrpc::Params::Map(to_params_map(params)?))
+}
+
+/// Everything a connection shares with all other connections
+#[derive(Clone)]
+pub struct SharedServices {
+    pub pianobar_actions: PianobarActions,
+    /// The errors pianobar prints, see `PianobarPlayerStateWatcher::errors`
+    pub pianobar_errors: broadcast::Sender<String>,
+    pub control_policy: ControlPolicy,
+    pub audit_log: AuditLog,
+    pub keepalive: KeepaliveSettings,
+    /// The number of messages that may wait for a slow client before it gets disconnected
+    pub send_queue_capacity: usize,
+    pub lag_counters: LagCounters,
+    /// Identifies this server process, see `protocol::new_instance_id`
+    pub instance_id: String,
+}
 
 pub struct PianobarWebsocketConnection {
     client_address: String,
-    json_rpc_websocket: JsonRpcWebsocket<PianobarActions>,
+    identity: Identity,
+    json_rpc_websocket: JsonRpcWebsocket<ClientContext>,
 }
 
 impl PianobarWebsocketConnection {
     pub fn new(
-        client_address: Option<SocketAddr>,
+        client_address: Option<String>,
+        identity: Identity,
         websocket: WebSocket,
+        services: &SharedServices,
     ) -> PianobarWebsocketConnection {
         PianobarWebsocketConnection {
-            client_address: match client_address {
-                Some(s) => s.to_string(),
-                None => "<UNKNOWN>".to_string(),
-            },
-            json_rpc_websocket: JsonRpcWebsocket::new(websocket),
+            client_address: client_address.unwrap_or_else(|| "<UNKNOWN>".to_string()),
+            identity,
+            json_rpc_websocket: JsonRpcWebsocket::new(
+                websocket,
+                services.keepalive.clone(),
+                services.send_queue_capacity,
+            ),
         }
     }
 
@@ -38,55 +96,380 @@ impl PianobarWebsocketConnection {
         self,
         ui_events: PianobarUiEventSource,
         player_state: watch::Receiver<PianobarPlayerState>,
-        pianobar_actions: PianobarActions,
+        services: SharedServices,
     ) {
         let client_address = self.client_address.clone();
-        log::info!("connected: {}", client_address);
-        if let Err(err) = self
-            .run_with_error_handling(ui_events, player_state, pianobar_actions)
+        log::info!(
+            "connected: {} ({}, {})",
+            client_address,
+            self.identity.user.as_deref().unwrap_or("anonymous"),
+            self.identity.role.as_str()
+        );
+        match self
+            .run_with_error_handling(ui_events, player_state, services)
             .await
         {
-            log::warn!("lost connection: {}", err);
+            Ok(reason) => log::info!("disconnected: {} ({})", client_address, reason),
+            Err(err) => log::warn!("lost connection: {} ({})", client_address, err),
         }
-        log::info!("disconnected: {}", client_address);
     }
 
-    fn send_ui_event(&self, event: PianobarUiEvent) -> Result<()> {
-        self.json_rpc_websocket
-            .send_notification("ui_event", jsonrpc::Params::Map(event.into()))
+    fn send_player_state(&self, player_state: &PianobarPlayerState) -> Result<()> {
+        self.json_rpc_websocket.send_state_notification(
+            NOTIFICATION_PLAYER_STATE,
+            to_params(&StateParams {
+                state: player_state,
+            })?,
+        )
     }
 
-    fn send_player_state(&self, player_state: &PianobarPlayerState) -> Result<()> {
-        let mut params = serde_json::Map::new();
-        params.insert("state".to_string(), serde_json::to_value(player_state)?);
+    fn send_vote_state(&self, vote_state: &VoteState) -> Result<()> {
+        self.json_rpc_websocket.send_state_notification(
+            NOTIFICATION_VOTE_STATE,
+            to_params(&StateParams { state: vote_state })?,
+        )
+    }
+
+    fn send_activity(&self, entry: &AuditEntry) -> Result<()> {
+        self.json_rpc_websocket
+            .send_notification(NOTIFICATION_ACTIVITY, to_params(entry)?)
+    }
 
+    /// Sends the full ui state together with the server info.
+    /// `resync` tells the client that it missed events before.
+    fn send_welcome_message(
+        &self,
+        ui_state: PianobarUiSnapshot,
+        server_info: &ServerInfo,
+        resync: bool,
+    ) -> Result<()> {
+        let params = UiEventParams {
+            command: WELCOME_COMMAND.to_string(),
+            state: ui_state.state,
+            sequence: ui_state.sequence,
+            server: Some(server_info.clone()),
+            resync,
+        };
         self.json_rpc_websocket
-            .send_notification("player_state", jsonrpc::Params::Map(params))
+            .send_notification(NOTIFICATION_UI_EVENT, to_params(&params)?)
     }
 
-    fn send_welcome_message(&self, ui_initial_state: PianobarUiState) -> Result<()> {
-        self.send_ui_event(PianobarUiEvent {
-            command: "websocket_welcome".to_string(),
-            state: ui_initial_state,
-        })
+    /// Derives all notifications a ui event can cause, together with their topics.
+    /// All of them carry the sequence number of the event.
+    fn notifications_for(ui_event: SequencedUiEvent) -> Result<Vec<Notification>> {
+        let SequencedUiEvent {
+            sequence,
+            event: ui_event,
+        } = ui_event;
+        let get_string = |key: &str| {
+            ui_event
+                .state
+                .get(key)
+                .and_then(json::Value::as_str)
+                .unwrap_or_default()
+                .to_string()
+        };
+        let mut notifications = vec![];
+
+        // Failed pianobar operations
+        for (ret, ret_str) in [("pRet", "pRetStr"), ("wRet", "wRetStr")] {
+            if let Some(json::Value::String(code)) = ui_event.state.get(ret) {
+                if code != PIANOBAR_RET_OK {
+                    notifications.push(Notification {
+                        topic: TOPIC_ERRORS.to_string(),
+                        method: NOTIFICATION_PLAYER_ERROR,
+                        params: to_params_map(&PlayerErrorParams {
+                            command: ui_event.command.clone(),
+                            message: get_string(ret_str),
+                            sequence: Some(sequence),
+                        })?,
+                    });
+                }
+            }
+        }
+
+        // A finished song moves to the history
+        if ui_event.command == "songfinish" {
+            notifications.push(Notification {
+                topic: TOPIC_HISTORY.to_string(),
+                method: NOTIFICATION_HISTORY_UPDATE,
+                params: to_params_map(&HistoryUpdateParams {
+                    song: HistoryEntry {
+                        artist: get_string("artist"),
+                        title: get_string("title"),
+                    },
+                    sequence,
+                })?,
+            });
+        }
+
+        notifications.push(Notification {
+            topic: ui_event_topic(&ui_event.command),
+            method: NOTIFICATION_UI_EVENT,
+            params: to_params_map(&UiEventParams {
+                command: ui_event.command,
+                state: ui_event.state,
+                sequence,
+                server: None,
+                resync: false,
+            })?,
+        });
+
+        Ok(notifications)
     }
 
-    async fn events_task(&self, mut ui_events: broadcast::Receiver<PianobarUiEvent>) -> Result<()> {
+    fn send_notification(&self, notification: Notification) -> Result<()> {
+        log::debug!("send {} ...", notification.method);
+        self.json_rpc_websocket.send_notification(
+            notification.method,
+            jsonrpc::Params::Map(notification.params),
+        )
+    }
+
+    /// Sends the snapshot of the current ui state. Returns its sequence number.
+    fn resynchronize(
+        &self,
+        ui_state: &watch::Receiver<PianobarUiSnapshot>,
+        context: &ClientContext,
+    ) -> Result<u64> {
+        let ui_state = ui_state.borrow().clone();
+        let sequence = ui_state.sequence;
+        self.send_welcome_message(ui_state, &context.server_info, true)?;
+        Ok(sequence)
+    }
+
+    /// Replays the buffered events after the requested sequence number,
+    /// or sends a snapshot if they aren't available
+    fn resume(
+        &self,
+        request: ResumeRequest,
+        ui_events: &PianobarUiEventSource,
+        last_sequence: &mut u64,
+        context: &ClientContext,
+    ) -> Result<()> {
+        let replay = request.sequence.and_then(|sequence| {
+            ui_events
+                .replay_buffer
+                .events_between(sequence, *last_sequence)
+        });
+        let outcome = match replay {
+            Some(events) => {
+                log::debug!("replay {} ui events ...", events.len());
+                let subscriptions = context.subscriptions();
+                let count = events.len();
+                for event in events {
+                    for notification in Self::notifications_for(event)? {
+                        // Replays aren't throttled, the client asked for all of them
+                        if subscriptions.get(&notification.topic).is_some() {
+                            self.send_notification(notification)?;
+                        }
+                    }
+                }
+                ResumeOutcome::replay(count, *last_sequence)
+            }
+            None => {
+                log::debug!("missed ui events aren't buffered, resynchronizing ...");
+                *last_sequence = self.resynchronize(&ui_events.ui_state, context)?;
+                ResumeOutcome::snapshot(*last_sequence)
+            }
+        };
+        // The request is gone if the client disconnected in the meantime
+        let _ = request.reply.send(outcome);
+        Ok(())
+    }
+
+    async fn events_task(
+        &self,
+        mut ui_events: PianobarUiEventSource,
+        mut resume_requests: mpsc::UnboundedReceiver<ResumeRequest>,
+        context: ClientContext,
+    ) -> Result<Infallible> {
+        let mut throttler = Throttler::new();
+        // Events up to this one are already known to the client
+        let mut last_sequence = ui_events.ui_initial_state.sequence;
         loop {
-            let ui_event = ui_events.recv().await?;
-            log::debug!("send ui event ...");
-            self.send_ui_event(ui_event)?;
+            let next_deadline = throttler.next_deadline();
+            tokio::select! {
+                ui_event = ui_events.ui_events.recv() => {
+                    let ui_event = match ui_event {
+                        Ok(ui_event) => ui_event,
+                        Err(broadcast::error::RecvError::Lagged(missed)) => {
+                            // The missed events are gone, so replace the client's state entirely
+                            log::warn!(
+                                "{} missed {} ui events, resynchronizing ...",
+                                self.client_address,
+                                missed
+                            );
+                            context.lag_counters.record_ui_event_lag(missed);
+                            last_sequence = self.resynchronize(&ui_events.ui_state, &context)?;
+                            continue;
+                        }
+                        Err(err) => return Err(err.into()),
+                    };
+                    if ui_event.sequence <= last_sequence {
+                        continue;
+                    }
+                    last_sequence = ui_event.sequence;
+                    let subscriptions = context.subscriptions();
+                    for notification in Self::notifications_for(ui_event)? {
+                        let throttle = match subscriptions.get(&notification.topic) {
+                            Some(throttle) => throttle,
+                            None => continue,
+                        };
+                        if let Some(notification) =
+                            throttler.submit(notification.topic.clone(), throttle, notification)
+                        {
+                            self.send_notification(notification)?;
+                        }
+                    }
+                }
+                _ = sleep_until(next_deadline.unwrap_or_else(Instant::now).into()),
+                    if next_deadline.is_some() =>
+                {
+                    for notification in throttler.take_due() {
+                        self.send_notification(notification)?;
+                    }
+                }
+                Some(request) = resume_requests.recv() => {
+                    self.resume(request, &ui_events, &mut last_sequence, &context)?;
+                }
+            }
         }
     }
 
     async fn player_state_task(
         &self,
         mut player_state: watch::Receiver<PianobarPlayerState>,
-    ) -> Result<()> {
+        context: ClientContext,
+    ) -> Result<Infallible> {
+        // Send the current state right away, the next one might take a while.
+        // Mark it as seen first, otherwise it would be sent twice.
+        let _ = player_state.changed().now_or_never();
         loop {
+            if let Some(throttle) = context.subscriptions().get(TOPIC_PLAYER_STATE) {
+                log::debug!("send new player state ...");
+                // Clients predict the position from the time they receive the state
+                let state = player_state.borrow().at(timestamp_now());
+                self.send_player_state(&state)?;
+
+                // Changes that happen in the meantime get coalesced by the watch channel
+                if let Some(throttle) = throttle {
+                    sleep(throttle).await;
+                }
+            }
             player_state.changed().await?;
-            log::debug!("send new player state ...");
-            self.send_player_state(player_state.borrow().borrow())?;
+        }
+    }
+
+    async fn vote_state_task(
+        &self,
+        mut vote_state: watch::Receiver<VoteState>,
+        context: ClientContext,
+    ) -> Result<Infallible> {
+        // Send the current votes right away, so the client doesn't miss pending ones.
+        // Mark them as seen first, otherwise they would be sent twice.
+        let _ = vote_state.changed().now_or_never();
+        loop {
+            if let Some(throttle) = context.subscriptions().get(TOPIC_VOTES) {
+                log::debug!("send vote state ...");
+                self.send_vote_state(vote_state.borrow().borrow())?;
+
+                // Changes that happen in the meantime get coalesced by the watch channel
+                if let Some(throttle) = throttle {
+                    sleep(throttle).await;
+                }
+            }
+            vote_state.changed().await?;
+        }
+    }
+
+    async fn player_errors_task(
+        &self,
+        mut errors: broadcast::Receiver<String>,
+        context: ClientContext,
+    ) -> Result<Infallible> {
+        let send = |message| {
+            log::debug!("send player error ...");
+            self.json_rpc_websocket.send_notification(
+                NOTIFICATION_PLAYER_ERROR,
+                to_params(&PlayerErrorParams {
+                    command: OUTPUT_ERROR_COMMAND.to_string(),
+                    message,
+                    sequence: None,
+                })?,
+            )
+        };
+        let mut throttler = Throttler::new();
+        loop {
+            let next_deadline = throttler.next_deadline();
+            tokio::select! {
+                message = errors.recv() => {
+                    let message = match message {
+                        Ok(message) => message,
+                        Err(broadcast::error::RecvError::Lagged(missed)) => {
+                            log::warn!("{} missed {} player errors", self.client_address, missed);
+                            continue;
+                        }
+                        Err(err) => return Err(err.into()),
+                    };
+                    if let Some(throttle) = context.subscriptions().get(TOPIC_ERRORS) {
+                        if let Some(message) = throttler.submit(TOPIC_ERRORS.to_string(), throttle, message) {
+                            send(message)?;
+                        }
+                    }
+                }
+                _ = sleep_until(next_deadline.unwrap_or_else(Instant::now).into()),
+                    if next_deadline.is_some() =>
+                {
+                    for message in throttler.take_due() {
+                        send(message)?;
+                    }
+                }
+            }
+        }
+    }
+
+    async fn activity_task(
+        &self,
+        mut activity: broadcast::Receiver<AuditEntry>,
+        context: ClientContext,
+    ) -> Result<Infallible> {
+        let send = |entry| {
+            log::debug!("send activity ...");
+            self.send_activity(&audit_log::visible_to(entry, context.identity.role))
+        };
+        let mut throttler = Throttler::new();
+        loop {
+            let next_deadline = throttler.next_deadline();
+            tokio::select! {
+                entry = activity.recv() => {
+                    let entry = match entry {
+                        Ok(entry) => entry,
+                        Err(broadcast::error::RecvError::Lagged(missed)) => {
+                            log::warn!(
+                                "{} missed {} activity notifications",
+                                self.client_address,
+                                missed
+                            );
+                            context.lag_counters.record_activity_lag(missed);
+                            continue;
+                        }
+                        Err(err) => return Err(err.into()),
+                    };
+                    if let Some(throttle) = context.subscriptions().get(TOPIC_ACTIVITY) {
+                        if let Some(entry) = throttler.submit(TOPIC_ACTIVITY.to_string(), throttle, entry) {
+                            send(entry)?;
+                        }
+                    }
+                }
+                _ = sleep_until(next_deadline.unwrap_or_else(Instant::now).into()),
+                    if next_deadline.is_some() =>
+                {
+                    for entry in throttler.take_due() {
+                        send(entry)?;
+                    }
+                }
+            }
         }
     }
 
@@ -94,23 +477,54 @@ impl PianobarWebsocketConnection {
         mut self,
         ui_events: PianobarUiEventSource,
         player_state: watch::Receiver<PianobarPlayerState>,
-        pianobar_actions: PianobarActions,
-    ) -> Result<()> {
+        services: SharedServices,
+    ) -> Result<CloseReason> {
+        protocol::register(&mut self.json_rpc_websocket);
+        pianobar_actions::register(&mut self.json_rpc_websocket);
+        subscriptions::register(&mut self.json_rpc_websocket);
+        client_context::register(&mut self.json_rpc_websocket);
+        audit_log::register(&mut self.json_rpc_websocket);
+        stats::register(&mut self.json_rpc_websocket);
+        resume::register(&mut self.json_rpc_websocket);
+        self.json_rpc_websocket.add_discover_method();
+
+        let server_info = protocol::server_info(
+            &services.instance_id,
+            self.json_rpc_websocket.method_names(),
+        );
+
         // Send welcome message
         log::debug!("send welcome message ...");
-        self.send_welcome_message(ui_events.ui_initial_state)?;
+        self.send_welcome_message(ui_events.ui_initial_state.clone(), &server_info, false)?;
 
-        pianobar_actions::register(&mut self.json_rpc_websocket);
+        let (resume_sender, resume_receiver) = mpsc::unbounded_channel();
+        let context = ClientContext::new(
+            self.identity.clone(),
+            self.client_address.clone(),
+            &services,
+            server_info,
+            self.json_rpc_websocket.close_request(),
+            resume_sender,
+        );
 
         // Start tasks
-        let events_task = self.events_task(ui_events.ui_events);
-        let player_state_task = self.player_state_task(player_state);
+        let events_task = self.events_task(ui_events, resume_receiver, context.clone());
+        let player_state_task = self.player_state_task(player_state, context.clone());
+        let vote_state_task =
+            self.vote_state_task(services.control_policy.subscribe(), context.clone());
+        let activity_task = self.activity_task(services.audit_log.subscribe(), context.clone());
+        let player_errors_task =
+            self.player_errors_task(services.pianobar_errors.subscribe(), context.clone());
 
         // Wait until the first task finished
+        // Only the websocket ends regularly, all other tasks end on errors only
         tokio::select!(
-            ret = self.json_rpc_websocket.run(pianobar_actions) => ret,
-            ret = events_task => ret,
-            ret = player_state_task => ret,
+            ret = self.json_rpc_websocket.run(context) => ret,
+            ret = events_task => match ret? {},
+            ret = player_state_task => match ret? {},
+            ret = vote_state_task => match ret? {},
+            ret = activity_task => match ret? {},
+            ret = player_errors_task => match ret? {},
         )
     }
 }
@@ -0,0 +1,420 @@
+use crate::auth::Role;
+use crate::event_receiver::{PianobarUiEvent, SequencedUiEvent};
+use crate::PianobarActions;
+use anyhow::{anyhow, bail, Result};
+pub use pianobar_webserver::protocol::{PendingVote, VoteState};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc, watch, Notify};
+use tokio::time::sleep_until;
+
+/// How shared actions like skipping are controlled
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ControlMode {
+    /// Every listener can execute every action instantly
+    Direct,
+    /// Shared actions need a quorum of votes of the connected listeners
+    Vote,
+}
+
+impl ControlMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ControlMode::Direct => "direct",
+            ControlMode::Vote => "vote",
+        }
+    }
+}
+
+impl FromStr for ControlMode {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "direct" => Ok(ControlMode::Direct),
+            "vote" => Ok(ControlMode::Vote),
+            _ => Err(anyhow!(
+                "Unknown control mode '{}', expected 'direct' or 'vote'",
+                value
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ControlSettings {
+    pub mode: ControlMode,
+    /// The fraction of connected listeners that has to agree, between 0 and 1
+    pub quorum: f64,
+    /// How long a vote stays open after the first vote
+    pub window: Duration,
+}
+
+impl ControlSettings {
+    pub fn new(mode: ControlMode, quorum: f64, window: Duration) -> Result<ControlSettings> {
+        if !(quorum > 0.0 && quorum <= 1.0) {
+            bail!("The vote quorum has to be in (0, 1], got {}", quorum);
+        }
+        Ok(ControlSettings {
+            mode,
+            quorum,
+            window,
+        })
+    }
+}
+
+/// An action that is subject to the control policy
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum VoteAction {
+    Skip,
+    Ban,
+    ChangeStation(usize),
+}
+
+impl VoteAction {
+    pub fn parse(action: &str, station_id: Option<usize>) -> Result<VoteAction> {
+        match (action, station_id) {
+            ("skip", None) => Ok(VoteAction::Skip),
+            ("ban", None) => Ok(VoteAction::Ban),
+            ("change_station", Some(station_id)) => Ok(VoteAction::ChangeStation(station_id)),
+            ("change_station", None) => bail!("'change_station' needs a station id"),
+            ("skip", Some(_)) | ("ban", Some(_)) => bail!("'{}' takes no station id", action),
+            _ => bail!("Unknown action: '{}'", action),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            VoteAction::Skip => "skip",
+            VoteAction::Ban => "ban",
+            VoteAction::ChangeStation(_) => "change_station",
+        }
+    }
+
+    fn station_id(self) -> Option<usize> {
+        match self {
+            VoteAction::ChangeStation(station_id) => Some(station_id),
+            _ => None,
+        }
+    }
+
+    /// Skip and ban votes refer to the song that is currently playing
+    fn is_song_specific(self) -> bool {
+        matches!(self, VoteAction::Skip | VoteAction::Ban)
+    }
+}
+
+/// What to do with a requested action
+pub enum Decision {
+    Execute,
+    Pending(PendingVote),
+}
+
+struct Vote {
+    /// The ids of the clients that voted
+    clients: BTreeSet<u64>,
+    expires: Instant,
+}
+
+struct Client {
+    role: Role,
+    /// Who is behind the client. Clients of the same voter count once,
+    /// so nobody reaches the quorum alone with several tabs.
+    voter: String,
+}
+
+#[derive(Default)]
+struct PolicyState {
+    next_client_id: u64,
+    clients: HashMap<u64, Client>,
+    votes: BTreeMap<VoteAction, Vote>,
+}
+
+impl PolicyState {
+    fn listeners(&self) -> usize {
+        self.clients
+            .values()
+            .filter(|client| client.role >= Role::Listener)
+            .map(|client| &client.voter)
+            .collect::<HashSet<_>>()
+            .len()
+    }
+
+    fn votes(&self, vote: &Vote) -> usize {
+        vote.clients
+            .iter()
+            .filter_map(|id| self.clients.get(id))
+            .map(|client| &client.voter)
+            .collect::<HashSet<_>>()
+            .len()
+    }
+
+    /// Removes the votes that reached the quorum, which can drop when listeners leave
+    fn take_passed(&mut self, quorum: f64) -> Vec<VoteAction> {
+        let required_votes = self.required_votes(quorum);
+        let passed = self
+            .votes
+            .iter()
+            .filter(|(_, vote)| self.votes(vote) >= required_votes)
+            .map(|(action, _)| *action)
+            .collect::<Vec<_>>();
+        for action in &passed {
+            self.votes.remove(action);
+        }
+        passed
+    }
+
+    fn required_votes(&self, quorum: f64) -> usize {
+        ((self.listeners() as f64 * quorum).ceil() as usize).max(1)
+    }
+
+    fn remove_expired(&mut self, now: Instant) {
+        self.votes.retain(|_, vote| vote.expires > now);
+    }
+
+    fn pending_vote(&self, action: VoteAction, vote: &Vote, quorum: f64) -> PendingVote {
+        PendingVote {
+            action: action.name().to_string(),
+            station_id: action.station_id(),
+            votes: self.votes(vote),
+            required_votes: self.required_votes(quorum),
+            expires_in: vote
+                .expires
+                .saturating_duration_since(Instant::now())
+                .as_secs_f64(),
+        }
+    }
+}
+
+/// Decides whether actions of clients get executed instantly or need votes.
+///
+/// Sits between the JSON-RPC methods and `PianobarActions`.
+/// Admins always bypass votes.
+#[derive(Clone)]
+pub struct ControlPolicy {
+    settings: ControlSettings,
+    state: Arc<Mutex<PolicyState>>,
+    vote_state_sender: Arc<watch::Sender<VoteState>>,
+    vote_state_receiver: watch::Receiver<VoteState>,
+    votes_changed: Arc<Notify>,
+    /// Executes votes that pass without a new vote, because the quorum dropped
+    actions: PianobarActions,
+    passed_votes: mpsc::UnboundedSender<VoteAction>,
+    passed_votes_receiver: Arc<tokio::sync::Mutex<mpsc::UnboundedReceiver<VoteAction>>>,
+}
+
+impl ControlPolicy {
+    pub fn new(settings: ControlSettings, actions: &PianobarActions) -> ControlPolicy {
+        let (vote_state_sender, vote_state_receiver) = watch::channel(VoteState {
+            mode: settings.mode.as_str().to_string(),
+            listeners: 0,
+            required_votes: 1,
+            votes: vec![],
+        });
+        let (passed_votes, passed_votes_receiver) = mpsc::unbounded_channel();
+        ControlPolicy {
+            settings,
+            state: Arc::new(Mutex::new(PolicyState::default())),
+            vote_state_sender: Arc::new(vote_state_sender),
+            vote_state_receiver,
+            votes_changed: Arc::new(Notify::new()),
+            actions: actions.clone(),
+            passed_votes,
+            passed_votes_receiver: Arc::new(tokio::sync::Mutex::new(passed_votes_receiver)),
+        }
+    }
+
+    /// Registers a connected client. It is unregistered when the returned handle is dropped.
+    ///
+    /// `voter` identifies the person behind the client, clients of the same person count once.
+    pub fn connect(&self, role: Role, voter: String) -> ControlClient {
+        let id = {
+            let mut state = self.state.lock().unwrap();
+            let id = state.next_client_id;
+            state.next_client_id += 1;
+            state.clients.insert(id, Client { role, voter });
+            self.publish(&state);
+            id
+        };
+        ControlClient {
+            id,
+            role,
+            policy: self.clone(),
+        }
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<VoteState> {
+        self.vote_state_receiver.clone()
+    }
+
+    /// Discards a pending vote. Returns false if there was none.
+    pub fn cancel(&self, action: VoteAction) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let cancelled = state.votes.remove(&action).is_some();
+        if cancelled {
+            log::info!("Vote for {:?} cancelled", action);
+            self.publish(&state);
+        }
+        cancelled
+    }
+
+    fn publish(&self, state: &PolicyState) {
+        let vote_state = VoteState {
+            mode: self.settings.mode.as_str().to_string(),
+            listeners: state.listeners(),
+            required_votes: state.required_votes(self.settings.quorum),
+            votes: state
+                .votes
+                .iter()
+                .map(|(action, vote)| state.pending_vote(*action, vote, self.settings.quorum))
+                .collect(),
+        };
+        // There is always a receiver, because the policy holds one itself
+        let _ = self.vote_state_sender.send(vote_state);
+        self.votes_changed.notify_one();
+    }
+
+    fn request(&self, client_id: u64, role: Role, action: VoteAction) -> Decision {
+        let mut state = self.state.lock().unwrap();
+
+        if self.settings.mode == ControlMode::Direct {
+            return Decision::Execute;
+        }
+        if role >= Role::Admin {
+            // Admins override pending votes
+            if state.votes.remove(&action).is_some() {
+                log::info!("Vote for {:?} overridden by admin", action);
+                self.publish(&state);
+            }
+            return Decision::Execute;
+        }
+
+        let now = Instant::now();
+        state.remove_expired(now);
+        let window = self.settings.window;
+        state
+            .votes
+            .entry(action)
+            .or_insert_with(|| Vote {
+                clients: BTreeSet::new(),
+                expires: now + window,
+            })
+            .clients
+            .insert(client_id);
+        let votes = state.votes(&state.votes[&action]);
+
+        let decision = if votes >= state.required_votes(self.settings.quorum) {
+            log::info!("Vote for {:?} passed with {} votes", action, votes);
+            state.votes.remove(&action);
+            Decision::Execute
+        } else {
+            Decision::Pending(state.pending_vote(
+                action,
+                &state.votes[&action],
+                self.settings.quorum,
+            ))
+        };
+        self.publish(&state);
+        decision
+    }
+
+    /// Hands the votes that passed since the quorum dropped to `run`
+    fn execute_passed(&self, state: &mut PolicyState) {
+        for action in state.take_passed(self.settings.quorum) {
+            log::info!("Vote for {:?} passed, the quorum dropped", action);
+            // Fails if `run` ended, then nothing gets executed anymore anyway
+            let _ = self.passed_votes.send(action);
+        }
+    }
+
+    fn disconnect(&self, client_id: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.clients.remove(&client_id);
+        for vote in state.votes.values_mut() {
+            vote.clients.remove(&client_id);
+        }
+        state.votes.retain(|_, vote| !vote.clients.is_empty());
+        self.execute_passed(&mut state);
+        self.publish(&state);
+    }
+
+    async fn execute(&self, action: VoteAction) {
+        let result = match action {
+            VoteAction::Skip => self.actions.skip().await,
+            VoteAction::Ban => self.actions.ban().await,
+            VoteAction::ChangeStation(station_id) => self.actions.change_station(station_id).await,
+        };
+        if let Err(err) = result {
+            log::warn!("Unable to execute the vote for {:?}: {}", action, err);
+        }
+    }
+
+    fn next_expiry(&self) -> Option<Instant> {
+        let state = self.state.lock().unwrap();
+        state.votes.values().map(|vote| vote.expires).min()
+    }
+
+    fn on_ui_event(&self, ui_event: &PianobarUiEvent) {
+        if ui_event.command != "songstart" {
+            return;
+        }
+        // Votes against the previous song are obsolete
+        let mut state = self.state.lock().unwrap();
+        let count = state.votes.len();
+        state.votes.retain(|action, _| !action.is_song_specific());
+        if state.votes.len() != count {
+            self.publish(&state);
+        }
+    }
+
+    /// Expires old votes, discards song specific votes when a new song starts
+    /// and executes votes that passed because listeners left
+    pub async fn run(&self, mut ui_events: broadcast::Receiver<SequencedUiEvent>) -> Result<()> {
+        let mut passed_votes = self.passed_votes_receiver.lock().await;
+        loop {
+            let next_expiry = self.next_expiry();
+            tokio::select! {
+                Some(action) = passed_votes.recv() => self.execute(action).await,
+                ui_event = ui_events.recv() => match ui_event {
+                    Ok(ui_event) => self.on_ui_event(&ui_event.event),
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(err) => return Err(err.into()),
+                },
+                _ = self.votes_changed.notified() => {}
+                _ = sleep_until(next_expiry.unwrap_or_else(Instant::now).into()),
+                    if next_expiry.is_some() =>
+                {
+                    let mut state = self.state.lock().unwrap();
+                    state.remove_expired(Instant::now());
+                    self.publish(&state);
+                }
+            }
+        }
+    }
+}
+
+/// A connected client, as seen by the control policy
+pub struct ControlClient {
+    id: u64,
+    role: Role,
+    policy: ControlPolicy,
+}
+
+impl ControlClient {
+    /// Casts a vote for the given action, or lets it pass directly
+    pub fn request(&self, action: VoteAction) -> Decision {
+        self.policy.request(self.id, self.role, action)
+    }
+
+    pub fn policy(&self) -> &ControlPolicy {
+        &self.policy
+    }
+}
+
+impl Drop for ControlClient {
+    fn drop(&mut self) {
+        self.policy.disconnect(self.id);
+    }
+}
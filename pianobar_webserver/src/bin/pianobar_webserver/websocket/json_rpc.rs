@@ -1,28 +1,123 @@
+use super::method_registry::{Args, Caller, MethodRegistry, MethodSpec, DISCOVER};
+use super::send_queue::SendQueue;
 use anyhow::{self, bail, Result};
 use futures::stream::SplitStream;
-use futures::{SinkExt, StreamExt};
+use futures::{Future, SinkExt, StreamExt};
 use jsonrpc_core as jsonrpc;
+use pianobar_webserver::protocol::CLOSE_TOO_SLOW;
 use serde_json as json;
+use std::collections::VecDeque;
 use std::sync::Arc;
-use tokio::sync::mpsc;
-use tokio::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::{Mutex, Notify};
+use tokio::time::{interval_at, sleep, sleep_until, timeout, Instant, MissedTickBehavior};
 use warp::ws::{Message, WebSocket};
 
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_GOING_AWAY: u16 = 1001;
+/// How long to wait for the close message to be sent when closing the connection
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// When and how to check whether the peer is still there
+#[derive(Clone, Debug)]
+pub struct KeepaliveSettings {
+    /// How often to ping the peer. `None` disables pings.
+    pub ping_interval: Option<Duration>,
+    /// The number of pings that may stay unanswered before the peer is considered dead
+    pub max_missed_pongs: u32,
+    /// Disconnects peers that didn't send a message for this long
+    pub idle_timeout: Option<Duration>,
+}
+
+/// Why a connection ended without an error
+#[derive(Debug)]
+pub enum CloseReason {
+    /// The peer closed the connection
+    Client { code: Option<u16>, message: String },
+    /// The peer didn't answer the given number of pings
+    MissedPongs(u32),
+    /// The peer didn't send a message for too long
+    IdleTimeout,
+    /// A method asked to close the connection
+    Requested { code: u16, message: String },
+    /// The peer sent this many messages while a request was pending
+    TooManyPending(usize),
+}
+
+impl std::fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CloseReason::Client { code: None, .. } => write!(f, "closed without code"),
+            CloseReason::Client {
+                code: Some(code),
+                message,
+            } if message.is_empty() => write!(f, "closed with code {}", code),
+            CloseReason::Client {
+                code: Some(code),
+                message,
+            } => write!(f, "closed with code {}: {}", code, message),
+            CloseReason::MissedPongs(count) => write!(f, "{} pings without pong", count),
+            CloseReason::IdleTimeout => write!(f, "idle timeout"),
+            CloseReason::Requested { code, message } => {
+                write!(f, "closed by server with code {}: {}", code, message)
+            }
+            CloseReason::TooManyPending(count) => {
+                write!(f, "{} messages sent during a pending request", count)
+            }
+        }
+    }
+}
+
+/// Lets methods close the connection once their response is sent
+#[derive(Clone, Default)]
+pub struct CloseRequest {
+    requested: Arc<std::sync::Mutex<Option<(u16, String)>>>,
+}
+
+impl CloseRequest {
+    pub fn close(&self, code: u16, reason: &str) {
+        self.requested
+            .lock()
+            .unwrap()
+            .get_or_insert_with(|| (code, reason.to_string()));
+    }
+
+    fn take(&self) -> Option<(u16, String)> {
+        self.requested.lock().unwrap().take()
+    }
+}
+
 pub struct JsonRpcWebsocket<T: jsonrpc::Metadata> {
-    send_queue: mpsc::UnboundedSender<Message>,
+    send_queue: Arc<SendQueue>,
     websocket_receiver: Arc<Mutex<SplitStream<WebSocket>>>,
     jsonrpc_handler: jsonrpc::MetaIoHandler<T>,
-    receive_task: tokio::task::JoinHandle<()>,
+    method_registry: MethodRegistry,
+    keepalive: KeepaliveSettings,
+    send_task_finished: Arc<Notify>,
+    close_request: CloseRequest,
+    /// The number of messages the peer may send while a request is pending
+    receive_queue_capacity: usize,
+    receive_task: Option<tokio::task::JoinHandle<()>>,
 }
 
 impl<T: jsonrpc::Metadata> JsonRpcWebsocket<T> {
-    pub fn new(websocket: WebSocket) -> Self {
+    /// `send_queue_capacity` is the number of ordered messages that may be pending,
+    /// before the client is considered too slow and gets disconnected.
+    /// It also limits the messages the client may send while a request is pending.
+    pub fn new(
+        websocket: WebSocket,
+        keepalive: KeepaliveSettings,
+        send_queue_capacity: usize,
+    ) -> Self {
         let (mut websocket_sender, websocket_receiver) = websocket.split();
 
-        // Move sender to separate task and abstract it behind an mpsc queue
-        let (send_queue, mut send_queue_receiver) = mpsc::unbounded_channel::<warp::ws::Message>();
+        // Move sender to separate task and abstract it behind a queue
+        let send_queue = Arc::new(SendQueue::new(send_queue_capacity));
+        let send_queue_receiver = send_queue.clone();
+        let send_task_finished = Arc::new(Notify::new());
+        let send_task_finished_sender = send_task_finished.clone();
         let receive_task = tokio::task::spawn(async move {
-            while let Some(item) = send_queue_receiver.recv().await {
+            while let Some(item) = send_queue_receiver.pop().await {
                 let is_close = item.is_close();
                 if let Err(err) = websocket_sender.send(item).await {
                     if !is_close {
@@ -30,29 +125,54 @@ impl<T: jsonrpc::Metadata> JsonRpcWebsocket<T> {
                     }
                     break;
                 }
+                // Nothing can be sent after a close message
+                if is_close {
+                    break;
+                }
             }
             log::debug!("send task ended");
+            send_task_finished_sender.notify_one();
         });
 
         JsonRpcWebsocket {
             send_queue,
             websocket_receiver: Arc::new(Mutex::new(websocket_receiver)),
             jsonrpc_handler: jsonrpc::MetaIoHandler::default(),
-            receive_task,
+            method_registry: MethodRegistry::default(),
+            keepalive,
+            send_task_finished,
+            close_request: CloseRequest::default(),
+            receive_queue_capacity: send_queue_capacity,
+            receive_task: Some(receive_task),
         }
     }
 
-    pub fn send_notification(&self, method: &str, params: jsonrpc::Params) -> Result<()> {
+    fn notification_message(method: &str, params: jsonrpc::Params) -> Result<Message> {
         let message = jsonrpc::Notification {
             jsonrpc: Some(jsonrpc::Version::V2),
             method: method.to_string(),
-            params: params,
+            params,
         };
+        Ok(Message::text(json::to_string(&message)?))
+    }
 
-        self.send_queue
-            .send(Message::text(json::to_string(&message)?))?;
+    /// Queues a message in order. Disconnects the client if too many messages are pending.
+    fn send(&self, message: Message) -> Result<()> {
+        self.send_queue.push(message, || {
+            Message::close_with(CLOSE_TOO_SLOW, "client too slow")
+        })
+    }
 
-        Ok(())
+    /// Sends a notification after all previously queued ones
+    pub fn send_notification(&self, method: &str, params: jsonrpc::Params) -> Result<()> {
+        self.send(Self::notification_message(method, params)?)
+    }
+
+    /// Sends a notification that only matters in its latest version, like a state update.
+    /// Replaces a notification of the same method that wasn't sent yet.
+    pub fn send_state_notification(&self, method: &str, params: jsonrpc::Params) -> Result<()> {
+        self.send_queue
+            .replace(method, Self::notification_message(method, params)?)
     }
 
     /// Processes a text message sent by the connected user
@@ -63,62 +183,250 @@ impl<T: jsonrpc::Metadata> JsonRpcWebsocket<T> {
     async fn handle_message(&self, message: &str, meta: T) -> Result<()> {
         // Just echo all messages
         if let Some(response) = self.jsonrpc_handler.handle_request(message, meta).await {
-            self.send_queue.send(Message::text(response))?;
+            self.send(Message::text(response))?;
         }
 
         // Handled message successfully
         Ok(())
     }
 
-    pub async fn run(&self, meta: T) -> Result<()> {
+    /// Like `handle_message`, but gives up once the peer is gone. That drops the method,
+    /// which cancels the pianobar command it waits for.
+    /// What the peer sends in the meantime gets added to `received`, except for pongs,
+    /// which reset `unanswered_pings` right away. Returns why the connection got closed,
+    /// if the peer sent more than `received` may hold.
+    async fn handle_message_while_connected(
+        &self,
+        message: &str,
+        meta: T,
+        websocket_receiver: &mut SplitStream<WebSocket>,
+        received: &mut VecDeque<Option<Result<Message, warp::Error>>>,
+        unanswered_pings: &mut u32,
+    ) -> Result<Option<CloseReason>> {
+        let mut handling = Box::pin(self.handle_message(message, meta));
+        loop {
+            tokio::select! {
+                result = &mut handling => return result.map(|()| None),
+                value = websocket_receiver.next() => {
+                    if matches!(&value, Some(Ok(value)) if value.is_pong()) {
+                        *unanswered_pings = 0;
+                        continue;
+                    }
+                    let connected = matches!(&value, Some(Ok(value)) if !value.is_close());
+                    if connected && received.len() >= self.receive_queue_capacity {
+                        self.close(CLOSE_TOO_SLOW, "too many pending messages").await?;
+                        return Ok(Some(CloseReason::TooManyPending(received.len() + 1)));
+                    }
+                    received.push_back(value);
+                    if !connected {
+                        log::debug!("Peer gone, cancelling the pending request");
+                        return Ok(None);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Sends a close message and waits until it is sent, or the peer turned out to be gone
+    async fn close(&self, code: u16, reason: &str) -> Result<()> {
+        self.send_queue
+            .close(Message::close_with(code, reason.to_string()));
+        let _ = timeout(CLOSE_TIMEOUT, self.send_task_finished.notified()).await;
+        Ok(())
+    }
+
+    /// Like `close`, but sends all pending messages first
+    async fn close_after_pending(&self, code: u16, reason: &str) -> Result<()> {
+        self.send_queue
+            .finish(Message::close_with(code, reason.to_string()));
+        let _ = timeout(CLOSE_TIMEOUT, self.send_task_finished.notified()).await;
+        Ok(())
+    }
+
+    /// A handle that closes this connection, for use in method contexts
+    pub fn close_request(&self) -> CloseRequest {
+        self.close_request.clone()
+    }
+
+    /// The names of all registered methods
+    pub fn method_names(&self) -> Vec<&'static str> {
+        self.method_registry.method_names()
+    }
+
+    pub async fn run(&self, meta: T) -> Result<CloseReason> {
         let mut websocket_receiver = self.websocket_receiver.try_lock()?;
 
-        while let Some(value) = websocket_receiver.next().await {
-            let value = value?;
+        let mut ping_interval = self.keepalive.ping_interval.map(|period| {
+            let mut interval = interval_at(Instant::now() + period, period);
+            // Pings missed during a slow request must not fire all at once
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            interval
+        });
+        let mut unanswered_pings = 0;
+        let mut last_activity = Instant::now();
+        // Received while a message was handled
+        let mut received = VecDeque::new();
+
+        loop {
+            let idle_deadline = self
+                .keepalive
+                .idle_timeout
+                .map(|idle_timeout| last_activity + idle_timeout);
+
+            tokio::select! {
+                value = async {
+                    match received.pop_front() {
+                        Some(value) => value,
+                        None => websocket_receiver.next().await,
+                    }
+                } => {
+                    // If the stream ended, this indicates that the client disconnected without a 'Close' message.
+                    let value = match value {
+                        Some(value) => value?,
+                        None => bail!("connection closed without close message"),
+                    };
+
+                    // Handle close
+                    if value.is_close() {
+                        let (code, message) = match value.close_frame() {
+                            Some((code, message)) => (Some(code), message.to_string()),
+                            None => (None, String::new()),
+                        };
+                        // Send will fail, but triggering the send command again
+                        // is necessary to enable proper websocket shutdown
+                        self.send_queue.close(value);
+                        return Ok(CloseReason::Client { code, message });
+                    }
+                    // Handle keepalive
+                    else if value.is_pong() {
+                        unanswered_pings = 0;
+                    }
+                    // Handle message
+                    else if value.is_text() {
+                        last_activity = Instant::now();
+                        let message = match value.to_str() {
+                            Ok(msg) => msg,
+                            Err(()) => bail!("expected string, didn't receive string"),
+                        };
+                        let started = Instant::now();
+                        if let Some(reason) = self
+                            .handle_message_while_connected(
+                                message,
+                                meta.clone(),
+                                &mut websocket_receiver,
+                                &mut received,
+                                &mut unanswered_pings,
+                            )
+                            .await?
+                        {
+                            return Ok(reason);
+                        }
+                        // No pings got sent meanwhile, so the next one is a full period away
+                        if let (Some(interval), Some(period)) =
+                            (ping_interval.as_mut(), self.keepalive.ping_interval)
+                        {
+                            if started.elapsed() >= period {
+                                interval.reset();
+                            }
+                        }
 
-            // Handle close
-            if value.is_close() {
-                if let Some((code, message)) = value.close_frame() {
-                    if message.is_empty() {
-                        log::debug!("closed with code {}", code);
-                    } else {
-                        log::debug!("closed with code {}: {}", code, message);
+                        // The response of the method that requested the close is already queued
+                        if let Some((code, message)) = self.close_request.take() {
+                            self.close_after_pending(code, &message).await?;
+                            return Ok(CloseReason::Requested { code, message });
+                        }
                     }
-                } else {
-                    log::debug!("closed without code");
                 }
-                // Send will fail, but triggering the send command again
-                // is necessary to enable proper websocket shutdown
-                self.send_queue.send(value)?;
-                return Ok(());
-            }
-            // Handle message
-            else if value.is_text() {
-                let message = match value.to_str() {
-                    Ok(msg) => msg,
-                    Err(()) => bail!("expected string, didn't receive string"),
-                };
-                self.handle_message(message, meta.clone()).await?;
+                _ = async { ping_interval.as_mut().unwrap().tick().await }, if ping_interval.is_some() => {
+                    if unanswered_pings >= self.keepalive.max_missed_pongs {
+                        self.close(CLOSE_GOING_AWAY, "no pong received").await?;
+                        return Ok(CloseReason::MissedPongs(unanswered_pings));
+                    }
+                    unanswered_pings += 1;
+                    self.send(Message::ping(Vec::new()))?;
+                }
+                _ = sleep_until(idle_deadline.unwrap_or_else(Instant::now)), if idle_deadline.is_some() => {
+                    self.close(CLOSE_NORMAL, "idle timeout").await?;
+                    return Ok(CloseReason::IdleTimeout);
+                }
             }
         }
-
-        // If the loop ended, this indicates that the client disconnected without a 'Close' message.
-        Err(anyhow::Error::msg(
-            "connection closed without close message",
-        ))
     }
 
-    pub fn add_method<F>(&mut self, name: &str, method: F)
+    /// Registers a method. Its parameters get validated against `spec`
+    /// before `method` gets called.
+    pub fn add_method<F, X>(&mut self, spec: &'static MethodSpec, method: F)
     where
-        F: jsonrpc::RpcMethod<T>,
+        T: Caller,
+        F: Fn(Args, T) -> X + Send + Sync + 'static,
+        X: Future<Output = jsonrpc::Result<json::Value>> + Send + 'static,
+    {
+        self.add_method_with_rejection(spec, method, |_params, _meta, _error| ());
+    }
+
+    /// Like `add_method`, but calls `rejected` with the raw parameters
+    /// if the caller lacks the permission or the parameters are invalid.
+    pub fn add_method_with_rejection<F, X, R>(
+        &mut self,
+        spec: &'static MethodSpec,
+        method: F,
+        rejected: R,
+    ) where
+        T: Caller,
+        F: Fn(Args, T) -> X + Send + Sync + 'static,
+        X: Future<Output = jsonrpc::Result<json::Value>> + Send + 'static,
+        R: Fn(json::Value, T, &jsonrpc::Error) + Send + Sync + 'static,
     {
-        self.jsonrpc_handler.add_method_with_meta(name, method);
+        self.method_registry.add(spec);
+        self.jsonrpc_handler.add_method_with_meta(
+            spec.name,
+            move |params: jsonrpc::Params, meta| {
+                let raw_params = params.clone();
+                let call = match spec
+                    .check_permission(&meta)
+                    .and_then(|()| spec.parse_params(params))
+                {
+                    Ok(args) => Ok(method(args, meta)),
+                    Err(err) => {
+                        rejected(params_to_json(raw_params), meta, &err);
+                        Err(err)
+                    }
+                };
+                async move { call?.await }
+            },
+        );
+    }
+
+    /// Registers `rpc.discover`, which describes all methods registered so far.
+    pub fn add_discover_method(&mut self) {
+        self.method_registry.add(&DISCOVER);
+        let document = self.method_registry.openrpc_document();
+        self.jsonrpc_handler
+            .add_method_with_meta(DISCOVER.name, move |params, _meta| {
+                let result = DISCOVER.parse_params(params).map(|_args| document.clone());
+                async move { result }
+            });
+    }
+}
+
+fn params_to_json(params: jsonrpc::Params) -> json::Value {
+    match params {
+        jsonrpc::Params::Array(values) => json::Value::Array(values),
+        jsonrpc::Params::Map(map) => json::Value::Object(map),
+        jsonrpc::Params::None => json::Value::Object(json::Map::new()),
     }
 }
 
 impl<T: jsonrpc::Metadata> Drop for JsonRpcWebsocket<T> {
     fn drop(&mut self) {
+        // Give a pending close message the chance to be sent
         log::debug!("Cancelling JsonRPC receive task ...");
-        self.receive_task.abort();
+        self.send_queue.shut_down();
+        if let Some(receive_task) = self.receive_task.take() {
+            tokio::spawn(async move {
+                sleep(CLOSE_TIMEOUT).await;
+                receive_task.abort();
+            });
+        }
     }
 }
@@ -0,0 +1,337 @@
+use crate::auth::Role;
+use jsonrpc_core::{Error, ErrorCode, Params, Result};
+use serde_json as json;
+
+const OPENRPC_VERSION: &str = "1.2.6";
+const PERMISSION_DENIED: i64 = -32001;
+
+/// The JSON type of a parameter, a result or an object field.
+#[derive(Debug)]
+pub enum ValueType {
+    Any,
+    Null,
+    UnsignedInteger,
+    Number,
+    String,
+    Nullable(&'static ValueType),
+    Array(&'static ValueType),
+    Object(&'static [FieldSpec]),
+}
+
+#[derive(Debug)]
+pub struct FieldSpec {
+    pub name: &'static str,
+    pub value_type: ValueType,
+}
+
+#[derive(Debug)]
+pub struct ParamSpec {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub value_type: ValueType,
+    pub required: bool,
+}
+
+/// Describes a JSON-RPC method: its name, its parameters and its result.
+///
+/// Incoming parameters get validated against this description before
+/// the method gets called, and `rpc.discover` serves it to clients.
+#[derive(Debug)]
+pub struct MethodSpec {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub params: &'static [ParamSpec],
+    pub result: ValueType,
+    /// The minimum role a client needs to call this method
+    pub role: Role,
+}
+
+/// Implemented by the metadata of JSON-RPC calls, to check permissions
+pub trait Caller {
+    fn role(&self) -> Role;
+}
+
+impl ValueType {
+    /// The JSON schema of this type
+    pub fn schema(&self) -> json::Value {
+        match self {
+            ValueType::Any => json::json!({}),
+            ValueType::Null => json::json!({"type": "null"}),
+            ValueType::UnsignedInteger => json::json!({"type": "integer", "minimum": 0}),
+            ValueType::Number => json::json!({"type": "number"}),
+            ValueType::String => json::json!({"type": "string"}),
+            ValueType::Nullable(inner) => {
+                json::json!({"anyOf": [inner.schema(), {"type": "null"}]})
+            }
+            ValueType::Array(items) => json::json!({"type": "array", "items": items.schema()}),
+            ValueType::Object(fields) => {
+                let properties = fields
+                    .iter()
+                    .map(|field| (field.name.to_string(), field.value_type.schema()))
+                    .collect::<json::Map<_, _>>();
+                let required = fields.iter().map(|field| field.name).collect::<Vec<_>>();
+                json::json!({"type": "object", "properties": properties, "required": required})
+            }
+        }
+    }
+
+    /// A human readable name, used in error messages
+    fn description(&self) -> &'static str {
+        match self {
+            ValueType::Any => "any value",
+            ValueType::Null => "null",
+            ValueType::UnsignedInteger => "a non-negative integer",
+            ValueType::Number => "a number",
+            ValueType::String => "a string",
+            ValueType::Nullable(inner) => inner.description(),
+            ValueType::Array(_) => "an array",
+            ValueType::Object(_) => "an object",
+        }
+    }
+
+    fn matches(&self, value: &json::Value) -> bool {
+        match self {
+            ValueType::Any => true,
+            ValueType::Null => value.is_null(),
+            ValueType::UnsignedInteger => value.is_u64(),
+            ValueType::Number => value.is_number(),
+            ValueType::String => value.is_string(),
+            ValueType::Nullable(inner) => value.is_null() || inner.matches(value),
+            ValueType::Array(items) => match value {
+                json::Value::Array(arr) => arr.iter().all(|item| items.matches(item)),
+                _ => false,
+            },
+            ValueType::Object(fields) => match value {
+                json::Value::Object(map) => fields.iter().all(|field| {
+                    map.get(field.name)
+                        .is_some_and(|item| field.value_type.matches(item))
+                }),
+                _ => false,
+            },
+        }
+    }
+}
+
+fn invalid_param(param: &ParamSpec, message: String) -> Error {
+    Error {
+        code: ErrorCode::InvalidParams,
+        message,
+        data: Some(json::json!({
+            "param": param.name,
+            "schema": param.value_type.schema(),
+        })),
+    }
+}
+
+/// The validated arguments of a method call
+pub struct Args {
+    method: &'static MethodSpec,
+    values: Vec<Option<json::Value>>,
+}
+
+impl Args {
+    fn position(&self, name: &str) -> usize {
+        self.method
+            .params
+            .iter()
+            .position(|param| param.name == name)
+            .unwrap_or_else(|| panic!("'{}' has no parameter '{}'", self.method.name, name))
+    }
+
+    /// Returns an optional argument, or `None` if the client didn't provide it.
+    ///
+    /// Panics if `name` is not a parameter of the method, as that is a
+    /// programming error and not a client error.
+    pub fn get_optional<T>(&self, name: &str) -> Result<Option<T>>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        let pos = self.position(name);
+        match &self.values[pos] {
+            Some(value) => json::value::from_value(value.clone())
+                .map(Some)
+                .map_err(|err| invalid_param(&self.method.params[pos], err.to_string())),
+            None => Ok(None),
+        }
+    }
+
+    /// All provided arguments as JSON object, for logging
+    pub fn to_json(&self) -> json::Value {
+        self.method
+            .params
+            .iter()
+            .zip(self.values.iter())
+            .filter_map(|(param, value)| Some((param.name.to_string(), value.clone()?)))
+            .collect::<json::Map<_, _>>()
+            .into()
+    }
+
+    /// Returns a required argument. Its existence was already checked during validation.
+    pub fn get<T>(&self, name: &str) -> Result<T>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        self.get_optional(name)?.ok_or_else(|| {
+            invalid_param(
+                &self.method.params[self.position(name)],
+                format!("Missing parameter: '{}'", name),
+            )
+        })
+    }
+}
+
+impl MethodSpec {
+    /// Checks whether the caller is allowed to call this method
+    pub fn check_permission(&self, caller: &impl Caller) -> Result<()> {
+        if caller.role() >= self.role {
+            Ok(())
+        } else {
+            Err(Error {
+                code: ErrorCode::ServerError(PERMISSION_DENIED),
+                message: format!(
+                    "Permission denied: '{}' requires the role '{}'",
+                    self.name,
+                    self.role.as_str()
+                ),
+                data: None,
+            })
+        }
+    }
+
+    /// Checks the parameters of a call against this description
+    pub fn parse_params(&'static self, params: Params) -> Result<Args> {
+        let mut values = match params {
+            Params::None => vec![],
+            Params::Array(arr) => {
+                if arr.len() > self.params.len() {
+                    return Err(Error::invalid_params(format!(
+                        "Too many parameters: '{}' takes at most {}, got {}",
+                        self.name,
+                        self.params.len(),
+                        arr.len()
+                    )));
+                }
+                arr.into_iter().map(Some).collect()
+            }
+            Params::Map(mut map) => {
+                let values = self
+                    .params
+                    .iter()
+                    .map(|param| map.remove(param.name))
+                    .collect();
+                if let Some(unknown) = map.keys().next() {
+                    return Err(Error::invalid_params(format!(
+                        "Unknown parameter: '{}'",
+                        unknown
+                    )));
+                }
+                values
+            }
+        };
+        values.resize(self.params.len(), None);
+
+        for (param, value) in self.params.iter().zip(values.iter_mut()) {
+            // Treat explicit nulls like omitted values
+            if let Some(json::Value::Null) = value {
+                *value = None;
+            }
+            match value {
+                None if param.required => {
+                    return Err(invalid_param(
+                        param,
+                        format!("Missing parameter: '{}'", param.name),
+                    ));
+                }
+                Some(value) if !param.value_type.matches(value) => {
+                    return Err(invalid_param(
+                        param,
+                        format!(
+                            "Invalid parameter '{}': expected {}, got {}",
+                            param.name,
+                            param.value_type.description(),
+                            value
+                        ),
+                    ));
+                }
+                _ => (),
+            }
+        }
+
+        Ok(Args {
+            method: self,
+            values,
+        })
+    }
+
+    /// The OpenRPC method object of this method
+    fn openrpc_description(&self) -> json::Value {
+        let params = self
+            .params
+            .iter()
+            .map(|param| {
+                json::json!({
+                    "name": param.name,
+                    "description": param.description,
+                    "required": param.required,
+                    "schema": param.value_type.schema(),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        json::json!({
+            "name": self.name,
+            "description": self.description,
+            "paramStructure": "either",
+            "x-role": self.role.as_str(),
+            "params": params,
+            "result": {
+                "name": "result",
+                "schema": self.result.schema(),
+            },
+        })
+    }
+}
+
+pub const DISCOVER: MethodSpec = MethodSpec {
+    name: "rpc.discover",
+    description: "Returns the OpenRPC document describing all methods of this server.",
+    params: &[],
+    result: ValueType::Object(&[]),
+    role: Role::Viewer,
+};
+
+/// Keeps track of all methods offered to a client
+#[derive(Clone, Default)]
+pub struct MethodRegistry {
+    methods: Vec<&'static MethodSpec>,
+}
+
+impl MethodRegistry {
+    pub fn add(&mut self, method: &'static MethodSpec) {
+        assert!(
+            self.methods.iter().all(|known| known.name != method.name),
+            "Method '{}' registered twice",
+            method.name
+        );
+        self.methods.push(method);
+    }
+
+    pub fn method_names(&self) -> Vec<&'static str> {
+        self.methods.iter().map(|method| method.name).collect()
+    }
+
+    pub fn openrpc_document(&self) -> json::Value {
+        json::json!({
+            "openrpc": OPENRPC_VERSION,
+            "info": {
+                "title": env!("CARGO_PKG_NAME"),
+                "version": env!("CARGO_PKG_VERSION"),
+            },
+            "methods": self
+                .methods
+                .iter()
+                .map(|method| method.openrpc_description())
+                .collect::<Vec<_>>(),
+        })
+    }
+}
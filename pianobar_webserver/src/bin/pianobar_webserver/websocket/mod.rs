@@ -1,7 +1,21 @@
+mod audit_log;
+mod client_context;
 mod connection;
+mod control_policy;
 mod json_rpc;
+mod method_registry;
 mod pianobar_actions;
+mod protocol;
+mod resume;
+mod send_queue;
 mod server;
+mod stats;
+mod subscriptions;
 
-use super::pianobar_controller::plugins::player_state::PianobarPlayerState;
+use super::pianobar_controller::plugins::player_state::{
+    PianobarPlayerState, PianobarPlayerStateWatcher,
+};
+pub use audit_log::AuditLog;
+pub use control_policy::{ControlMode, ControlPolicy, ControlSettings};
+pub use json_rpc::KeepaliveSettings;
 pub use server::PianobarWebsocket;
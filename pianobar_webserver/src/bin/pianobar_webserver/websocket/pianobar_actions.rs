@@ -1,14 +1,12 @@
+use super::audit_log::add_audited_method;
+use super::client_context::ClientContext;
+use super::control_policy::{Decision, VoteAction};
 use super::json_rpc::JsonRpcWebsocket;
-use crate::PianobarActions;
-use jsonrpc_core::{Error, ErrorCode, Params, Result};
+use super::method_registry::{Args, FieldSpec, MethodSpec, ParamSpec, ValueType};
+use crate::auth::Role;
+use jsonrpc_core::{Error, ErrorCode, Result};
 use serde_json as json;
 
-macro_rules! bail {
-    ($err:expr $(,)?) => {
-        return Err($err);
-    };
-}
-
 // Implement .to_json conversion function for internal errors
 trait ResultToJson {
     fn to_json(self) -> Result<json::Value>;
@@ -27,93 +25,254 @@ impl<T: serde::Serialize> ResultToJson for std::result::Result<T, anyhow::Error>
     }
 }
 
-struct ArgsExtractor {
-    params: Params,
-}
+const SONG: ValueType = ValueType::Object(&[
+    FieldSpec {
+        name: "artist",
+        value_type: ValueType::String,
+    },
+    FieldSpec {
+        name: "title",
+        value_type: ValueType::String,
+    },
+]);
 
-impl ArgsExtractor {
-    pub fn new(params: Params, max_params: usize) -> Result<ArgsExtractor> {
-        if match &params {
-            Params::Array(arr) => (arr.len() > max_params),
-            Params::Map(map) => (map.len() > max_params),
-            Params::None => false,
-        } {
-            bail!(Error::invalid_params("Too many arguments"));
-        }
-
-        Ok(ArgsExtractor { params })
-    }
+const UPCOMING_SONG: ValueType = ValueType::Object(&[
+    FieldSpec {
+        name: "artist",
+        value_type: ValueType::String,
+    },
+    FieldSpec {
+        name: "title",
+        value_type: ValueType::String,
+    },
+    FieldSpec {
+        name: "album",
+        value_type: ValueType::String,
+    },
+    FieldSpec {
+        name: "rating",
+        value_type: ValueType::UnsignedInteger,
+    },
+]);
 
-    fn get<T>(&self, pos: usize, name: &str) -> Result<T>
-    where
-        T: serde::de::DeserializeOwned,
-    {
-        let value = match &self.params {
-            Params::Array(arr) => arr
-                .get(pos)
-                .ok_or(Error::invalid_params("Not enough arguments")),
-            Params::Map(map) => map.get(name).ok_or(Error::invalid_params(format!(
-                "Missing argument: '{}'",
-                name
-            ))),
-            Params::None => Err(Error::new(ErrorCode::InvalidParams)),
-        }?;
-        json::value::from_value(value.clone())
-            .or_else(|err| Err(Error::invalid_params(err.to_string())))
-    }
-}
+const EXPLANATION: ValueType = ValueType::Object(&[
+    FieldSpec {
+        name: "traits",
+        value_type: ValueType::Array(&ValueType::String),
+    },
+    FieldSpec {
+        name: "reasons",
+        value_type: ValueType::Array(&ValueType::String),
+    },
+    FieldSpec {
+        name: "raw",
+        value_type: ValueType::String,
+    },
+]);
 
-pub fn register(handler: &mut JsonRpcWebsocket<PianobarActions>) {
-    handler.add_method("change_station", change_station);
-    handler.add_method("pause", pause);
-    handler.add_method("toggle_pause", toggle_pause);
-    handler.add_method("skip", skip);
-    handler.add_method("resume", resume);
-    handler.add_method("explain", explain);
-    handler.add_method("history", history);
-}
+const PENDING_VOTE: ValueType = ValueType::Object(&[
+    FieldSpec {
+        name: "action",
+        value_type: ValueType::String,
+    },
+    FieldSpec {
+        name: "station_id",
+        value_type: ValueType::Nullable(&ValueType::UnsignedInteger),
+    },
+    FieldSpec {
+        name: "votes",
+        value_type: ValueType::UnsignedInteger,
+    },
+    FieldSpec {
+        name: "required_votes",
+        value_type: ValueType::UnsignedInteger,
+    },
+    FieldSpec {
+        name: "expires_in",
+        value_type: ValueType::Number,
+    },
+]);
 
-async fn change_station(params: Params, actions: PianobarActions) -> Result<json::Value> {
-    let _args = ArgsExtractor::new(params, 1)?;
+const CHANGE_STATION: MethodSpec = MethodSpec {
+    name: "change_station",
+    description: "Switches to another station. \
+                  In vote mode this casts a vote and returns it, unless the vote passes.",
+    params: &[ParamSpec {
+        name: "station_id",
+        description: "The index of the station in the station list",
+        value_type: ValueType::UnsignedInteger,
+        required: true,
+    }],
+    result: ValueType::Nullable(&PENDING_VOTE),
+    role: Role::Listener,
+};
 
-    actions
-        .change_station(_args.get(0, "station_id")?)
-        .await
-        .to_json()
-}
+const PAUSE: MethodSpec = MethodSpec {
+    name: "pause",
+    description: "Pauses playback.",
+    params: &[],
+    result: ValueType::Null,
+    role: Role::Listener,
+};
+
+const TOGGLE_PAUSE: MethodSpec = MethodSpec {
+    name: "toggle_pause",
+    description: "Pauses playback if playing, resumes it if paused.",
+    params: &[],
+    result: ValueType::Null,
+    role: Role::Listener,
+};
+
+const SKIP: MethodSpec = MethodSpec {
+    name: "skip",
+    description: "Skips the current song. \
+                  In vote mode this casts a vote and returns it, unless the vote passes.",
+    params: &[],
+    result: ValueType::Nullable(&PENDING_VOTE),
+    role: Role::Listener,
+};
+
+const BAN: MethodSpec = MethodSpec {
+    name: "ban",
+    description: "Bans the current song, so it won't be played again. \
+                  In vote mode this casts a vote and returns it, unless the vote passes.",
+    params: &[],
+    result: ValueType::Nullable(&PENDING_VOTE),
+    role: Role::Listener,
+};
+
+const CANCEL_VOTE: MethodSpec = MethodSpec {
+    name: "cancel_vote",
+    description: "Discards a pending vote.",
+    params: &[
+        ParamSpec {
+            name: "action",
+            description: "The action of the vote: 'skip', 'ban' or 'change_station'",
+            value_type: ValueType::String,
+            required: true,
+        },
+        ParamSpec {
+            name: "station_id",
+            description: "The station of a 'change_station' vote",
+            value_type: ValueType::UnsignedInteger,
+            required: false,
+        },
+    ],
+    result: ValueType::Null,
+    role: Role::Admin,
+};
+
+const RESUME: MethodSpec = MethodSpec {
+    name: "resume",
+    description: "Resumes playback.",
+    params: &[],
+    result: ValueType::Null,
+    role: Role::Listener,
+};
+
+const EXPLAIN: MethodSpec = MethodSpec {
+    name: "explain",
+    description: "Explains why the current song is playing. \
+                  The explanation also gets added to the ui state, as 'explanation'.",
+    params: &[],
+    result: EXPLANATION,
+    role: Role::Viewer,
+};
 
-pub async fn pause(params: Params, actions: PianobarActions) -> Result<json::Value> {
-    let _args = ArgsExtractor::new(params, 0)?;
+const HISTORY: MethodSpec = MethodSpec {
+    name: "history",
+    description: "Lists the recently played songs.",
+    params: &[],
+    result: ValueType::Array(&SONG),
+    role: Role::Viewer,
+};
 
-    actions.pause().await.to_json()
+const UPCOMING: MethodSpec = MethodSpec {
+    name: "upcoming",
+    description: "Lists the songs that play next. The list also gets added to the ui state, \
+                  as 'upcoming', and is refreshed whenever a song starts.",
+    params: &[],
+    result: ValueType::Array(&UPCOMING_SONG),
+    role: Role::Viewer,
+};
+
+pub fn register(handler: &mut JsonRpcWebsocket<ClientContext>) {
+    add_audited_method(handler, &CHANGE_STATION, change_station);
+    add_audited_method(handler, &PAUSE, pause);
+    add_audited_method(handler, &TOGGLE_PAUSE, toggle_pause);
+    add_audited_method(handler, &SKIP, skip);
+    add_audited_method(handler, &BAN, ban);
+    add_audited_method(handler, &CANCEL_VOTE, cancel_vote);
+    add_audited_method(handler, &RESUME, resume);
+    add_audited_method(handler, &EXPLAIN, explain);
+    add_audited_method(handler, &HISTORY, history);
+    add_audited_method(handler, &UPCOMING, upcoming);
 }
 
-pub async fn resume(params: Params, actions: PianobarActions) -> Result<json::Value> {
-    let _args = ArgsExtractor::new(params, 0)?;
+/// Asks the control policy whether to execute the action.
+/// Returns the pending vote if the action has to wait for more votes.
+fn pending_vote(context: &ClientContext, action: VoteAction) -> Option<json::Value> {
+    match context.control.request(action) {
+        Decision::Execute => None,
+        Decision::Pending(vote) => Some(json::json!(vote)),
+    }
+}
 
-    actions.resume().await.to_json()
+async fn change_station(args: Args, context: ClientContext) -> Result<json::Value> {
+    let station_id = args.get("station_id")?;
+    if let Some(vote) = pending_vote(&context, VoteAction::ChangeStation(station_id)) {
+        return Ok(vote);
+    }
+    context.actions.change_station(station_id).await.to_json()
 }
 
-pub async fn toggle_pause(params: Params, actions: PianobarActions) -> Result<json::Value> {
-    let _args = ArgsExtractor::new(params, 0)?;
+pub async fn pause(_args: Args, context: ClientContext) -> Result<json::Value> {
+    context.actions.pause().await.to_json()
+}
 
-    actions.toggle_pause().await.to_json()
+pub async fn resume(_args: Args, context: ClientContext) -> Result<json::Value> {
+    context.actions.resume().await.to_json()
 }
 
-pub async fn skip(params: Params, actions: PianobarActions) -> Result<json::Value> {
-    let _args = ArgsExtractor::new(params, 0)?;
+pub async fn toggle_pause(_args: Args, context: ClientContext) -> Result<json::Value> {
+    context.actions.toggle_pause().await.to_json()
+}
 
-    actions.skip().await.to_json()
+pub async fn skip(_args: Args, context: ClientContext) -> Result<json::Value> {
+    if let Some(vote) = pending_vote(&context, VoteAction::Skip) {
+        return Ok(vote);
+    }
+    context.actions.skip().await.to_json()
 }
 
-pub async fn explain(params: Params, actions: PianobarActions) -> Result<json::Value> {
-    let _args = ArgsExtractor::new(params, 0)?;
+pub async fn ban(_args: Args, context: ClientContext) -> Result<json::Value> {
+    if let Some(vote) = pending_vote(&context, VoteAction::Ban) {
+        return Ok(vote);
+    }
+    context.actions.ban().await.to_json()
+}
+
+async fn cancel_vote(args: Args, context: ClientContext) -> Result<json::Value> {
+    let action = VoteAction::parse(
+        &args.get::<String>("action")?,
+        args.get_optional("station_id")?,
+    )
+    .map_err(|err| Error::invalid_params(err.to_string()))?;
+    if context.control.policy().cancel(action) {
+        Ok(json::Value::Null)
+    } else {
+        Err(Error::invalid_params("There is no such pending vote"))
+    }
+}
 
-    actions.explain().await.to_json()
+pub async fn explain(_args: Args, context: ClientContext) -> Result<json::Value> {
+    context.actions.explain().await.to_json()
 }
 
-pub async fn history(params: Params, actions: PianobarActions) -> Result<json::Value> {
-    let _args = ArgsExtractor::new(params, 0)?;
+pub async fn history(_args: Args, context: ClientContext) -> Result<json::Value> {
+    context.actions.history().await.to_json()
+}
 
-    actions.history().await.to_json()
+pub async fn upcoming(_args: Args, context: ClientContext) -> Result<json::Value> {
+    context.actions.upcoming().await.to_json()
 }
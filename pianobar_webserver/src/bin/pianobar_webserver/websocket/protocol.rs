@@ -0,0 +1,121 @@
+use super::client_context::ClientContext;
+use super::json_rpc::JsonRpcWebsocket;
+use super::method_registry::{Args, FieldSpec, MethodSpec, ParamSpec, ValueType};
+use crate::auth::Role;
+use jsonrpc_core::{Error, ErrorCode, Result};
+use pianobar_webserver::protocol::{
+    Capabilities, ServerInfo, CLOSE_INCOMPATIBLE_VERSION, ERROR_INCOMPATIBLE_VERSION,
+    NOTIFICATIONS, PROTOCOL_VERSION_MAJOR, PROTOCOL_VERSION_MINOR,
+};
+use rand::RngCore;
+use serde_json as json;
+
+/// Identifies a server process, so clients can tell that the server restarted
+pub fn new_instance_id() -> String {
+    let mut id = [0u8; 8];
+    rand::thread_rng().fill_bytes(&mut id);
+    hex::encode(id)
+}
+
+/// Describes this server, in the welcome message and as result of `hello`
+pub fn server_info(instance_id: &str, methods: Vec<&'static str>) -> ServerInfo {
+    ServerInfo {
+        protocol_version: format!("{}.{}", PROTOCOL_VERSION_MAJOR, PROTOCOL_VERSION_MINOR),
+        server_version: env!("CARGO_PKG_VERSION").to_string(),
+        instance_id: instance_id.to_string(),
+        capabilities: Capabilities {
+            methods: methods.into_iter().map(str::to_string).collect(),
+            notifications: NOTIFICATIONS.iter().map(|name| name.to_string()).collect(),
+        },
+    }
+}
+
+/// Parses `<major>` or `<major>.<minor>`, and returns the major version
+fn parse_major_version(version: &str) -> Option<u32> {
+    let mut parts = version.trim().split('.');
+    let major = parts.next()?.parse().ok()?;
+    match (parts.next(), parts.next()) {
+        (None, _) => Some(major),
+        (Some(minor), None) if minor.parse::<u32>().is_ok() => Some(major),
+        _ => None,
+    }
+}
+
+const SERVER_INFO: ValueType = ValueType::Object(&[
+    FieldSpec {
+        name: "protocol_version",
+        value_type: ValueType::String,
+    },
+    FieldSpec {
+        name: "server_version",
+        value_type: ValueType::String,
+    },
+    FieldSpec {
+        name: "instance_id",
+        value_type: ValueType::String,
+    },
+    FieldSpec {
+        name: "capabilities",
+        value_type: ValueType::Object(&[
+            FieldSpec {
+                name: "methods",
+                value_type: ValueType::Array(&ValueType::String),
+            },
+            FieldSpec {
+                name: "notifications",
+                value_type: ValueType::Array(&ValueType::String),
+            },
+        ]),
+    },
+]);
+
+// `formatcp!` expands to a transmute
+#[allow(clippy::transmute_bytes_to_str)]
+const HELLO: MethodSpec = MethodSpec {
+    name: "hello",
+    description: "Negotiates the protocol version. Returns the server info \
+                  if the major versions match, otherwise the connection gets closed \
+                  with code 4001 after the error response.",
+    params: &[ParamSpec {
+        name: "protocol_version",
+        description: const_format::formatcp!(
+            "The protocol version the client speaks, like '{}.{}'",
+            PROTOCOL_VERSION_MAJOR,
+            PROTOCOL_VERSION_MINOR
+        ),
+        value_type: ValueType::String,
+        required: true,
+    }],
+    result: SERVER_INFO,
+    role: Role::Viewer,
+};
+
+pub fn register(handler: &mut JsonRpcWebsocket<ClientContext>) {
+    handler.add_method(&HELLO, hello);
+}
+
+async fn hello(args: Args, context: ClientContext) -> Result<json::Value> {
+    let version = args.get::<String>("protocol_version")?;
+    let major = parse_major_version(&version)
+        .ok_or_else(|| Error::invalid_params(format!("Invalid protocol version: '{}'", version)))?;
+
+    if major != PROTOCOL_VERSION_MAJOR {
+        let message = format!(
+            "Incompatible protocol version {}, the server speaks {}",
+            version, context.server_info.protocol_version
+        );
+        log::warn!("{}: {}", context.address(), message);
+        context
+            .close_request
+            .close(CLOSE_INCOMPATIBLE_VERSION, "incompatible protocol version");
+        return Err(Error {
+            code: ErrorCode::ServerError(ERROR_INCOMPATIBLE_VERSION),
+            message,
+            data: Some(json::json!({
+                "protocol_version": context.server_info.protocol_version,
+            })),
+        });
+    }
+
+    Ok(json::json!(*context.server_info))
+}
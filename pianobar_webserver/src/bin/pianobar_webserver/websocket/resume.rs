@@ -0,0 +1,77 @@
+use super::client_context::ClientContext;
+use super::json_rpc::JsonRpcWebsocket;
+use super::method_registry::{Args, FieldSpec, MethodSpec, ParamSpec, ValueType};
+use crate::auth::Role;
+use jsonrpc_core::{Error, Result};
+use pianobar_webserver::protocol::ResumeOutcome;
+use serde_json as json;
+use tokio::sync::oneshot;
+
+/// Asks the events task of a connection to replay the events after `sequence`.
+/// `None` requests a full snapshot.
+pub struct ResumeRequest {
+    pub sequence: Option<u64>,
+    pub reply: oneshot::Sender<ResumeOutcome>,
+}
+
+const RESUME_EVENTS: MethodSpec = MethodSpec {
+    name: "resume_events",
+    description: "Replays the ui events a reconnecting client missed, with their \
+                  'player_error' and 'history_update' notifications, \
+                  all carrying their original sequence numbers. \
+                  If the events aren't buffered anymore, a full 'websocket_welcome' \
+                  snapshot with 'resync' set is sent instead. \
+                  Returns after all notifications are queued.",
+    params: &[
+        ParamSpec {
+            name: "sequence",
+            description: "The sequence number of the last notification the client received",
+            value_type: ValueType::UnsignedInteger,
+            required: true,
+        },
+        ParamSpec {
+            name: "instance_id",
+            description: "The instance id of the server the sequence number came from. \
+                          If the server restarted since, a snapshot gets sent.",
+            value_type: ValueType::String,
+            required: false,
+        },
+    ],
+    result: ValueType::Object(&[
+        FieldSpec {
+            name: "mode",
+            value_type: ValueType::String,
+        },
+        FieldSpec {
+            name: "events",
+            value_type: ValueType::UnsignedInteger,
+        },
+        FieldSpec {
+            name: "sequence",
+            value_type: ValueType::UnsignedInteger,
+        },
+    ]),
+    role: Role::Viewer,
+};
+
+pub fn register(handler: &mut JsonRpcWebsocket<ClientContext>) {
+    handler.add_method(&RESUME_EVENTS, resume_events);
+}
+
+async fn resume_events(args: Args, context: ClientContext) -> Result<json::Value> {
+    let sequence = args.get::<u64>("sequence")?;
+    let same_instance = args
+        .get_optional::<String>("instance_id")?
+        .is_none_or(|instance_id| instance_id == context.server_info.instance_id);
+
+    let (reply, outcome) = oneshot::channel();
+    context
+        .resume_requests
+        .send(ResumeRequest {
+            sequence: Some(sequence).filter(|_| same_instance),
+            reply,
+        })
+        .map_err(|_| Error::internal_error())?;
+    let outcome = outcome.await.map_err(|_| Error::internal_error())?;
+    Ok(json::json!(outcome))
+}
@@ -0,0 +1,148 @@
+use anyhow::{bail, Result};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Mutex;
+use tokio::sync::Notify;
+use warp::ws::Message;
+
+#[derive(Default)]
+struct QueueState {
+    /// Kept in order, limited by the capacity
+    ordered: VecDeque<Message>,
+    /// Only the latest message of every kind, sent after the ordered ones
+    latest: BTreeMap<String, Message>,
+    /// Sent before everything else; nothing gets queued after it
+    close: Option<Message>,
+    /// A close message is queued behind the ordered messages, later messages get discarded
+    finishing: bool,
+    /// The sending side is gone, so the queue ends once the close message is sent
+    shut_down: bool,
+}
+
+/// The outgoing messages of a websocket connection.
+///
+/// Ordered messages like responses and ui events are limited by the capacity.
+/// If a client doesn't read fast enough to stay below it, the queue gets replaced
+/// by a close message. State messages like `player_state` are coalesced instead,
+/// as only their latest value matters.
+pub struct SendQueue {
+    capacity: usize,
+    state: Mutex<QueueState>,
+    changed: Notify,
+}
+
+impl SendQueue {
+    pub fn new(capacity: usize) -> SendQueue {
+        SendQueue {
+            capacity,
+            state: Mutex::new(QueueState::default()),
+            changed: Notify::new(),
+        }
+    }
+
+    /// Queues a message behind all other ordered messages.
+    ///
+    /// Fails if the queue is full or closed. A full queue gets closed with `overflow_close`.
+    pub fn push(&self, message: Message, overflow_close: impl FnOnce() -> Message) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.close.is_some() || state.shut_down {
+            bail!("send queue closed");
+        }
+        if state.finishing {
+            return Ok(());
+        }
+        if state.ordered.len() >= self.capacity {
+            state.ordered.clear();
+            state.latest.clear();
+            state.close = Some(overflow_close());
+            drop(state);
+            self.changed.notify_one();
+            bail!(
+                "send queue full: more than {} messages pending",
+                self.capacity
+            );
+        }
+        state.ordered.push_back(message);
+        drop(state);
+        self.changed.notify_one();
+        Ok(())
+    }
+
+    /// Queues a message, replacing a queued message of the same kind
+    pub fn replace(&self, kind: &str, message: Message) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.close.is_some() || state.shut_down {
+            bail!("send queue closed");
+        }
+        if state.finishing {
+            return Ok(());
+        }
+        state.latest.insert(kind.to_string(), message);
+        drop(state);
+        self.changed.notify_one();
+        Ok(())
+    }
+
+    /// Discards all pending messages and queues the close message
+    pub fn close(&self, message: Message) {
+        let mut state = self.state.lock().unwrap();
+        state.ordered.clear();
+        state.latest.clear();
+        state.close.get_or_insert(message);
+        drop(state);
+        self.changed.notify_one();
+    }
+
+    /// Queues the close message behind all ordered messages.
+    /// Coalesced messages and everything queued later get discarded.
+    pub fn finish(&self, message: Message) {
+        let mut state = self.state.lock().unwrap();
+        if state.close.is_some() || state.shut_down || state.finishing {
+            return;
+        }
+        state.latest.clear();
+        state.ordered.push_back(message);
+        state.finishing = true;
+        drop(state);
+        self.changed.notify_one();
+    }
+
+    /// Discards all pending messages except the close message,
+    /// and ends the queue once that is sent.
+    pub fn shut_down(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.ordered.clear();
+        state.latest.clear();
+        state.shut_down = true;
+        drop(state);
+        self.changed.notify_one();
+    }
+
+    fn try_pop(&self) -> Option<Option<Message>> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(close) = state.close.take() {
+            // Nothing follows the close message
+            state.shut_down = true;
+            return Some(Some(close));
+        }
+        if let Some(message) = state.ordered.pop_front() {
+            return Some(Some(message));
+        }
+        if let Some(kind) = state.latest.keys().next().cloned() {
+            return Some(state.latest.remove(&kind));
+        }
+        if state.shut_down {
+            return Some(None);
+        }
+        None
+    }
+
+    /// Waits for the next message to send. Returns `None` once the queue ended.
+    pub async fn pop(&self) -> Option<Message> {
+        loop {
+            if let Some(message) = self.try_pop() {
+                return message;
+            }
+            self.changed.notified().await;
+        }
+    }
+}
@@ -1,55 +1,88 @@
+use crate::auth::Identity;
 use crate::event_receiver::{PianobarUiEventSource, PianobarUiEventSourceCreator};
 use crate::PianobarActions;
 
-use super::connection::PianobarWebsocketConnection;
-use super::PianobarPlayerState;
+use super::audit_log::AuditLog;
+use super::connection::{PianobarWebsocketConnection, SharedServices};
+use super::control_policy::ControlPolicy;
+use super::json_rpc::KeepaliveSettings;
+use super::protocol;
+use super::stats::LagCounters;
+use super::{PianobarPlayerState, PianobarPlayerStateWatcher};
 
-use std::net::SocketAddr;
 use tokio::sync::watch;
 use warp::{Filter, Rejection, Reply};
 
 pub struct PianobarWebsocket {
     pianobar_ui_event_source_creator: PianobarUiEventSourceCreator,
     pianobar_player_state: watch::Receiver<PianobarPlayerState>,
-    pianobar_actions: PianobarActions,
+    services: SharedServices,
 }
 
 impl PianobarWebsocket {
     pub fn new(
         pianobar_ui_event_source_creator: PianobarUiEventSourceCreator,
-        pianobar_player_state: watch::Receiver<PianobarPlayerState>,
+        pianobar_player_state: &PianobarPlayerStateWatcher,
         pianobar_actions: PianobarActions,
+        control_policy: ControlPolicy,
+        audit_log: AuditLog,
+        keepalive: KeepaliveSettings,
+        send_queue_capacity: usize,
     ) -> PianobarWebsocket {
         PianobarWebsocket {
             pianobar_ui_event_source_creator,
-            pianobar_player_state,
-            pianobar_actions,
+            pianobar_player_state: pianobar_player_state.subscribe(),
+            services: SharedServices {
+                pianobar_actions,
+                pianobar_errors: pianobar_player_state.errors(),
+                control_policy,
+                audit_log,
+                keepalive,
+                send_queue_capacity,
+                lag_counters: LagCounters::default(),
+                instance_id: protocol::new_instance_id(),
+            },
         }
     }
 
     async fn connection_upgrader(
+        identity: Identity,
         ws: warp::ws::Ws,
-        addr: Option<SocketAddr>,
+        addr: Option<String>,
         ui_events: PianobarUiEventSource,
         player_state: watch::Receiver<PianobarPlayerState>,
-        pianobar_actions: PianobarActions,
+        services: SharedServices,
     ) -> std::result::Result<impl Reply, Rejection> {
         Ok(ws.on_upgrade(move |socket| {
-            let client = PianobarWebsocketConnection::new(addr, socket);
-            client.run(ui_events, player_state, pianobar_actions)
+            let client = PianobarWebsocketConnection::new(addr, identity, socket, &services);
+            client.run(ui_events, player_state, services)
         }))
     }
 
     pub fn create_route(
         &self,
         path: &'static str,
+        with_client_address: impl Filter<Extract = (Option<String>,), Error = Rejection>
+            + Clone
+            + Send
+            + Sync
+            + 'static,
+        with_identity: impl Filter<Extract = (Identity,), Error = Rejection>
+            + Clone
+            + Send
+            + Sync
+            + 'static,
     ) -> impl warp::Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+        // Authenticate before checking for the upgrade, so the web ui can find out
+        // with a plain request whether it has to log in. Browsers hide the status
+        // of failed websocket handshakes.
         warp::path(path)
+            .and(with_identity)
             .and(warp::ws())
-            .and(warp::addr::remote())
+            .and(with_client_address)
             .and(self.with_ui_events())
             .and(self.with_player_state())
-            .and(self.with_pianobar_actions())
+            .and(self.with_services())
             .and_then(PianobarWebsocket::connection_upgrader)
     }
 
@@ -71,10 +104,10 @@ impl PianobarWebsocket {
         warp::any().map(move || pianobar_player_state.clone())
     }
 
-    fn with_pianobar_actions(
+    fn with_services(
         &self,
-    ) -> impl Filter<Extract = (PianobarActions,), Error = std::convert::Infallible> + Clone {
-        let source_creator = self.pianobar_actions.clone();
-        warp::any().map(move || source_creator.clone())
+    ) -> impl Filter<Extract = (SharedServices,), Error = std::convert::Infallible> + Clone {
+        let services = self.services.clone();
+        warp::any().map(move || services.clone())
     }
 }
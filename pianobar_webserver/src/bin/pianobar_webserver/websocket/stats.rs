@@ -0,0 +1,85 @@
+use super::client_context::ClientContext;
+use super::json_rpc::JsonRpcWebsocket;
+use super::method_registry::{Args, FieldSpec, MethodSpec, ValueType};
+use crate::auth::Role;
+use jsonrpc_core::Result;
+use pianobar_webserver::protocol::Stats;
+use serde_json as json;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+#[derive(Default)]
+struct Counters {
+    ui_event_lags: AtomicU64,
+    ui_events_missed: AtomicU64,
+    activity_lags: AtomicU64,
+    activity_missed: AtomicU64,
+}
+
+/// Counts how often clients fell behind a broadcast channel, across all connections
+#[derive(Clone, Default)]
+pub struct LagCounters {
+    counters: Arc<Counters>,
+}
+
+impl LagCounters {
+    /// A client missed `missed` ui events and got resynchronized
+    pub fn record_ui_event_lag(&self, missed: u64) {
+        self.counters.ui_event_lags.fetch_add(1, Ordering::Relaxed);
+        self.counters
+            .ui_events_missed
+            .fetch_add(missed, Ordering::Relaxed);
+    }
+
+    /// A client missed `missed` activity notifications
+    pub fn record_activity_lag(&self, missed: u64) {
+        self.counters.activity_lags.fetch_add(1, Ordering::Relaxed);
+        self.counters
+            .activity_missed
+            .fetch_add(missed, Ordering::Relaxed);
+    }
+
+    fn stats(&self) -> Stats {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        Stats {
+            ui_event_lags: load(&self.counters.ui_event_lags),
+            ui_events_missed: load(&self.counters.ui_events_missed),
+            activity_lags: load(&self.counters.activity_lags),
+            activity_missed: load(&self.counters.activity_missed),
+        }
+    }
+}
+
+const STATS: MethodSpec = MethodSpec {
+    name: "stats",
+    description:
+        "Returns how often clients fell behind the event broadcasts since the server started.",
+    params: &[],
+    result: ValueType::Object(&[
+        FieldSpec {
+            name: "ui_event_lags",
+            value_type: ValueType::UnsignedInteger,
+        },
+        FieldSpec {
+            name: "ui_events_missed",
+            value_type: ValueType::UnsignedInteger,
+        },
+        FieldSpec {
+            name: "activity_lags",
+            value_type: ValueType::UnsignedInteger,
+        },
+        FieldSpec {
+            name: "activity_missed",
+            value_type: ValueType::UnsignedInteger,
+        },
+    ]),
+    role: Role::Viewer,
+};
+
+pub fn register(handler: &mut JsonRpcWebsocket<ClientContext>) {
+    handler.add_method(&STATS, stats);
+}
+
+async fn stats(_args: Args, context: ClientContext) -> Result<json::Value> {
+    Ok(json::json!(context.lag_counters.stats()))
+}
@@ -0,0 +1,266 @@
+use super::client_context::ClientContext;
+use super::json_rpc::JsonRpcWebsocket;
+use super::method_registry::{Args, FieldSpec, MethodSpec, ParamSpec, ValueType};
+use crate::auth::Role;
+use jsonrpc_core::{Error, Result};
+use pianobar_webserver::protocol::{SubscriptionEntry, TOPICS, TOPIC_UI_EVENT};
+use serde_json as json;
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+/// Longer throttle intervals are rejected
+const MAX_THROTTLE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The topic of a ui event of a specific kind, like `ui_event.songstart`
+pub fn ui_event_topic(command: &str) -> String {
+    format!("{}.{}", TOPIC_UI_EVENT, command)
+}
+
+fn validate_topic(topic: &str) -> Result<()> {
+    let is_valid = TOPICS.contains(&topic)
+        || topic
+            .strip_prefix(TOPIC_UI_EVENT)
+            .and_then(|kind| kind.strip_prefix('.'))
+            .is_some_and(|kind| !kind.is_empty());
+
+    if is_valid {
+        Ok(())
+    } else {
+        Err(Error::invalid_params(format!("Unknown topic: '{}'", topic)))
+    }
+}
+
+/// The notification topics a client is interested in.
+///
+/// Every topic can have a throttle interval. Notifications of a throttled
+/// topic are sent at most once per interval; if more arrive in between,
+/// only the latest one gets delivered.
+#[derive(Clone, Debug)]
+pub struct Subscriptions {
+    topics: BTreeMap<String, Option<Duration>>,
+}
+
+impl Default for Subscriptions {
+    /// Clients are subscribed to everything until they say otherwise
+    fn default() -> Self {
+        Subscriptions {
+            topics: TOPICS
+                .iter()
+                .map(|topic| (topic.to_string(), None))
+                .collect(),
+        }
+    }
+}
+
+impl Subscriptions {
+    /// Returns `None` if the client isn't subscribed to the given topic,
+    /// otherwise its throttle interval.
+    ///
+    /// `ui_event.<kind>` topics fall back to the `ui_event` subscription.
+    pub fn get(&self, topic: &str) -> Option<Option<Duration>> {
+        if let Some(throttle) = self.topics.get(topic) {
+            return Some(*throttle);
+        }
+        if topic.starts_with(TOPIC_UI_EVENT) {
+            return self.topics.get(TOPIC_UI_EVENT).copied();
+        }
+        None
+    }
+
+    fn subscribe(&mut self, topic: String, throttle: Option<Duration>) {
+        self.topics.insert(topic, throttle);
+    }
+
+    /// Unsubscribing from a kind of ui event can't work while subscribed to all of them
+    fn validate_unsubscribe(&self, topics: &[String]) -> Result<()> {
+        if !self.topics.contains_key(TOPIC_UI_EVENT) || topics.iter().any(|t| t == TOPIC_UI_EVENT) {
+            return Ok(());
+        }
+        match topics
+            .iter()
+            .find(|topic| topic.starts_with(&ui_event_topic("")))
+        {
+            Some(topic) => Err(Error::invalid_params(format!(
+                "Can't unsubscribe from '{}' while subscribed to '{}'",
+                topic, TOPIC_UI_EVENT
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    fn unsubscribe(&mut self, topic: &str) {
+        self.topics.remove(topic);
+        if topic == TOPIC_UI_EVENT {
+            self.topics
+                .retain(|key, _| !key.starts_with(&ui_event_topic("")));
+        }
+    }
+
+    fn describe(&self) -> Vec<SubscriptionEntry> {
+        self.topics
+            .iter()
+            .map(|(topic, throttle)| SubscriptionEntry {
+                topic: topic.clone(),
+                throttle: throttle.map(|throttle| throttle.as_secs_f64()),
+            })
+            .collect()
+    }
+}
+
+/// Delays and coalesces notifications of throttled topics
+pub struct Throttler<T> {
+    last_sent: HashMap<String, Instant>,
+    pending: HashMap<String, (Instant, T)>,
+}
+
+impl<T> Throttler<T> {
+    pub fn new() -> Self {
+        Throttler {
+            last_sent: HashMap::new(),
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Returns the item if it can be sent right away.
+    /// Otherwise it replaces the pending item of the topic.
+    pub fn submit(&mut self, topic: String, throttle: Option<Duration>, item: T) -> Option<T> {
+        let throttle = match throttle {
+            Some(throttle) => throttle,
+            None => return Some(item),
+        };
+
+        let now = Instant::now();
+        match self.last_sent.get(&topic) {
+            Some(last_sent) if *last_sent + throttle > now => {
+                self.pending.insert(topic, (*last_sent + throttle, item));
+                None
+            }
+            _ => {
+                self.last_sent.insert(topic, now);
+                Some(item)
+            }
+        }
+    }
+
+    /// The time at which the next pending item is due
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pending.values().map(|(deadline, _)| *deadline).min()
+    }
+
+    /// Removes and returns all pending items that are due
+    pub fn take_due(&mut self) -> Vec<T> {
+        let now = Instant::now();
+        let due_topics = self
+            .pending
+            .iter()
+            .filter(|(_, (deadline, _))| *deadline <= now)
+            .map(|(topic, _)| topic.clone())
+            .collect::<Vec<_>>();
+
+        due_topics
+            .into_iter()
+            .filter_map(|topic| {
+                let (_, item) = self.pending.remove(&topic)?;
+                self.last_sent.insert(topic, now);
+                Some(item)
+            })
+            .collect()
+    }
+}
+
+const SUBSCRIPTION: ValueType = ValueType::Object(&[
+    FieldSpec {
+        name: "topic",
+        value_type: ValueType::String,
+    },
+    FieldSpec {
+        name: "throttle",
+        value_type: ValueType::Nullable(&ValueType::Number),
+    },
+]);
+
+const SUBSCRIBE: MethodSpec = MethodSpec {
+    name: "subscribe",
+    description: "Subscribes to notification topics: 'player_state', 'ui_event', \
+                  'ui_event.<kind>', 'errors', 'history', 'votes' and 'activity'. \
+                  The throttle applies to all of them. Returns all active subscriptions.",
+    params: &[
+        ParamSpec {
+            name: "topics",
+            description: "The topics to subscribe to",
+            value_type: ValueType::Array(&ValueType::String),
+            required: true,
+        },
+        ParamSpec {
+            name: "throttle",
+            description: "The minimum number of seconds between two notifications of a topic, \
+                          at most a day",
+            value_type: ValueType::Number,
+            required: false,
+        },
+    ],
+    result: ValueType::Array(&SUBSCRIPTION),
+    role: Role::Viewer,
+};
+
+const UNSUBSCRIBE: MethodSpec = MethodSpec {
+    name: "unsubscribe",
+    description: "Unsubscribes from notification topics. \
+                  Unsubscribing from 'ui_event' also removes all 'ui_event.<kind>' topics. \
+                  While subscribed to 'ui_event', its kinds can't be unsubscribed one by one. \
+                  Returns all remaining subscriptions.",
+    params: &[ParamSpec {
+        name: "topics",
+        description: "The topics to unsubscribe from",
+        value_type: ValueType::Array(&ValueType::String),
+        required: true,
+    }],
+    result: ValueType::Array(&SUBSCRIPTION),
+    role: Role::Viewer,
+};
+
+pub fn register(handler: &mut JsonRpcWebsocket<ClientContext>) {
+    handler.add_method(&SUBSCRIBE, subscribe);
+    handler.add_method(&UNSUBSCRIBE, unsubscribe);
+}
+
+async fn subscribe(args: Args, context: ClientContext) -> Result<json::Value> {
+    let topics = args.get::<Vec<String>>("topics")?;
+    let throttle = match args.get_optional::<f64>("throttle")? {
+        Some(seconds) => match Duration::try_from_secs_f64(seconds) {
+            Ok(throttle) if throttle <= MAX_THROTTLE => Some(throttle),
+            _ => {
+                return Err(Error::invalid_params(format!(
+                    "The throttle interval has to be between 0 and {} seconds",
+                    MAX_THROTTLE.as_secs()
+                )))
+            }
+        },
+        None => None,
+    };
+    for topic in &topics {
+        validate_topic(topic)?;
+    }
+
+    context.update_subscriptions(|subscriptions| {
+        for topic in topics {
+            subscriptions.subscribe(topic, throttle);
+        }
+        Ok(json::json!(subscriptions.describe()))
+    })
+}
+
+async fn unsubscribe(args: Args, context: ClientContext) -> Result<json::Value> {
+    let topics = args.get::<Vec<String>>("topics")?;
+    for topic in &topics {
+        validate_topic(topic)?;
+    }
+
+    context.update_subscriptions(|subscriptions| {
+        subscriptions.validate_unsubscribe(&topics)?;
+        for topic in topics {
+            subscriptions.unsubscribe(&topic);
+        }
+        Ok(json::json!(subscriptions.describe()))
+    })
+}
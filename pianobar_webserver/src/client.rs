@@ -0,0 +1,739 @@
+//! A typed async client for the websocket JSON-RPC protocol of `pianobar_webserver`.
+//!
+//! The client keeps its connection alive: if it drops, it reconnects,
+//! restores the subscriptions and the nickname, and resumes the event stream
+//! from the last sequence number it received.
+
+use crate::protocol::{
+    AuditEntry, ClientIdentity, Explanation, HistoryEntry, HistoryUpdateParams, PendingVote,
+    PianobarPlayerState, PlayerErrorParams, ResumeOutcome, ServerInfo, StateParams, Stats,
+    SubscriptionEntry, UiEventParams, UpcomingSong, VoteState, NOTIFICATION_ACTIVITY,
+    NOTIFICATION_HISTORY_UPDATE, NOTIFICATION_PLAYER_ERROR, NOTIFICATION_PLAYER_STATE,
+    NOTIFICATION_UI_EVENT, NOTIFICATION_VOTE_STATE, PROTOCOL_VERSION_MAJOR, PROTOCOL_VERSION_MINOR,
+    TOPICS, WELCOME_COMMAND,
+};
+use crate::ui_state::PianobarUiState;
+use anyhow::{anyhow, bail, Result};
+use futures::{SinkExt, Stream, StreamExt};
+use serde::de::DeserializeOwned;
+use serde_json as json;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
+use tokio::time::{sleep, timeout};
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::{self, ClientConfig, RootCertStore, ServerName};
+use tokio_rustls::TlsConnector;
+use tokio_tungstenite::stream::Stream as MaybeTlsStream;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::{HeaderValue, Uri};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+/// The number of notifications a receiver can fall behind before it misses some
+const NOTIFICATION_CHANNEL_CAPACITY: usize = 64;
+
+/// Where and how to connect
+#[derive(Clone)]
+pub struct ClientSettings {
+    /// The websocket URL, like `ws://localhost:3030/ws`.
+    /// `wss://` URLs get verified against the certificates of the system.
+    pub url: String,
+    /// An API token of the server's auth config
+    pub token: Option<String>,
+    /// How long connecting, including the TLS and websocket handshakes, may take
+    pub connect_timeout: Duration,
+    /// How long to wait between reconnection attempts
+    pub reconnect_delay: Duration,
+    /// How long a method call may take, including the time spent reconnecting
+    pub request_timeout: Duration,
+}
+
+impl ClientSettings {
+    pub fn new(url: &str) -> ClientSettings {
+        ClientSettings {
+            url: url.to_string(),
+            token: None,
+            connect_timeout: Duration::from_secs(10),
+            reconnect_delay: Duration::from_secs(5),
+            request_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl std::fmt::Debug for ClientSettings {
+    /// Leaves out the token, settings end up in logs
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClientSettings")
+            .field("url", &self.url)
+            .field("token", &self.token.as_ref().map(|_| "<redacted>"))
+            .field("connect_timeout", &self.connect_timeout)
+            .field("reconnect_delay", &self.reconnect_delay)
+            .field("request_timeout", &self.request_timeout)
+            .finish()
+    }
+}
+
+/// An error response of the server
+#[derive(Debug)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+    pub data: Option<json::Value>,
+}
+
+impl std::fmt::Display for RpcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (code {})", self.message, self.code)
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+/// Everything the client receives without asking for it
+#[derive(Clone, Debug)]
+pub enum Notification {
+    /// The client (re)connected. A welcome `UiEvent` with the full state preceded it.
+    Connected(ServerInfo),
+    /// The connection got lost, the client tries to reconnect
+    Disconnected(String),
+    /// A pianobar event. Its command is `websocket_welcome` for full state snapshots.
+    UiEvent(UiEventParams),
+    PlayerState(PianobarPlayerState),
+    PlayerError(PlayerErrorParams),
+    HistoryUpdate(HistoryUpdateParams),
+    VoteState(VoteState),
+    Activity(AuditEntry),
+    /// A notification of a newer server this client doesn't know
+    Unknown {
+        method: String,
+        params: json::Value,
+    },
+}
+
+impl Notification {
+    fn parse(method: String, params: json::Value) -> Result<Notification> {
+        Ok(match method.as_str() {
+            NOTIFICATION_UI_EVENT => Notification::UiEvent(json::from_value(params)?),
+            NOTIFICATION_PLAYER_STATE => {
+                Notification::PlayerState(json::from_value::<StateParams<_>>(params)?.state)
+            }
+            NOTIFICATION_PLAYER_ERROR => Notification::PlayerError(json::from_value(params)?),
+            NOTIFICATION_HISTORY_UPDATE => Notification::HistoryUpdate(json::from_value(params)?),
+            NOTIFICATION_VOTE_STATE => {
+                Notification::VoteState(json::from_value::<StateParams<_>>(params)?.state)
+            }
+            NOTIFICATION_ACTIVITY => Notification::Activity(json::from_value(params)?),
+            _ => Notification::Unknown { method, params },
+        })
+    }
+
+    /// The sequence number of notifications that stem from pianobar events
+    fn sequence(&self) -> Option<u64> {
+        match self {
+            Notification::UiEvent(params) => Some(params.sequence),
+            Notification::PlayerError(params) => params.sequence,
+            Notification::HistoryUpdate(params) => Some(params.sequence),
+            _ => None,
+        }
+    }
+}
+
+/// What gets restored after reconnecting
+#[derive(Default)]
+struct Session {
+    /// The subscriptions after the last `subscribe` or `unsubscribe`, if any
+    subscriptions: Option<Vec<SubscriptionEntry>>,
+    nickname: Option<String>,
+    instance_id: Option<String>,
+    /// The sequence number of the last event received
+    sequence: u64,
+}
+
+/// State shared between the client handles and the connection task
+struct Shared {
+    settings: ClientSettings,
+    notifications: broadcast::Sender<Notification>,
+    ui_state: watch::Sender<PianobarUiState>,
+    server_info: watch::Sender<Option<ServerInfo>>,
+    session: Mutex<Session>,
+}
+
+impl Session {
+    /// Where the event stream stopped, if the client received events before
+    fn resume_point(&self) -> Option<(String, u64)> {
+        match &self.instance_id {
+            Some(instance_id) if self.sequence > 0 => Some((instance_id.clone(), self.sequence)),
+            _ => None,
+        }
+    }
+}
+
+impl Shared {
+    fn publish(&self, notification: Notification) {
+        match &notification {
+            Notification::UiEvent(params) => {
+                let _ = self.ui_state.send(params.state.clone());
+                if let Some(server) = &params.server {
+                    self.session.lock().unwrap().instance_id = Some(server.instance_id.clone());
+                }
+            }
+            Notification::Connected(server) => {
+                let _ = self.server_info.send(Some(server.clone()));
+            }
+            _ => (),
+        }
+        if let Some(sequence) = notification.sequence() {
+            let mut session = self.session.lock().unwrap();
+            // Welcome messages are snapshots, so they may also move the sequence backwards
+            let is_welcome = matches!(
+                &notification,
+                Notification::UiEvent(params) if params.command == WELCOME_COMMAND
+            );
+            if is_welcome || sequence > session.sequence {
+                session.sequence = sequence;
+            }
+        }
+        // Fails if nobody listens, which is fine
+        let _ = self.notifications.send(notification);
+    }
+}
+
+struct Request {
+    method: String,
+    params: json::Value,
+    reply: oneshot::Sender<Result<json::Value>>,
+}
+
+enum Command {
+    Call(Request),
+    /// Closes the connection and ends the connection task
+    Close(oneshot::Sender<()>),
+}
+
+type ClientStream = MaybeTlsStream<TcpStream, TlsStream<TcpStream>>;
+
+fn tls_config() -> Result<Arc<ClientConfig>> {
+    let mut roots = RootCertStore::empty();
+    let certificates = rustls_native_certs::load_native_certs()
+        .map_err(|err| anyhow!("Unable to load the certificates of the system: {}", err))?;
+    for certificate in certificates {
+        // Some systems ship certificates rustls doesn't understand
+        if let Err(err) = roots.add(&rustls::Certificate(certificate.0)) {
+            log::debug!("Skipping certificate of the system: {}", err);
+        }
+    }
+    Ok(Arc::new(
+        ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth(),
+    ))
+}
+
+/// Opens the connection to the host of the URL, with TLS for `wss://`
+async fn connect_stream(uri: &Uri) -> Result<ClientStream> {
+    let tls = match uri.scheme_str() {
+        Some("ws") => false,
+        Some("wss") => true,
+        _ => bail!("Unsupported URL scheme, expected 'ws' or 'wss'"),
+    };
+    // IPv6 addresses come in brackets
+    let host = uri
+        .host()
+        .ok_or_else(|| anyhow!("The URL has no host"))?
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let port = uri.port_u16().unwrap_or(if tls { 443 } else { 80 });
+
+    let stream = TcpStream::connect((host, port)).await?;
+    if !tls {
+        return Ok(MaybeTlsStream::Plain(stream));
+    }
+    let server_name = ServerName::try_from(host)
+        .map_err(|err| anyhow!("Invalid host name '{}': {}", host, err))?;
+    let stream = TlsConnector::from(tls_config()?)
+        .connect(server_name, stream)
+        .await?;
+    Ok(MaybeTlsStream::Tls(stream))
+}
+
+/// A single websocket connection, replaced on reconnects
+struct Connection {
+    websocket: WebSocketStream<ClientStream>,
+    next_id: u64,
+    pending: HashMap<u64, oneshot::Sender<Result<json::Value>>>,
+    shared: Arc<Shared>,
+}
+
+impl Connection {
+    /// Connects, negotiates the protocol version and restores the session
+    async fn open(shared: Arc<Shared>) -> Result<Connection> {
+        let settings = &shared.settings;
+        let mut request = settings
+            .url
+            .as_str()
+            .into_client_request()
+            .map_err(|err| anyhow!("Invalid server URL '{}': {}", settings.url, err))?;
+        if let Some(token) = &settings.token {
+            request.headers_mut().insert(
+                "Authorization",
+                HeaderValue::from_str(&format!("Bearer {}", token))?,
+            );
+        }
+        let connecting = async {
+            let stream = connect_stream(request.uri()).await?;
+            let (websocket, _) = tokio_tungstenite::client_async(request, stream).await?;
+            Ok::<_, anyhow::Error>(websocket)
+        };
+        let websocket = timeout(settings.connect_timeout, connecting)
+            .await
+            .map_err(|_| anyhow!("Timed out connecting to '{}'", settings.url))?
+            .map_err(|err| anyhow!("Unable to connect to '{}': {}", settings.url, err))?;
+
+        // The welcome moves the session to the current sequence, so remember where it stopped
+        let resume = shared.session.lock().unwrap().resume_point();
+        let mut connection = Connection {
+            websocket,
+            next_id: 0,
+            pending: HashMap::new(),
+            shared: shared.clone(),
+        };
+
+        // The server greets with the full state
+        loop {
+            let message = connection.receive().await?;
+            if let Some(Notification::UiEvent(params)) = connection.handle_message(message)? {
+                if params.command == WELCOME_COMMAND {
+                    break;
+                }
+            }
+        }
+
+        let server_info: ServerInfo = json::from_value(
+            connection
+                .call(
+                    "hello",
+                    json::json!({
+                        "protocol_version":
+                            format!("{}.{}", PROTOCOL_VERSION_MAJOR, PROTOCOL_VERSION_MINOR)
+                    }),
+                )
+                .await?,
+        )?;
+        connection.restore_session(&server_info, resume).await?;
+        shared.publish(Notification::Connected(server_info));
+        Ok(connection)
+    }
+
+    /// Restores subscriptions and nickname, and replays the events after `resume`
+    async fn restore_session(
+        &mut self,
+        server_info: &ServerInfo,
+        resume: Option<(String, u64)>,
+    ) -> Result<()> {
+        let (subscriptions, nickname) = {
+            let session = self.shared.session.lock().unwrap();
+            (session.subscriptions.clone(), session.nickname.clone())
+        };
+
+        if let Some(subscriptions) = subscriptions {
+            self.call("unsubscribe", json::json!({ "topics": TOPICS }))
+                .await?;
+            // Topics with the same throttle interval get subscribed together
+            let mut by_throttle = BTreeMap::<Option<u64>, (Option<f64>, Vec<String>)>::new();
+            for entry in subscriptions {
+                by_throttle
+                    .entry(entry.throttle.map(f64::to_bits))
+                    .or_insert_with(|| (entry.throttle, vec![]))
+                    .1
+                    .push(entry.topic);
+            }
+            for (throttle, topics) in by_throttle.into_values() {
+                let mut params = json::json!({ "topics": topics });
+                if let Some(throttle) = throttle {
+                    params["throttle"] = throttle.into();
+                }
+                self.call("subscribe", params).await?;
+            }
+        }
+
+        if nickname.is_some() {
+            self.call("set_nickname", json::json!({ "nickname": nickname }))
+                .await?;
+        }
+
+        // The welcome message already carries the current state,
+        // the replay only delivers the events in between
+        if let Some((instance_id, sequence)) = resume {
+            if instance_id == server_info.instance_id {
+                let outcome: ResumeOutcome = json::from_value(
+                    self.call(
+                        "resume_events",
+                        json::json!({ "sequence": sequence, "instance_id": instance_id }),
+                    )
+                    .await?,
+                )?;
+                log::debug!(
+                    "Resumed from sequence {}: {} ({} events)",
+                    sequence,
+                    outcome.mode,
+                    outcome.events
+                );
+            }
+        }
+        Ok(())
+    }
+
+    async fn receive(&mut self) -> Result<json::Value> {
+        loop {
+            let message = match self.websocket.next().await {
+                Some(message) => message?,
+                None => bail!("Connection closed"),
+            };
+            match message {
+                Message::Text(text) => return Ok(json::from_str(&text)?),
+                Message::Close(Some(frame)) => {
+                    bail!(
+                        "Connection closed with code {}: {}",
+                        frame.code,
+                        frame.reason
+                    )
+                }
+                Message::Close(None) => bail!("Connection closed"),
+                // Pings get answered by the websocket itself
+                _ => continue,
+            }
+        }
+    }
+
+    async fn send_request(&mut self, method: &str, params: json::Value) -> Result<u64> {
+        self.next_id += 1;
+        let request = json::json!({
+            "jsonrpc": "2.0",
+            "id": self.next_id,
+            "method": method,
+            "params": params,
+        });
+        self.websocket
+            .send(Message::text(json::to_string(&request)?))
+            .await?;
+        Ok(self.next_id)
+    }
+
+    /// Publishes notifications and routes responses to their callers.
+    /// Returns the notification, if the message was one.
+    fn handle_message(&mut self, mut message: json::Value) -> Result<Option<Notification>> {
+        if let Some(json::Value::String(method)) = message.get("method") {
+            let method = method.clone();
+            let notification = Notification::parse(method, message["params"].take())?;
+            self.shared.publish(notification.clone());
+            return Ok(Some(notification));
+        }
+        let id = message["id"]
+            .as_u64()
+            .ok_or_else(|| anyhow!("Response without id: {}", message))?;
+        if let Some(reply) = self.pending.remove(&id) {
+            let _ = reply.send(parse_response(message));
+        }
+        Ok(None)
+    }
+
+    /// Calls a method directly, while no other calls are pending
+    async fn call(&mut self, method: &str, params: json::Value) -> Result<json::Value> {
+        let (reply, response) = oneshot::channel();
+        let id = self.send_request(method, params).await?;
+        self.pending.insert(id, reply);
+        loop {
+            let message = self.receive().await?;
+            self.handle_message(message)?;
+            if !self.pending.contains_key(&id) {
+                return response.await?;
+            }
+        }
+    }
+
+    /// Serves requests until the connection breaks.
+    /// Returns `Ok` once the client got closed or all its handles are gone.
+    async fn run(
+        &mut self,
+        commands: &mut mpsc::UnboundedReceiver<Command>,
+        backlog: &mut VecDeque<Request>,
+    ) -> Result<()> {
+        while let Some(request) = backlog.pop_front() {
+            self.start_request(request).await?;
+        }
+        loop {
+            tokio::select! {
+                message = self.receive() => {
+                    self.handle_message(message?)?;
+                }
+                command = commands.recv() => match command {
+                    Some(Command::Call(request)) => self.start_request(request).await?,
+                    Some(Command::Close(done)) => {
+                        let _ = self.websocket.close(None).await;
+                        let _ = done.send(());
+                        return Ok(());
+                    }
+                    None => {
+                        let _ = self.websocket.close(None).await;
+                        return Ok(());
+                    }
+                },
+            }
+        }
+    }
+
+    async fn start_request(&mut self, request: Request) -> Result<()> {
+        // The caller gave up already
+        if request.reply.is_closed() {
+            return Ok(());
+        }
+        let id = self.send_request(&request.method, request.params).await?;
+        self.pending.insert(id, request.reply);
+        Ok(())
+    }
+}
+
+fn parse_response(mut response: json::Value) -> Result<json::Value> {
+    if let Some(error) = response.get("error") {
+        return Err(RpcError {
+            code: error["code"].as_i64().unwrap_or_default(),
+            message: error["message"].as_str().unwrap_or_default().to_string(),
+            data: error.get("data").cloned(),
+        }
+        .into());
+    }
+    Ok(response["result"].take())
+}
+
+/// Keeps the connection alive until all client handles are gone
+async fn connection_task(
+    shared: Arc<Shared>,
+    mut connection: Connection,
+    mut commands: mpsc::UnboundedReceiver<Command>,
+) {
+    let mut backlog = VecDeque::new();
+    loop {
+        match connection.run(&mut commands, &mut backlog).await {
+            Ok(()) => return,
+            Err(err) => {
+                log::warn!("Lost connection to pianobar_webserver: {}", err);
+                shared.publish(Notification::Disconnected(err.to_string()));
+            }
+        }
+
+        connection = loop {
+            // Requests wait for the next connection, unless they time out before
+            let delay = sleep(shared.settings.reconnect_delay);
+            tokio::pin!(delay);
+            loop {
+                tokio::select! {
+                    _ = &mut delay => break,
+                    command = commands.recv() => match command {
+                        Some(Command::Call(request)) => backlog.push_back(request),
+                        Some(Command::Close(done)) => {
+                            let _ = done.send(());
+                            return;
+                        }
+                        None => return,
+                    },
+                }
+            }
+            match Connection::open(shared.clone()).await {
+                Ok(connection) => break connection,
+                Err(err) => log::debug!("Reconnecting failed: {}", err),
+            }
+        };
+    }
+}
+
+/// A handle to a connection to `pianobar_webserver`. Clones share the connection.
+#[derive(Clone)]
+pub struct PianobarClient {
+    shared: Arc<Shared>,
+    commands: mpsc::UnboundedSender<Command>,
+    ui_state: watch::Receiver<PianobarUiState>,
+    server_info: watch::Receiver<Option<ServerInfo>>,
+}
+
+impl PianobarClient {
+    /// Connects to the server. Fails if the first connection fails,
+    /// later disconnects are handled by reconnecting.
+    pub async fn connect(settings: ClientSettings) -> Result<PianobarClient> {
+        let (notifications, _) = broadcast::channel(NOTIFICATION_CHANNEL_CAPACITY);
+        let (ui_state_sender, ui_state) = watch::channel(PianobarUiState::new());
+        let (server_info_sender, server_info) = watch::channel(None);
+        let shared = Arc::new(Shared {
+            settings,
+            notifications,
+            ui_state: ui_state_sender,
+            server_info: server_info_sender,
+            session: Mutex::new(Session::default()),
+        });
+
+        let connection = Connection::open(shared.clone()).await?;
+        let (commands, commands_receiver) = mpsc::unbounded_channel();
+        tokio::spawn(connection_task(
+            shared.clone(),
+            connection,
+            commands_receiver,
+        ));
+
+        Ok(PianobarClient {
+            shared,
+            commands,
+            ui_state,
+            server_info,
+        })
+    }
+
+    /// The latest ui state, like the current song and the stations
+    pub fn ui_state(&self) -> PianobarUiState {
+        self.ui_state.borrow().clone()
+    }
+
+    /// Describes the server of the current connection
+    pub fn server_info(&self) -> Option<ServerInfo> {
+        self.server_info.borrow().clone()
+    }
+
+    /// All notifications from now on. Notifications get skipped if the stream falls behind.
+    pub fn notifications(&self) -> impl Stream<Item = Notification> {
+        let receiver = self.shared.notifications.subscribe();
+        futures::stream::unfold(receiver, |mut receiver| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(notification) => return Some((notification, receiver)),
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        log::warn!("Skipped {} notifications", missed);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        })
+    }
+
+    /// Closes the connection for all clones of this client
+    pub async fn close(&self) {
+        let (done, closed) = oneshot::channel();
+        if self.commands.send(Command::Close(done)).is_ok() {
+            let _ = closed.await;
+        }
+    }
+
+    /// Calls any method. Prefer the typed methods.
+    pub async fn call<T: DeserializeOwned>(&self, method: &str, params: json::Value) -> Result<T> {
+        let (reply, response) = oneshot::channel();
+        self.commands
+            .send(Command::Call(Request {
+                method: method.to_string(),
+                params,
+                reply,
+            }))
+            .map_err(|_| anyhow!("The client is closed"))?;
+        let result = timeout(self.shared.settings.request_timeout, response)
+            .await
+            .map_err(|_| anyhow!("'{}' timed out", method))?
+            .map_err(|_| anyhow!("'{}' failed: connection lost", method))??;
+        Ok(json::from_value(result)?)
+    }
+
+    pub async fn change_station(&self, station_id: usize) -> Result<Option<PendingVote>> {
+        self.call("change_station", json::json!({ "station_id": station_id }))
+            .await
+    }
+
+    pub async fn pause(&self) -> Result<()> {
+        self.call("pause", json::json!({})).await
+    }
+
+    pub async fn resume(&self) -> Result<()> {
+        self.call("resume", json::json!({})).await
+    }
+
+    pub async fn toggle_pause(&self) -> Result<()> {
+        self.call("toggle_pause", json::json!({})).await
+    }
+
+    pub async fn skip(&self) -> Result<Option<PendingVote>> {
+        self.call("skip", json::json!({})).await
+    }
+
+    pub async fn ban(&self) -> Result<Option<PendingVote>> {
+        self.call("ban", json::json!({})).await
+    }
+
+    /// `action` is `skip`, `ban` or `change_station`, which needs a station id
+    pub async fn cancel_vote(&self, action: &str, station_id: Option<usize>) -> Result<()> {
+        let mut params = json::json!({ "action": action });
+        if let Some(station_id) = station_id {
+            params["station_id"] = station_id.into();
+        }
+        self.call("cancel_vote", params).await
+    }
+
+    pub async fn explain(&self) -> Result<Explanation> {
+        self.call("explain", json::json!({})).await
+    }
+
+    pub async fn history(&self) -> Result<Vec<HistoryEntry>> {
+        self.call("history", json::json!({})).await
+    }
+
+    pub async fn upcoming(&self) -> Result<Vec<UpcomingSong>> {
+        self.call("upcoming", json::json!({})).await
+    }
+
+    /// The subscriptions get restored after reconnecting
+    pub async fn subscribe(
+        &self,
+        topics: &[&str],
+        throttle: Option<Duration>,
+    ) -> Result<Vec<SubscriptionEntry>> {
+        let mut params = json::json!({ "topics": topics });
+        if let Some(throttle) = throttle {
+            params["throttle"] = throttle.as_secs_f64().into();
+        }
+        let subscriptions: Vec<SubscriptionEntry> = self.call("subscribe", params).await?;
+        self.shared.session.lock().unwrap().subscriptions = Some(subscriptions.clone());
+        Ok(subscriptions)
+    }
+
+    pub async fn unsubscribe(&self, topics: &[&str]) -> Result<Vec<SubscriptionEntry>> {
+        let subscriptions: Vec<SubscriptionEntry> = self
+            .call("unsubscribe", json::json!({ "topics": topics }))
+            .await?;
+        self.shared.session.lock().unwrap().subscriptions = Some(subscriptions.clone());
+        Ok(subscriptions)
+    }
+
+    /// The nickname gets restored after reconnecting
+    pub async fn set_nickname(&self, nickname: Option<&str>) -> Result<ClientIdentity> {
+        let identity: ClientIdentity = self
+            .call("set_nickname", json::json!({ "nickname": nickname }))
+            .await?;
+        self.shared.session.lock().unwrap().nickname = identity.nickname.clone();
+        Ok(identity)
+    }
+
+    pub async fn audit_log(&self, limit: Option<usize>) -> Result<Vec<AuditEntry>> {
+        let mut params = json::json!({});
+        if let Some(limit) = limit {
+            params["limit"] = limit.into();
+        }
+        self.call("audit_log", params).await
+    }
+
+    pub async fn stats(&self) -> Result<Stats> {
+        self.call("stats", json::json!({})).await
+    }
+
+    /// The OpenRPC description of all methods
+    pub async fn discover(&self) -> Result<json::Value> {
+        self.call("rpc.discover", json::json!({})).await
+    }
+}
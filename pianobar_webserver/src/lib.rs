@@ -1,2 +1,4 @@
+pub mod client;
 pub mod default_config;
+pub mod protocol;
 pub mod ui_state;
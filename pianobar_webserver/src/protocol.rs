@@ -0,0 +1,338 @@
+//! The types exchanged over the websocket, shared by the server and the client.
+
+use crate::ui_state::PianobarUiState;
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Clients with a different major version can't talk to the server.
+/// Increment it on breaking changes, and the minor version on additions.
+pub const PROTOCOL_VERSION_MAJOR: u32 = 2;
+pub const PROTOCOL_VERSION_MINOR: u32 = 1;
+
+/// The client didn't read its messages fast enough
+pub const CLOSE_TOO_SLOW: u16 = 4000;
+/// The client speaks an incompatible protocol version and should reload
+pub const CLOSE_INCOMPATIBLE_VERSION: u16 = 4001;
+
+/// The error code of the `hello` method if the protocol versions are incompatible
+pub const ERROR_INCOMPATIBLE_VERSION: i64 = -32002;
+
+pub const TOPIC_PLAYER_STATE: &str = "player_state";
+pub const TOPIC_UI_EVENT: &str = "ui_event";
+pub const TOPIC_ERRORS: &str = "errors";
+pub const TOPIC_HISTORY: &str = "history";
+pub const TOPIC_VOTES: &str = "votes";
+pub const TOPIC_ACTIVITY: &str = "activity";
+
+/// All topics, every client is subscribed to them after connecting
+pub const TOPICS: &[&str] = &[
+    TOPIC_PLAYER_STATE,
+    TOPIC_UI_EVENT,
+    TOPIC_ERRORS,
+    TOPIC_HISTORY,
+    TOPIC_VOTES,
+    TOPIC_ACTIVITY,
+];
+
+pub const NOTIFICATION_UI_EVENT: &str = "ui_event";
+pub const NOTIFICATION_PLAYER_STATE: &str = "player_state";
+pub const NOTIFICATION_PLAYER_ERROR: &str = "player_error";
+pub const NOTIFICATION_HISTORY_UPDATE: &str = "history_update";
+pub const NOTIFICATION_VOTE_STATE: &str = "vote_state";
+pub const NOTIFICATION_ACTIVITY: &str = "activity";
+
+/// All notifications the server sends
+pub const NOTIFICATIONS: &[&str] = &[
+    NOTIFICATION_UI_EVENT,
+    NOTIFICATION_PLAYER_STATE,
+    NOTIFICATION_PLAYER_ERROR,
+    NOTIFICATION_HISTORY_UPDATE,
+    NOTIFICATION_VOTE_STATE,
+    NOTIFICATION_ACTIVITY,
+];
+
+/// The command of the `ui_event` notification that carries the full ui state
+pub const WELCOME_COMMAND: &str = "websocket_welcome";
+
+/// The command of the `ui_event` notification that adds the explanation of the
+/// current song to the ui state, as `explanation`
+pub const EXPLANATION_COMMAND: &str = "explanation";
+
+/// The command of the `ui_event` notification that updates the songs pianobar
+/// plays next, as `upcoming` in the ui state
+pub const UPCOMING_COMMAND: &str = "upcoming";
+
+/// The command of `player_error` notifications for errors pianobar printed,
+/// instead of reporting them with a ui event
+pub const OUTPUT_ERROR_COMMAND: &str = "output";
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Capabilities {
+    pub methods: Vec<String>,
+    pub notifications: Vec<String>,
+}
+
+/// Describes the server to its clients, in the welcome message and as result of `hello`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ServerInfo {
+    pub protocol_version: String,
+    pub server_version: String,
+    /// Changes when the server restarts
+    pub instance_id: String,
+    pub capabilities: Capabilities,
+}
+
+/// What the player is doing
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlaybackState {
+    /// No song is loaded
+    Stopped,
+    /// A song is about to play, or playback stalled
+    Buffering,
+    Playing,
+    Paused,
+}
+
+/// The progress of the current song, sent as `player_state` notification.
+///
+/// It is only sent when the prediction from `position`, `rate` and `timestamp`
+/// goes wrong, so clients have to advance the position themselves.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PianobarPlayerState {
+    /// `position` in whole seconds
+    pub song_time_played: u32,
+    pub song_time_total: u32,
+    /// Server time in milliseconds since the unix epoch at which `position` was sampled
+    #[serde(default)]
+    pub timestamp: u64,
+    /// The song position in seconds
+    #[serde(default)]
+    pub position: f64,
+    /// How many seconds the position advances per second, 1 while playing and 0 otherwise
+    #[serde(default)]
+    pub rate: f64,
+    /// Whether the song doesn't advance, `playback` tells why
+    pub paused: bool,
+    #[serde(default = "PianobarPlayerState::default_playback")]
+    pub playback: PlaybackState,
+    /// The operation pianobar is waiting for, like `Login...`
+    #[serde(default)]
+    pub busy: Option<String>,
+}
+
+impl Default for PianobarPlayerState {
+    fn default() -> Self {
+        PianobarPlayerState {
+            song_time_played: 0,
+            song_time_total: 0,
+            timestamp: 0,
+            position: 0.0,
+            rate: 0.0,
+            paused: true,
+            playback: PlaybackState::Stopped,
+            busy: None,
+        }
+    }
+}
+
+impl PianobarPlayerState {
+    fn default_playback() -> PlaybackState {
+        PlaybackState::Stopped
+    }
+
+    /// The predicted song position in seconds at the given server time
+    pub fn position_at(&self, timestamp: u64) -> f64 {
+        let elapsed = timestamp.saturating_sub(self.timestamp) as f64 / 1000.0;
+        (self.position + self.rate * elapsed).min(self.song_time_total as f64)
+    }
+
+    /// The same prediction, sampled at the given server time
+    pub fn at(&self, timestamp: u64) -> Self {
+        let position = self.position_at(timestamp);
+        PianobarPlayerState {
+            song_time_played: position as u32,
+            timestamp,
+            position,
+            ..self.clone()
+        }
+    }
+}
+
+/// The current server time, in milliseconds since the unix epoch
+pub fn timestamp_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// A vote that hasn't reached its quorum yet
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PendingVote {
+    /// `skip`, `ban` or `change_station`
+    pub action: String,
+    pub station_id: Option<usize>,
+    pub votes: usize,
+    pub required_votes: usize,
+    /// Seconds until the vote expires
+    pub expires_in: f64,
+}
+
+/// The state of all votes, sent as `vote_state` notification
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct VoteState {
+    /// `direct` or `vote`
+    pub mode: String,
+    pub listeners: usize,
+    pub required_votes: usize,
+    pub votes: Vec<PendingVote>,
+}
+
+/// A song in the history
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub artist: String,
+    pub title: String,
+}
+
+/// A song that plays after the current one, as listed by `upcoming`
+/// and in the ui state as `upcoming`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct UpcomingSong {
+    pub artist: String,
+    pub title: String,
+    /// Empty if pianobar doesn't list albums
+    pub album: String,
+    /// Like the `rating` of ui events: 0 = none, 1 = loved, 2 = banned, 3 = tired
+    pub rating: u8,
+}
+
+/// Why the current song is playing, the result of `explain`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Explanation {
+    /// The musical traits the song was picked for, like `a leisurely tempo`
+    pub traits: Vec<String>,
+    /// What else led to the song, like `many other similarities as identified by the Music Genome Project`
+    pub reasons: Vec<String>,
+    /// The sentence pianobar printed
+    pub raw: String,
+}
+
+/// Who a client is, as shown to other clients and recorded in the audit log
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ClientIdentity {
+    /// The nickname if set, otherwise the user
+    pub name: String,
+    pub user: Option<String>,
+    pub nickname: Option<String>,
+    /// Only visible to admins
+    pub address: Option<String>,
+}
+
+/// A single action of a client, sent as `activity` notification
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// Milliseconds since the unix epoch
+    pub timestamp: u64,
+    pub client: ClientIdentity,
+    pub method: String,
+    pub params: serde_json::Value,
+    /// The result on success, otherwise `null`
+    pub result: serde_json::Value,
+    /// The error message on failure, otherwise `null`
+    pub error: Option<String>,
+}
+
+/// A notification topic a client is subscribed to
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SubscriptionEntry {
+    pub topic: String,
+    /// The minimum number of seconds between two notifications
+    pub throttle: Option<f64>,
+}
+
+/// How often clients fell behind the event broadcasts since the server started
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Stats {
+    pub ui_event_lags: u64,
+    pub ui_events_missed: u64,
+    pub activity_lags: u64,
+    pub activity_missed: u64,
+}
+
+/// The result of `resume_events`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ResumeOutcome {
+    /// `replay` if the missed events got replayed, `snapshot` if a full
+    /// welcome message got sent instead
+    pub mode: String,
+    /// The number of replayed events
+    pub events: usize,
+    /// The sequence number the client is at now
+    pub sequence: u64,
+}
+
+impl ResumeOutcome {
+    pub fn replay(events: usize, sequence: u64) -> ResumeOutcome {
+        ResumeOutcome {
+            mode: "replay".to_string(),
+            events,
+            sequence,
+        }
+    }
+
+    pub fn snapshot(sequence: u64) -> ResumeOutcome {
+        ResumeOutcome {
+            mode: "snapshot".to_string(),
+            events: 0,
+            sequence,
+        }
+    }
+}
+
+/// The parameters of the `ui_event` notification
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UiEventParams {
+    pub command: String,
+    pub state: PianobarUiState,
+    /// The sequence number of the event, or of the last event contained in a welcome message
+    #[serde(default)]
+    pub sequence: u64,
+    /// Only part of welcome messages
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server: Option<ServerInfo>,
+    /// Set on welcome messages that replace the state of a client that missed events
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub resync: bool,
+}
+
+/// The parameters of the `player_error` notification
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PlayerErrorParams {
+    /// The ui event that reported the error, or `output`
+    pub command: String,
+    pub message: String,
+    /// Only errors reported by ui events have a sequence number
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sequence: Option<u64>,
+}
+
+/// The parameters of the `history_update` notification
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HistoryUpdateParams {
+    #[serde(flatten)]
+    pub song: HistoryEntry,
+    #[serde(default)]
+    pub sequence: u64,
+}
+
+/// The parameters of the `player_state` and `vote_state` notifications
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StateParams<T> {
+    pub state: T,
+}
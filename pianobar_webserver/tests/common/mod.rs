@@ -0,0 +1,287 @@
+//! Runs pianobar_webserver with fake_pianobar for the integration tests.
+//!
+//! pianobar_event_handler always reports to the default event port,
+//! so only one server can run at a time. Every test file starts at most one,
+//! cargo runs the test files one after another.
+
+#![allow(dead_code)]
+
+use anyhow::{anyhow, Result};
+use futures::{SinkExt, Stream, StreamExt};
+use pianobar_webserver::client::{ClientSettings, Notification, PianobarClient};
+use pianobar_webserver::protocol::PlaybackState;
+use pianobar_webserver::ui_state::PianobarUiState;
+use serde_json as json;
+use std::net::{Ipv4Addr, SocketAddr, TcpListener};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::process::{Child, Command};
+use tokio::sync::{mpsc, Mutex};
+use tokio::time::{sleep, timeout, Instant};
+use tokio_tungstenite::tungstenite::{http, Message};
+
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(20);
+const NOTIFICATION_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// The server, killed together with fake_pianobar when dropped
+pub struct Server {
+    _process: Child,
+    _pianobar_config: tempfile::NamedTempFile,
+    pub port: u16,
+    pub url: String,
+}
+
+impl Server {
+    /// Starts the server with additional command line arguments
+    pub fn start(args: &[&str]) -> Result<Server> {
+        // The server writes its message formats into the config, fake_pianobar reads them
+        let pianobar_config = tempfile::NamedTempFile::new()?;
+        let pianobar_config_path = pianobar_config
+            .path()
+            .to_str()
+            .ok_or_else(|| anyhow!("Unable to stringify the temp path"))?
+            .to_string();
+
+        let port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?
+            .local_addr()?
+            .port();
+        let process = Command::new(env!("CARGO_BIN_EXE_pianobar_webserver"))
+            .args(["--port", &port.to_string(), "--bind", "127.0.0.1"])
+            .args(["--pianobar-path", env!("CARGO_BIN_EXE_fake_pianobar")])
+            .args(["--pianobar-config", &pianobar_config_path])
+            .args(args)
+            .env("FAKE_PIANOBAR_CONFIG", &pianobar_config_path)
+            // Only the errors, the server warns about the missing web ui
+            .env("RUST_LOG", "error")
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        Ok(Server {
+            _process: process,
+            _pianobar_config: pianobar_config,
+            port,
+            url: format!("ws://127.0.0.1:{}/ws", port),
+        })
+    }
+
+    /// Connects as soon as the server listens
+    pub async fn connect(&self) -> Result<PianobarClient> {
+        self.connect_with(ClientSettings::new(&self.url)).await
+    }
+
+    pub async fn connect_with(&self, settings: ClientSettings) -> Result<PianobarClient> {
+        let deadline = Instant::now() + STARTUP_TIMEOUT;
+        loop {
+            match PianobarClient::connect(settings.clone()).await {
+                Ok(client) => return Ok(client),
+                Err(err) if Instant::now() > deadline => return Err(err),
+                Err(_) => sleep(Duration::from_millis(100)).await,
+            }
+        }
+    }
+}
+
+/// Skips notifications until one matches
+pub async fn wait_for<T>(
+    notifications: &mut (impl Stream<Item = Notification> + Unpin),
+    description: &str,
+    mut matches: impl FnMut(Notification) -> Option<T>,
+) -> Result<T> {
+    timeout(NOTIFICATION_TIMEOUT, async {
+        while let Some(notification) = notifications.next().await {
+            if let Some(result) = matches(notification) {
+                return Ok(result);
+            }
+        }
+        Err(anyhow!("Notifications ended"))
+    })
+    .await
+    .map_err(|_| anyhow!("Timed out waiting for {}", description))?
+}
+
+/// Waits until fake_pianobar logged in and asks for a station.
+/// It might have done so before the client connected.
+pub async fn wait_for_stations(
+    client: &PianobarClient,
+    notifications: &mut (impl Stream<Item = Notification> + Unpin),
+) -> Result<()> {
+    let has_stations = |state: &PianobarUiState| {
+        state
+            .get("stations")
+            .and_then(|stations| stations.as_array())
+            .is_some_and(|stations| !stations.is_empty())
+    };
+    if has_stations(&client.ui_state()) {
+        return Ok(());
+    }
+    wait_for(
+        notifications,
+        "the stations",
+        |notification| match notification {
+            Notification::UiEvent(event) if has_stations(&event.state) => Some(()),
+            _ => None,
+        },
+    )
+    .await
+}
+
+/// Waits for the `songstart` ui event and the player state that says it plays,
+/// which arrive in any order. Returns the title.
+pub async fn wait_for_song(
+    notifications: &mut (impl Stream<Item = Notification> + Unpin),
+) -> Result<String> {
+    let mut title = None;
+    let mut playing = false;
+    wait_for(notifications, "the song to play", |notification| {
+        match notification {
+            Notification::UiEvent(event) if event.command == "songstart" => {
+                title = event
+                    .state
+                    .get("title")
+                    .and_then(|title| title.as_str())
+                    .map(str::to_string);
+            }
+            Notification::PlayerState(state) => {
+                playing = state.playback == PlaybackState::Playing;
+            }
+            _ => (),
+        }
+        if playing {
+            title.clone()
+        } else {
+            None
+        }
+    })
+    .await
+}
+
+type WebSocketSink =
+    futures::stream::SplitSink<tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>, Message>;
+
+/// A bare JSON-RPC connection that claims to be forwarded by a proxy for `address`.
+/// Needs the server to trust 127.0.0.1 as proxy.
+pub struct ForwardedClient {
+    sink: Mutex<WebSocketSink>,
+    responses: Mutex<mpsc::UnboundedReceiver<json::Value>>,
+}
+
+impl ForwardedClient {
+    pub async fn connect(url: &str, address: &str) -> Result<ForwardedClient> {
+        let request = http::Request::builder()
+            .uri(url)
+            .header("X-Forwarded-For", address)
+            .body(())?;
+        let (websocket, _) = tokio_tungstenite::connect_async(request).await?;
+        let (sink, mut stream) = websocket.split();
+
+        // Reading also answers the pings of the server
+        let (sender, responses) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(Ok(message)) = stream.next().await {
+                if let Message::Text(text) = message {
+                    match json::from_str::<json::Value>(&text) {
+                        Ok(response) if response.get("id").is_some() => {
+                            let _ = sender.send(response);
+                        }
+                        _ => (),
+                    }
+                }
+            }
+        });
+
+        Ok(ForwardedClient {
+            sink: Mutex::new(sink),
+            responses: Mutex::new(responses),
+        })
+    }
+
+    /// Calls a method and returns its result
+    pub async fn call(&self, method: &str, params: json::Value) -> Result<json::Value> {
+        let request =
+            json::json!({"jsonrpc": "2.0", "id": method, "method": method, "params": params});
+        self.sink
+            .lock()
+            .await
+            .send(Message::Text(request.to_string()))
+            .await?;
+
+        let mut responses = self.responses.lock().await;
+        timeout(NOTIFICATION_TIMEOUT, async {
+            while let Some(response) = responses.recv().await {
+                if response["id"] != method {
+                    continue;
+                }
+                return match response.get("error") {
+                    Some(error) => Err(anyhow!("{} failed: {}", method, error)),
+                    None => Ok(response["result"].clone()),
+                };
+            }
+            Err(anyhow!("Connection closed"))
+        })
+        .await
+        .map_err(|_| anyhow!("Timed out waiting for the response of {}", method))?
+    }
+}
+
+/// Forwards TCP connections to the server until it gets cut
+pub struct Proxy {
+    pub url: String,
+    cut: Arc<AtomicBool>,
+    connections: Arc<Mutex<Vec<tokio::task::JoinHandle<()>>>>,
+}
+
+impl Proxy {
+    pub async fn start(server_port: u16) -> Result<Proxy> {
+        let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let url = format!("ws://{}/ws", listener.local_addr()?);
+        let server_address = SocketAddr::from((Ipv4Addr::LOCALHOST, server_port));
+        let cut = Arc::new(AtomicBool::new(false));
+        let connections = Arc::new(Mutex::new(Vec::new()));
+
+        let proxy = Proxy {
+            url,
+            cut: cut.clone(),
+            connections: connections.clone(),
+        };
+        tokio::spawn(async move {
+            while let Ok((client, _)) = listener.accept().await {
+                // Refused connections get dropped right away
+                if cut.load(Ordering::SeqCst) {
+                    continue;
+                }
+                let server = match TcpStream::connect(server_address).await {
+                    Ok(server) => server,
+                    Err(_) => continue,
+                };
+                let (mut client_read, mut client_write) = client.into_split();
+                let (mut server_read, mut server_write) = server.into_split();
+                let mut connections = connections.lock().await;
+                connections.push(tokio::spawn(async move {
+                    let _ = tokio::io::copy(&mut client_read, &mut server_write).await;
+                }));
+                connections.push(tokio::spawn(async move {
+                    let _ = tokio::io::copy(&mut server_read, &mut client_write).await;
+                }));
+            }
+        });
+        Ok(proxy)
+    }
+
+    /// Drops all connections and refuses new ones
+    pub async fn cut(&self) {
+        self.cut.store(true, Ordering::SeqCst);
+        for connection in self.connections.lock().await.drain(..) {
+            connection.abort();
+        }
+    }
+
+    /// Accepts connections again
+    pub fn restore(&self) {
+        self.cut.store(false, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,39 @@
+//! Runs pianobar_webserver with fake_pianobar and talks to it through the client.
+
+mod common;
+
+use anyhow::Result;
+use common::{wait_for, wait_for_song, wait_for_stations, Server};
+use pianobar_webserver::client::Notification;
+use pianobar_webserver::protocol::PlaybackState;
+
+#[tokio::test]
+async fn plays_and_pauses_a_song() -> Result<()> {
+    let server = Server::start(&[])?;
+    let client = server.connect().await?;
+    let mut notifications = Box::pin(client.notifications());
+    wait_for_stations(&client, &mut notifications).await?;
+
+    client.change_station(2).await?;
+    let title = wait_for_song(&mut notifications).await?;
+    assert!(!title.is_empty());
+
+    client.pause().await?;
+    let state = wait_for(
+        &mut notifications,
+        "paused",
+        |notification| match notification {
+            Notification::PlayerState(state) if state.playback != PlaybackState::Playing => {
+                Some(state)
+            }
+            _ => None,
+        },
+    )
+    .await?;
+    assert_eq!(state.playback, PlaybackState::Paused);
+    assert!(state.paused);
+    assert_eq!(state.rate, 0.0);
+
+    client.close().await;
+    Ok(())
+}
@@ -0,0 +1,65 @@
+//! Reconnects through a proxy that drops the connection in between.
+
+mod common;
+
+use anyhow::Result;
+use common::{wait_for, wait_for_song, wait_for_stations, Proxy, Server};
+use pianobar_webserver::client::{ClientSettings, Notification};
+use std::time::Duration;
+
+#[tokio::test]
+async fn replays_missed_events_after_reconnecting() -> Result<()> {
+    let server = Server::start(&[])?;
+    let proxy = Proxy::start(server.port).await?;
+    let direct = server.connect().await?;
+    let mut settings = ClientSettings::new(&proxy.url);
+    settings.reconnect_delay = Duration::from_millis(200);
+    let client = server.connect_with(settings).await?;
+    let mut notifications = Box::pin(client.notifications());
+    wait_for_stations(&client, &mut notifications).await?;
+
+    client.change_station(2).await?;
+    wait_for_song(&mut notifications).await?;
+
+    proxy.cut().await;
+    wait_for(
+        &mut notifications,
+        "the disconnect",
+        |notification| match notification {
+            Notification::Disconnected(_) => Some(()),
+            _ => None,
+        },
+    )
+    .await?;
+
+    // Another song starts while the client is gone
+    let mut direct_notifications = Box::pin(direct.notifications());
+    direct.skip().await?;
+    let missed =
+        wait_for(
+            &mut direct_notifications,
+            "the next song",
+            |notification| match notification {
+                Notification::UiEvent(event) if event.command == "songstart" => Some(event),
+                _ => None,
+            },
+        )
+        .await?;
+
+    proxy.restore();
+    let replayed = wait_for(
+        &mut notifications,
+        "the replay",
+        |notification| match notification {
+            Notification::UiEvent(event) if event.command == "songstart" => Some(event),
+            _ => None,
+        },
+    )
+    .await?;
+    assert_eq!(replayed.sequence, missed.sequence);
+    assert_eq!(replayed.state.get("title"), missed.state.get("title"));
+
+    client.close().await;
+    direct.close().await;
+    Ok(())
+}
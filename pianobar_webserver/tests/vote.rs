@@ -0,0 +1,54 @@
+//! Vote mode without an auth config, where everyone is a listener.
+
+mod common;
+
+use anyhow::Result;
+use common::{wait_for, wait_for_song, wait_for_stations, ForwardedClient, Server};
+use pianobar_webserver::client::Notification;
+use pianobar_webserver::protocol::PendingVote;
+use serde_json as json;
+use std::time::Duration;
+use tokio::time::timeout;
+
+#[tokio::test]
+async fn holds_a_skip_until_the_quorum_is_reached() -> Result<()> {
+    // Trusting the proxy headers lets the connections come from different people
+    let server = Server::start(&["--control-mode", "vote", "--trusted-proxy", "127.0.0.1"])?;
+    let client = server.connect().await?;
+    let mut notifications = Box::pin(client.notifications());
+    wait_for_stations(&client, &mut notifications).await?;
+
+    // Alone, the only listener is the quorum
+    assert!(client.change_station(2).await?.is_none());
+    wait_for_song(&mut notifications).await?;
+
+    // Three people, one of them with a second connection: two votes are needed
+    let second_tab = ForwardedClient::connect(&server.url, "127.0.0.1").await?;
+    let second_person = ForwardedClient::connect(&server.url, "192.0.2.2").await?;
+    let _third_person = ForwardedClient::connect(&server.url, "192.0.2.3").await?;
+
+    let vote = client.skip().await?.expect("the skip to wait for votes");
+    assert_eq!((vote.votes, vote.required_votes), (1, 2));
+    let vote: PendingVote = json::from_value(second_tab.call("skip", json::json!({})).await?)?;
+    assert_eq!((vote.votes, vote.required_votes), (1, 2));
+
+    let skipped = timeout(
+        Duration::from_secs(2),
+        wait_for(
+            &mut notifications,
+            "songstart",
+            |notification| match notification {
+                Notification::UiEvent(event) if event.command == "songstart" => Some(()),
+                _ => None,
+            },
+        ),
+    )
+    .await;
+    assert!(skipped.is_err(), "the song got skipped without a quorum");
+
+    assert!(second_person.call("skip", json::json!({})).await?.is_null());
+    wait_for_song(&mut notifications).await?;
+
+    client.close().await;
+    Ok(())
+}
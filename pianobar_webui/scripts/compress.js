@@ -0,0 +1,35 @@
+// Precompresses the build output with gzip and brotli,
+// so pianobar_webserver can embed and serve the compressed variants.
+const fs = require("fs");
+const path = require("path");
+const zlib = require("zlib");
+
+const BUILD_DIR = path.join(__dirname, "..", "build");
+const COMPRESSIBLE = /\.(html|js|css|json|svg|txt|map|ico)$/;
+
+function writeIfSmaller(file, original, compressed) {
+    if (compressed.length < original.length) {
+        fs.writeFileSync(file, compressed);
+    }
+}
+
+function compressDirectory(dir) {
+    for (const entry of fs.readdirSync(dir, { withFileTypes: true })) {
+        const file = path.join(dir, entry.name);
+        if (entry.isDirectory()) {
+            compressDirectory(file);
+        } else if (COMPRESSIBLE.test(entry.name)) {
+            const content = fs.readFileSync(file);
+            writeIfSmaller(file + ".gz", content, zlib.gzipSync(content, { level: 9 }));
+            writeIfSmaller(
+                file + ".br",
+                content,
+                zlib.brotliCompressSync(content, {
+                    params: { [zlib.constants.BROTLI_PARAM_QUALITY]: zlib.constants.BROTLI_MAX_QUALITY },
+                })
+            );
+        }
+    }
+}
+
+compressDirectory(BUILD_DIR);
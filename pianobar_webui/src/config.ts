@@ -1,2 +1,25 @@
-export const WEBSOCKET_PORT =
-    process.env.NODE_ENV === "production" ? window.location.port : 3030;
+// In production the websocket is served next to the web ui, possibly under a
+// reverse proxy base path like "/music/", so it gets resolved relative to the page.
+// In development the web ui is served by the dev server and the websocket by pianobar_webserver.
+function websocketUrl(): string {
+    const protocol = window.location.protocol === "https:" ? "wss:" : "ws:";
+    if (process.env.NODE_ENV !== "production") {
+        return protocol + "//" + window.location.hostname + ":3030/ws";
+    }
+    const url = new URL("ws", window.location.href);
+    url.protocol = protocol;
+    url.search = "";
+    url.hash = "";
+    return url.toString();
+}
+
+// The login page of pianobar_webserver, for when it requires a session
+function loginUrl(): string {
+    if (process.env.NODE_ENV !== "production") {
+        return window.location.protocol + "//" + window.location.hostname + ":3030/login";
+    }
+    return new URL("login", window.location.href).toString();
+}
+
+export const WEBSOCKET_URL = websocketUrl();
+export const LOGIN_URL = loginUrl();
@@ -1,5 +1,14 @@
+export type PlaybackState = "stopped" | "buffering" | "playing" | "paused";
+
 export type PlayerState = {
     paused: boolean,
+    playback: PlaybackState,
     song_time_played: number,
-    song_time_total: number
+    song_time_total: number,
+    // Server time in milliseconds at which `position` was sampled
+    timestamp: number,
+    // The song position in seconds, advancing with `rate` seconds per second
+    position: number,
+    rate: number,
+    busy: string | null
 };
@@ -1,5 +1,6 @@
 import { createSelector } from "@reduxjs/toolkit";
 import { RootState } from "../../../app/store";
+import { PlaybackState } from "./playerState";
 
 // Selectors
 export const selectPianobarRawUiState = (state: RootState) => state.pianobar.ui;
@@ -86,11 +87,54 @@ export const selectPianobarStationId = createSelector(
     }
 )
 
+export interface UpcomingSong {
+    artist: string;
+    title: string;
+    album: string;
+    rating: number;
+}
+
+function isUpcomingSong(value: any): value is UpcomingSong {
+    return typeof value === "object" && value !== null
+        && typeof value.artist === "string"
+        && typeof value.title === "string"
+        && typeof value.album === "string"
+        && typeof value.rating === "number";
+}
+
+const selectPianobarRawUpcoming = (state: RootState): unknown => {
+    return selectPianobarRawUiState(state)["upcoming"];
+};
+
+export const selectPianobarUpcoming = createSelector(
+    selectPianobarRawUpcoming,
+    (upcoming): UpcomingSong[] => {
+        if (upcoming === undefined) {
+            return [];
+        }
+        if (!Array.isArray(upcoming) || !upcoming.every(isUpcomingSong)) {
+            console.error("'upcoming' contains invalid values:", upcoming);
+            return [];
+        }
+        return upcoming;
+    }
+)
+
 
 export const selectPianobarConnected = (state: RootState): boolean => state.pianobar.websocket.connected;
+export const selectPianobarIncompatible = (state: RootState): boolean => state.pianobar.websocket.incompatible;
+export const selectPianobarUnauthorized = (state: RootState): boolean => state.pianobar.websocket.unauthorized;
 export const selectPianobarPaused = (state: RootState): boolean => state.pianobar.player.paused;
-export const selectPianobarSongPlayedSeconds = (state: RootState): number => state.pianobar.player.song_time_played;
+// The server only sends the player state when it changes unexpectedly,
+// in between the position gets interpolated
+export const selectPianobarSongPlayedSeconds = (state: RootState): number => {
+    const { position, rate, song_time_total } = state.pianobar.player;
+    const elapsed = (state.pianobar.clock - state.pianobar.playerReceivedAt) / 1000;
+    return Math.floor(Math.min(position + rate * elapsed, song_time_total));
+};
 export const selectPianobarSongDurationSeconds = (state: RootState): number => state.pianobar.player.song_time_total;
+export const selectPianobarPlayback = (state: RootState): PlaybackState => state.pianobar.player.playback;
+export const selectPianobarBusy = (state: RootState): string | null => state.pianobar.player.busy;
 
 
 function convert_seconds_to_string(secs: number): string {
@@ -103,7 +147,7 @@ function convert_seconds_to_string(secs: number): string {
     }
 }
 export const selectPianobarSongPlayedTime = (state: RootState): string => {
-    return convert_seconds_to_string(state.pianobar.player.song_time_played);
+    return convert_seconds_to_string(selectPianobarSongPlayedSeconds(state));
 }
 export const selectPianobarSongDurationTime = (state: RootState): string => {
     return convert_seconds_to_string(state.pianobar.player.song_time_total);
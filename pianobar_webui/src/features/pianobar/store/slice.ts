@@ -4,16 +4,29 @@ import { PlayerState } from "./playerState";
 let initialState: {
     ui: { [key: string]: object },
     player: PlayerState,
-    websocket: { connected: boolean },
+    // Local time at which the player state arrived, and the local time the
+    // song position is interpolated for
+    playerReceivedAt: number,
+    clock: number,
+    websocket: { connected: boolean, incompatible: boolean, unauthorized: boolean },
 } = {
     ui: {},
     player: {
         paused: true,
+        playback: "stopped",
         song_time_played: 0,
-        song_time_total: 0
+        song_time_total: 0,
+        timestamp: 0,
+        position: 0,
+        rate: 0,
+        busy: null
     },
+    playerReceivedAt: 0,
+    clock: 0,
     websocket: {
         connected: false,
+        incompatible: false,
+        unauthorized: false,
     },
 };
 
@@ -32,9 +45,14 @@ const slice = createSlice({
         },
         playerStateReceived: (
             state,
-            action: PayloadAction<{ state: PlayerState }>
+            action: PayloadAction<{ state: PlayerState, receivedAt: number }>
         ) => {
             state.player = action.payload.state;
+            state.playerReceivedAt = action.payload.receivedAt;
+            state.clock = action.payload.receivedAt;
+        },
+        clockTicked: (state, action: PayloadAction<number>) => {
+            state.clock = action.payload;
         },
         websocketConnectionOpened: (state) => {
             state.websocket.connected = true;
@@ -42,6 +60,12 @@ const slice = createSlice({
         websocketConnectionClosed: (state) => {
             state.websocket.connected = false;
         },
+        websocketProtocolIncompatible: (state) => {
+            state.websocket.incompatible = true;
+        },
+        websocketUnauthorized: (state) => {
+            state.websocket.unauthorized = true;
+        },
     },
 });
 
@@ -49,8 +73,11 @@ const slice = createSlice({
 export const {
     uiEventReceived,
     playerStateReceived,
+    clockTicked,
     websocketConnectionOpened,
     websocketConnectionClosed,
+    websocketProtocolIncompatible,
+    websocketUnauthorized,
 } = slice.actions;
 
 export default slice.reducer;
@@ -1,11 +1,42 @@
 import { Client } from "rpc-websockets";
 import store from "../../../app/store";
+import { LOGIN_URL, WEBSOCKET_URL } from "../../../config";
 import {
     websocketConnectionOpened,
     websocketConnectionClosed,
+    websocketProtocolIncompatible,
+    websocketUnauthorized,
 } from "../store/slice";
+import { CLOSE_INCOMPATIBLE_VERSION, PROTOCOL_VERSION } from "./protocol";
 
 export function initializeConnectionHandlers(websocket: Client) {
-    websocket.on("open", () => store.dispatch(websocketConnectionOpened()));
-    websocket.on("close", () => store.dispatch(websocketConnectionClosed()));
+    websocket.on("open", () => {
+        store.dispatch(websocketConnectionOpened());
+        // An incompatible server answers with an error and closes the connection
+        websocket
+            .call("hello", { protocol_version: PROTOCOL_VERSION })
+            .catch((error) => console.error("Protocol handshake failed:", error));
+    });
+    websocket.on("close", (code: number) => {
+        if (code === CLOSE_INCOMPATIBLE_VERSION) {
+            store.dispatch(websocketProtocolIncompatible());
+        }
+        store.dispatch(websocketConnectionClosed());
+        redirectIfUnauthorized();
+    });
+}
+
+// Browsers hide the status of a failed websocket handshake, so ask with a
+// plain request whether the server wants a login. It answers 401 if so.
+function redirectIfUnauthorized() {
+    fetch(WEBSOCKET_URL.replace(/^ws/, "http"), { credentials: "same-origin" })
+        .then((response) => {
+            if (response.status === 401) {
+                store.dispatch(websocketUnauthorized());
+                window.location.assign(LOGIN_URL);
+            }
+        })
+        .catch(() => {
+            // The server is unreachable, keep reconnecting
+        });
 }
@@ -1,9 +1,17 @@
 import { Client } from "rpc-websockets";
 import store from "../../../app/store";
-import { playerStateReceived } from "../store/slice";
+import { clockTicked, playerStateReceived } from "../store/slice";
+
+// How often the interpolated song position gets updated
+const CLOCK_INTERVAL_MS = 1000;
 
 export function initializePlayerStateReceiver(websocket: Client) {
     websocket.on("player_state", (payload) =>
-        store.dispatch(playerStateReceived(payload))
+        store.dispatch(playerStateReceived({ state: payload.state, receivedAt: Date.now() }))
     );
+    setInterval(() => {
+        if (store.getState().pianobar.player.rate > 0) {
+            store.dispatch(clockTicked(Date.now()));
+        }
+    }, CLOCK_INTERVAL_MS);
 }
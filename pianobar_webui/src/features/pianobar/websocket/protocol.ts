@@ -0,0 +1,4 @@
+// The protocol version this web ui speaks, see `protocol.rs` of the server.
+// The server closes the connection with CLOSE_INCOMPATIBLE_VERSION if the major versions differ.
+export const PROTOCOL_VERSION = "2.1";
+export const CLOSE_INCOMPATIBLE_VERSION = 4001;
@@ -1,7 +1,7 @@
 import { call } from "@redux-saga/core/effects";
 import { Client } from "rpc-websockets";
 
-import { WEBSOCKET_PORT } from "../../../config";
+import { WEBSOCKET_URL } from "../../../config";
 import { initializeConnectionHandlers } from "./connectionChanged";
 import { initializePlayerStateReceiver } from "./playerState";
 import { initializeUiEventReceiver } from "./uiEvents";
@@ -18,7 +18,8 @@ export function* pianobarWebsocketSaga() {
 }
 
 // Create websocket
-export default new Client(
-    "ws://" + window.location.hostname + ":" + WEBSOCKET_PORT + "/ws",
-    { autoconnect: false, reconnect: true, max_reconnects: 0 }
-);
+export default new Client(WEBSOCKET_URL, {
+    autoconnect: false,
+    reconnect: true,
+    max_reconnects: 0,
+});
@@ -10,7 +10,8 @@ import {
     selectPianobarArtist,
     selectPianobarStationId,
     selectPianobarStations,
-    selectPianobarTitle
+    selectPianobarTitle,
+    selectPianobarUpcoming
 } from "../../../pianobar/store/selector";
 import Popups from "../Popups";
 import TextAutoShrinker from "../../widgets/TextAutoShrinker";
@@ -22,6 +23,8 @@ const MainContent = () => {
     let pianobarAlbum = useSelector(selectPianobarAlbum);
     let pianobarArtist = useSelector(selectPianobarArtist);
     let pianobarStationId = useSelector(selectPianobarStationId);
+    let pianobarUpcoming = useSelector(selectPianobarUpcoming);
+    let nextSong = pianobarUpcoming.length > 0 ? pianobarUpcoming[0] : null;
 
     let dispatch = useAppDispatch();
 
@@ -80,6 +83,11 @@ const MainContent = () => {
                         {pianobarAlbum}
                     </TextAutoShrinker>
                 </Typography>
+                {nextSong !== null ?
+                    <Typography noWrap align="center" variant="caption" component="div" color="textSecondary">
+                        Up next: {nextSong.artist} - {nextSong.title}
+                    </Typography>
+                    : null}
             </Box>
             <Box flex="1 0 0" /> {/* space */}
 
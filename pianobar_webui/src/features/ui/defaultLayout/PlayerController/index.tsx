@@ -5,7 +5,9 @@ import PauseIcon from '@material-ui/icons/Pause';
 import SkipNextIcon from '@material-ui/icons/SkipNext';
 import { useSelector } from "react-redux";
 import {
+    selectPianobarBusy,
     selectPianobarPaused,
+    selectPianobarPlayback,
     selectPianobarSongDurationSeconds,
     selectPianobarSongPlayedSeconds,
     selectPianobarSongDurationTime,
@@ -31,6 +33,15 @@ const SongLinearProgress = withStyles((theme: Theme) => {
 const ProgressBar = React.memo(() => {
     const songDurationSeconds = useSelector(selectPianobarSongDurationSeconds);
     const songPlayedSeconds = useSelector(selectPianobarSongPlayedSeconds);
+    const busy = useSelector(selectPianobarBusy);
+    const playback = useSelector(selectPianobarPlayback);
+    // pianobar is waiting for something, like the login or the next playlist
+    if (busy !== null) {
+        return <SongLinearProgress color="primary" variant="indeterminate" title={busy} />;
+    }
+    if (playback === "buffering") {
+        return <SongLinearProgress color="primary" variant="indeterminate" />;
+    }
     return <SongLinearProgress color="primary" variant="determinate" value={100 * (songPlayedSeconds / songDurationSeconds)} />;
 });
 
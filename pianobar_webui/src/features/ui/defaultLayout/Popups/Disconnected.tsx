@@ -1,7 +1,12 @@
 import { Backdrop, Box, CircularProgress, createStyles, makeStyles, Theme } from "@material-ui/core";
 import React from "react";
 import { useSelector } from "react-redux";
-import { selectPianobarConnected } from "../../../pianobar/store/selector";
+import { LOGIN_URL } from "../../../../config";
+import {
+    selectPianobarConnected,
+    selectPianobarIncompatible,
+    selectPianobarUnauthorized,
+} from "../../../pianobar/store/selector";
 
 const useStyles = makeStyles((theme: Theme) =>
     createStyles({
@@ -15,6 +20,28 @@ const useStyles = makeStyles((theme: Theme) =>
 const Disconnected = () => {
     const classes = useStyles();
     const connected = useSelector(selectPianobarConnected);
+    const incompatible = useSelector(selectPianobarIncompatible);
+    const unauthorized = useSelector(selectPianobarUnauthorized);
+    if (unauthorized) {
+        return (
+            <Backdrop className={classes.backdrop} open={true}>
+                <Box display="flex" flexDirection="column" alignItems="center">
+                    <Box marginBottom="1.5em">Login required.</Box>
+                    <Box>Redirecting to the <a href={LOGIN_URL}>login</a> ...</Box>
+                </Box>
+            </Backdrop>
+        );
+    }
+    if (incompatible) {
+        return (
+            <Backdrop className={classes.backdrop} open={true}>
+                <Box display="flex" flexDirection="column" alignItems="center">
+                    <Box marginBottom="1.5em">The pianobar server was updated.</Box>
+                    <Box>Please reload the page.</Box>
+                </Box>
+            </Backdrop>
+        );
+    }
     return (
         <Backdrop className={classes.backdrop} open={!connected}>
             <Box display="flex" flexDirection="column" alignItems="center">